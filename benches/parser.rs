@@ -0,0 +1,78 @@
+use std::hint::black_box;
+use std::io::BufRead;
+use std::time::{Duration, Instant};
+use libhttp::http::Header;
+use libhttp::message::HttpRequest;
+use libhttp::parser::{parse_request, Parsed, RawHeader};
+
+const ITERATIONS: u32 = 200_000;
+
+const REQUEST: &[u8] = b"GET /articles/2024/parsing-http?page=2&sort=desc HTTP/1.1\r\n\
+Host: www.example.com\r\n\
+User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0\r\n\
+Accept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8\r\n\
+Accept-Language: en-GB,en;q=0.5\r\n\
+Accept-Encoding: gzip, deflate, br\r\n\
+Referer: https://www.example.com/articles/2024\r\n\
+Cookie: session=8f14e45fceea167a5a36dedd4bea2543; theme=dark\r\n\
+Connection: keep-alive\r\n\
+Cache-Control: max-age=0\r\n\
+\r\n";
+
+// The line based path the server used before: a String per line and two per header
+fn parse_lines(mut bytes: &[u8]) -> HttpRequest {
+    let mut line = String::new();
+    bytes.read_line(&mut line).unwrap();
+    let mut request = HttpRequest::parse(line);
+
+    loop {
+        let mut line = String::new();
+        bytes.read_line(&mut line).unwrap();
+        if line.trim().is_empty() {
+            break;
+        }
+        request.headers.push(Header::parse(line));
+    }
+
+    request
+}
+
+fn parse_borrowed(bytes: &[u8]) -> usize {
+    let mut headers = [RawHeader::default(); 100];
+    match parse_request(bytes, &mut headers, true).unwrap() {
+        Parsed::Complete(head, _) => head.headers.len(),
+        Parsed::Partial => unreachable!(),
+    }
+}
+
+fn parse_owned(bytes: &[u8]) -> Vec<Header> {
+    let mut headers = [RawHeader::default(); 100];
+    match parse_request(bytes, &mut headers, true).unwrap() {
+        Parsed::Complete(head, _) => head.headers.iter().map(Header::from).collect(),
+        Parsed::Partial => unreachable!(),
+    }
+}
+
+fn bench<F, T>(name: &str, mut f: F) where F: FnMut(&[u8]) -> T {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(f(black_box(REQUEST)));
+    }
+    let elapsed = start.elapsed();
+
+    let per_request = elapsed / ITERATIONS;
+    let throughput = (REQUEST.len() as u32 * ITERATIONS) as f64 / elapsed.as_secs_f64() / 1_000_000.0;
+    println!("{:<28} {:>10?} per request {:>10.1} MB/s", name, per_request, throughput);
+}
+
+fn main() {
+    // warm up
+    let deadline = Instant::now() + Duration::from_millis(200);
+    while Instant::now() < deadline {
+        black_box(parse_borrowed(black_box(REQUEST)));
+    }
+
+    bench("read_line + Header::parse", parse_lines);
+    bench("parse_request", parse_borrowed);
+    bench("parse_request + Header", parse_owned);
+}
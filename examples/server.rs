@@ -31,7 +31,7 @@ impl HttpHandler for MyHandler {
         
         let body = format!("Hello, world! {}", self.counter).as_bytes().to_vec();
 
-        return HttpResponse::builder()
+        HttpResponse::builder()
             .status(Status::Ok)
             .header(HEADER_CONTENT_TYPE, CONTENT_TYPE_TEXT_PLAIN)
             .body(body)
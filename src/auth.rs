@@ -0,0 +1,659 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use crate::http::{Header, HEADER_WWW_AUTHENTICATE, Method, Status};
+use crate::message::{HttpRequest, HttpResponse};
+use crate::server::HttpHandler;
+
+const DEFAULT_REALM: &str = "libhttp";
+const DEFAULT_NONCE_TTL_SECS: i64 = 300;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Authorization {
+    Basic {
+        username: String,
+        password: String,
+    },
+    Bearer(String),
+    Digest(DigestCredentials),
+    Other {
+        scheme: String,
+        credentials: String,
+    },
+}
+
+impl Authorization {
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (scheme, credentials) = value.split_once(' ').unwrap_or((value, ""));
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let decoded = STANDARD.decode(credentials).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            return Some(Authorization::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            });
+        }
+
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            if credentials.is_empty() {
+                return None;
+            }
+            return Some(Authorization::Bearer(credentials.to_string()));
+        }
+
+        if scheme.eq_ignore_ascii_case("Digest") {
+            return DigestCredentials::parse(credentials).map(Authorization::Digest);
+        }
+
+        Some(Authorization::Other {
+            scheme: scheme.to_string(),
+            credentials: credentials.to_string(),
+        })
+    }
+
+    pub fn scheme(&self) -> &str {
+        match self {
+            Authorization::Basic { .. } => "Basic",
+            Authorization::Bearer(_) => "Bearer",
+            Authorization::Digest(_) => "Digest",
+            Authorization::Other { scheme, .. } => scheme,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DigestAlgorithm {
+    Md5,
+    Md5Sess,
+    Sha256,
+    Sha256Sess,
+}
+
+impl DigestAlgorithm {
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_uppercase().as_str() {
+            "MD5" => Some(DigestAlgorithm::Md5),
+            "MD5-SESS" => Some(DigestAlgorithm::Md5Sess),
+            "SHA-256" => Some(DigestAlgorithm::Sha256),
+            "SHA-256-SESS" => Some(DigestAlgorithm::Sha256Sess),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Md5 => "MD5",
+            DigestAlgorithm::Md5Sess => "MD5-sess",
+            DigestAlgorithm::Sha256 => "SHA-256",
+            DigestAlgorithm::Sha256Sess => "SHA-256-sess",
+        }
+    }
+
+    fn is_session(&self) -> bool {
+        matches!(self, DigestAlgorithm::Md5Sess | DigestAlgorithm::Sha256Sess)
+    }
+
+    fn hash(&self, data: &str) -> String {
+        let digest = match self {
+            DigestAlgorithm::Md5 | DigestAlgorithm::Md5Sess => Md5::digest(data.as_bytes()).to_vec(),
+            DigestAlgorithm::Sha256 | DigestAlgorithm::Sha256Sess => Sha256::digest(data.as_bytes()).to_vec(),
+        };
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DigestCredentials {
+    pub username: String,
+    pub realm: String,
+    pub nonce: String,
+    pub uri: String,
+    pub response: String,
+    pub algorithm: DigestAlgorithm,
+    pub cnonce: Option<String>,
+    pub opaque: Option<String>,
+    pub qop: Option<String>,
+    pub nc: Option<String>,
+    pub userhash: bool,
+}
+
+impl DigestCredentials {
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let params = parse_auth_params(value);
+        let param = |key: &str| params.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.clone());
+
+        let algorithm = match param("algorithm") {
+            Some(algorithm) => DigestAlgorithm::parse(&algorithm)?,
+            None => DigestAlgorithm::Md5,
+        };
+
+        Some(DigestCredentials {
+            username: param("username")?,
+            realm: param("realm")?,
+            nonce: param("nonce")?,
+            uri: param("uri")?,
+            response: param("response")?,
+            algorithm,
+            cnonce: param("cnonce"),
+            opaque: param("opaque"),
+            qop: param("qop"),
+            nc: param("nc"),
+            userhash: param("userhash").is_some_and(|value| value.eq_ignore_ascii_case("true")),
+        })
+    }
+
+    // Computes the expected response for the password as described in RFC 7616
+    pub fn expected_response(&self, method: &str, password: &str) -> Option<String> {
+        let algorithm = self.algorithm;
+
+        let mut ha1 = algorithm.hash(&format!("{}:{}:{}", self.username, self.realm, password));
+        if algorithm.is_session() {
+            ha1 = algorithm.hash(&format!("{}:{}:{}", ha1, self.nonce, self.cnonce.as_deref()?));
+        }
+
+        let ha2 = algorithm.hash(&format!("{}:{}", method, self.uri));
+
+        match self.qop.as_deref() {
+            Some("auth") => Some(algorithm.hash(&format!("{}:{}:{}:{}:auth:{}", ha1, self.nonce, self.nc.as_deref()?, self.cnonce.as_deref()?, ha2))),
+            Some(_) => None,
+            None => Some(algorithm.hash(&format!("{}:{}:{}", ha1, self.nonce, ha2))),
+        }
+    }
+}
+
+// Parses comma separated auth-params, e.g. `realm="a, b", nc=00000001`
+fn parse_auth_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+
+        let key: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=' && *c != ',')).collect();
+        let key = key.trim().to_string();
+        if key.is_empty() {
+            break;
+        }
+
+        let mut param_value = String::new();
+        if chars.next_if_eq(&'=').is_some() {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.next_if_eq(&'"').is_some() {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => param_value.extend(chars.next()),
+                        c => param_value.push(c),
+                    }
+                }
+                while chars.next_if(|c| *c != ',').is_some() {}
+            } else {
+                param_value = std::iter::from_fn(|| chars.next_if(|c| *c != ',')).collect();
+                param_value = param_value.trim().to_string();
+            }
+        }
+
+        params.push((key, param_value));
+    }
+
+    params
+}
+
+// Checks credentials on behalf of AuthMiddleware. Only the schemes a verifier
+// implements need to be enabled on the middleware.
+pub trait AuthVerifier: Send + Sync + 'static {
+
+    fn verify_basic(&self, _username: &str, _password: &str) -> bool {
+        false
+    }
+
+    fn verify_bearer(&self, _token: &str) -> bool {
+        false
+    }
+
+    // Digest needs the plain password to recompute the client's response
+    fn digest_password(&self, _username: &str, _realm: &str) -> Option<String> {
+        None
+    }
+}
+
+#[derive(Clone)]
+struct AuthConfig {
+    realm: String,
+    basic: bool,
+    bearer: bool,
+    digest: Vec<DigestAlgorithm>,
+    nonce_ttl_secs: i64,
+}
+
+pub struct AuthMiddleware {
+    handler: Arc<Mutex<dyn HttpHandler>>,
+    verifier: Arc<dyn AuthVerifier>,
+    config: AuthConfig,
+    nonce_key: [u8; 32],
+    opaque: String,
+    // the highest nc seen for each nonce still in use, with the time it was issued
+    nonce_counts: Arc<Mutex<HashMap<String, (i64, u32)>>>,
+}
+
+enum Outcome {
+    Authorized,
+    Missing,
+    Invalid,
+    StaleNonce,
+}
+
+impl AuthMiddleware {
+
+    pub fn builder(handler: Arc<Mutex<dyn HttpHandler>>, verifier: Arc<dyn AuthVerifier>) -> AuthMiddlewareBuilder {
+        AuthMiddlewareBuilder {
+            handler,
+            verifier,
+            config: AuthConfig {
+                realm: DEFAULT_REALM.to_string(),
+                basic: false,
+                bearer: false,
+                digest: Vec::new(),
+                nonce_ttl_secs: DEFAULT_NONCE_TTL_SECS,
+            },
+        }
+    }
+
+    fn check(&self, request: &HttpRequest) -> Outcome {
+        let authorization = match request.authorization() {
+            Some(authorization) => authorization,
+            None => return Outcome::Missing,
+        };
+
+        match authorization {
+            Authorization::Basic { username, password } if self.config.basic => {
+                if self.verifier.verify_basic(&username, &password) {
+                    Outcome::Authorized
+                } else {
+                    Outcome::Invalid
+                }
+            }
+            Authorization::Bearer(token) if self.config.bearer => {
+                if self.verifier.verify_bearer(&token) {
+                    Outcome::Authorized
+                } else {
+                    Outcome::Invalid
+                }
+            }
+            Authorization::Digest(credentials) if !self.config.digest.is_empty() => self.check_digest(request, &credentials),
+            _ => Outcome::Missing,
+        }
+    }
+
+    fn check_digest(&self, request: &HttpRequest, credentials: &DigestCredentials) -> Outcome {
+
+        // without qop there is no nonce count to detect replays with
+        if !self.config.digest.contains(&credentials.algorithm)
+            || credentials.qop.is_none()
+            || credentials.realm != self.config.realm
+            || credentials.uri != request.path()
+            || credentials.userhash
+            || credentials.opaque.as_deref().is_some_and(|opaque| opaque != self.opaque) {
+            return Outcome::Invalid;
+        }
+
+        let issued_at = match self.nonce_issued_at(&credentials.nonce) {
+            Some(issued_at) => issued_at,
+            None => return Outcome::Invalid,
+        };
+        let nonce_fresh = Utc::now().timestamp() - issued_at <= self.config.nonce_ttl_secs;
+
+        // the client computed its response with the method it sent, which the
+        // server may since have changed, e.g. HEAD to GET
+        let expected = self.verifier.digest_password(&credentials.username, &credentials.realm)
+            .and_then(|password| credentials.expected_response(request.original_method().as_str(), &password));
+
+        match expected {
+            Some(expected) if constant_time_eq(expected.as_bytes(), credentials.response.to_ascii_lowercase().as_bytes()) => {
+                // the password was right, the client just has to retry with a new
+                // nonce. A reused count may be a replay, but also requests sent
+                // concurrently arriving out of order, which a new nonce fixes too.
+                if nonce_fresh && self.count_nonce(credentials, issued_at) { Outcome::Authorized } else { Outcome::StaleNonce }
+            }
+            _ => Outcome::Invalid,
+        }
+    }
+
+    // Records the nonce count of accepted credentials, which must be higher
+    // than any used with the nonce before
+    fn count_nonce(&self, credentials: &DigestCredentials, issued_at: i64) -> bool {
+        let count = match credentials.nc.as_deref().and_then(|nc| u32::from_str_radix(nc, 16).ok()) {
+            Some(count) => count,
+            None => return false,
+        };
+
+        let mut nonce_counts = self.nonce_counts.lock().unwrap();
+
+        // expired nonces can't be used anymore, so there is no need to remember them
+        let now = Utc::now().timestamp();
+        nonce_counts.retain(|_, (issued_at, _)| now - *issued_at <= self.config.nonce_ttl_secs);
+
+        match nonce_counts.get_mut(&credentials.nonce) {
+            Some((_, last)) if count <= *last => false,
+            Some((_, last)) => {
+                *last = count;
+                true
+            }
+            None => {
+                nonce_counts.insert(credentials.nonce.clone(), (issued_at, count));
+                true
+            }
+        }
+    }
+
+    // Nonces are self-validating: a timestamp and random bytes, so that every
+    // challenge gets its own nonce count, plus a MAC over both
+    fn generate_nonce(&self) -> String {
+        let mut salt = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        let data = format!("{}.{}", Utc::now().timestamp(), URL_SAFE_NO_PAD.encode(salt));
+        format!("{}.{}", data, URL_SAFE_NO_PAD.encode(self.nonce_mac(&data)))
+    }
+
+    fn nonce_issued_at(&self, nonce: &str) -> Option<i64> {
+        let (data, mac) = nonce.rsplit_once('.')?;
+        let mac = URL_SAFE_NO_PAD.decode(mac).ok()?;
+
+        if !constant_time_eq(&self.nonce_mac(data), &mac) {
+            return None;
+        }
+
+        data.split('.').next()?.parse().ok()
+    }
+
+    fn nonce_mac(&self, data: &str) -> Vec<u8> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.nonce_key).expect("hmac accepts any key length");
+        mac.update(data.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn challenge(&self, outcome: &Outcome, request: &HttpRequest) -> HttpResponse {
+        let realm = quote(&self.config.realm);
+        let mut headers = Vec::new();
+
+        for algorithm in &self.config.digest {
+            let stale = if matches!(outcome, Outcome::StaleNonce) { ", stale=true" } else { "" };
+            headers.push(Header::new(HEADER_WWW_AUTHENTICATE, format!(
+                "Digest realm={}, qop=\"auth\", algorithm={}, nonce={}, opaque={}{}",
+                realm, algorithm.as_str(), quote(&self.generate_nonce()), quote(&self.opaque), stale
+            )));
+        }
+
+        if self.config.bearer {
+            let sent_bearer = matches!(request.authorization(), Some(Authorization::Bearer(_)));
+            let error = if sent_bearer && matches!(outcome, Outcome::Invalid) { ", error=\"invalid_token\"" } else { "" };
+            headers.push(Header::new(HEADER_WWW_AUTHENTICATE, format!("Bearer realm={}{}", realm, error)));
+        }
+
+        if self.config.basic {
+            headers.push(Header::new(HEADER_WWW_AUTHENTICATE, format!("Basic realm={}, charset=\"UTF-8\"", realm)));
+        }
+
+        HttpResponse::new(Status::Unauthorized, headers, None)
+    }
+}
+
+impl HttpHandler for AuthMiddleware {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        match self.check(request) {
+            Outcome::Authorized => self.handler.lock().unwrap().handle(request),
+            outcome => self.challenge(&outcome, request),
+        }
+    }
+
+    fn supports_method(&self, method: &Method) -> bool {
+        self.handler.lock().unwrap().supports_method(method)
+    }
+
+    fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        self.handler.lock().unwrap().allowed_methods(path)
+    }
+
+    fn expect_continue(&mut self, request: &HttpRequest) -> Option<HttpResponse> {
+        self.handler.lock().unwrap().expect_continue(request)
+    }
+
+    fn stream_body(&self, request: &HttpRequest) -> bool {
+        self.handler.lock().unwrap().stream_body(request)
+    }
+
+    fn fork(&self) -> Option<Box<dyn HttpHandler>> {
+        let handler = self.handler.lock().unwrap().fork()?;
+        Some(Box::new(AuthMiddleware {
+            handler: Arc::new(Mutex::new(handler)),
+            verifier: self.verifier.clone(),
+            config: self.config.clone(),
+            nonce_key: self.nonce_key,
+            opaque: self.opaque.clone(),
+            nonce_counts: self.nonce_counts.clone(),
+        }))
+    }
+}
+
+pub struct AuthMiddlewareBuilder {
+    handler: Arc<Mutex<dyn HttpHandler>>,
+    verifier: Arc<dyn AuthVerifier>,
+    config: AuthConfig,
+}
+
+impl AuthMiddlewareBuilder {
+
+    pub fn realm<R>(&mut self, realm: R) -> &mut Self where R: Into<String> {
+        self.config.realm = realm.into();
+        self
+    }
+
+    pub fn basic(&mut self) -> &mut Self {
+        self.config.basic = true;
+        self
+    }
+
+    pub fn bearer(&mut self) -> &mut Self {
+        self.config.bearer = true;
+        self
+    }
+
+    // Challenges are sent in the order algorithms are added, strongest first
+    pub fn digest(&mut self, algorithm: DigestAlgorithm) -> &mut Self {
+        if !self.config.digest.contains(&algorithm) {
+            self.config.digest.push(algorithm);
+        }
+        self
+    }
+
+    pub fn nonce_ttl(&mut self, seconds: i64) -> &mut Self {
+        self.config.nonce_ttl_secs = seconds;
+        self
+    }
+
+    pub fn build(&self) -> AuthMiddleware {
+        let mut nonce_key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut nonce_key);
+
+        let mut opaque = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut opaque);
+
+        AuthMiddleware {
+            handler: self.handler.clone(),
+            verifier: self.verifier.clone(),
+            config: self.config.clone(),
+            nonce_key,
+            opaque: URL_SAFE_NO_PAD.encode(opaque),
+            nonce_counts: Arc::default(),
+        }
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::HEADER_AUTHORIZATION;
+    use super::*;
+
+    struct Hello;
+
+    impl HttpHandler for Hello {
+        fn handle(&mut self, _request: &HttpRequest) -> HttpResponse {
+            HttpResponse::new(Status::Ok, vec![], None)
+        }
+    }
+
+    struct Mufasa;
+
+    impl AuthVerifier for Mufasa {
+        fn verify_basic(&self, username: &str, password: &str) -> bool {
+            username == "Mufasa" && password == "Circle of Life"
+        }
+
+        fn digest_password(&self, username: &str, _realm: &str) -> Option<String> {
+            (username == "Mufasa").then(|| "Circle of Life".to_string())
+        }
+    }
+
+    fn middleware() -> AuthMiddleware {
+        AuthMiddleware::builder(Arc::new(Mutex::new(Hello)), Arc::new(Mufasa))
+            .realm("http-auth@example.org")
+            .basic()
+            .digest(DigestAlgorithm::Sha256)
+            .build()
+    }
+
+    fn rfc_credentials(algorithm: &str, response: &str) -> DigestCredentials {
+        DigestCredentials::parse(&format!(
+            r#"username="Mufasa", realm="http-auth@example.org", uri="/dir/index.html", algorithm={}, nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", nc=00000001, cnonce="f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ", qop=auth, response="{}", opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#,
+            algorithm, response
+        )).unwrap()
+    }
+
+    // Answers the first Digest challenge of the middleware the way a client would
+    fn digest_request(auth: &mut AuthMiddleware, method: Method, nc: u32) -> (HttpRequest, String) {
+        let challenge = auth.handle(&HttpRequest::builder().method(method.clone()).path("/dir/index.html").build());
+        let challenge = challenge.header(HEADER_WWW_AUTHENTICATE).unwrap().to_string();
+        let nonce = parse_auth_params(challenge.trim_start_matches("Digest "))
+            .into_iter()
+            .find(|(key, _)| key == "nonce")
+            .unwrap()
+            .1;
+        (authorized(&nonce, method, nc), nonce)
+    }
+
+    fn authorized(nonce: &str, method: Method, nc: u32) -> HttpRequest {
+        let mut credentials = rfc_credentials("SHA-256", "");
+        credentials.nonce = nonce.to_string();
+        credentials.opaque = None;
+        credentials.nc = Some(format!("{:08x}", nc));
+        let response = credentials.expected_response(method.as_str(), "Circle of Life").unwrap();
+
+        let header = format!(
+            r#"Digest username="Mufasa", realm="http-auth@example.org", uri="/dir/index.html", algorithm=SHA-256, nonce="{}", nc={:08x}, cnonce="{}", qop=auth, response="{}""#,
+            nonce, nc, credentials.cnonce.unwrap(), response
+        );
+        HttpRequest::builder().method(method).path("/dir/index.html").header(HEADER_AUTHORIZATION, &header).build()
+    }
+
+    #[test]
+    fn digest_responses_match_rfc_7616() {
+        let md5 = rfc_credentials("MD5", "8ca523f5e9506fed4657c9700eebdbec");
+        assert_eq!(md5.expected_response("GET", "Circle of Life").as_deref(), Some(md5.response.as_str()));
+
+        let sha256 = rfc_credentials("SHA-256", "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1");
+        assert_eq!(sha256.expected_response("GET", "Circle of Life").as_deref(), Some(sha256.response.as_str()));
+    }
+
+    #[test]
+    fn auth_params_handle_quoting() {
+        let params = parse_auth_params(r#"realm="a, \"b\"", nc=00000001 ,qop = auth, empty="""#);
+        assert_eq!(params, vec![
+            ("realm".to_string(), "a, \"b\"".to_string()),
+            ("nc".to_string(), "00000001".to_string()),
+            ("qop".to_string(), "auth".to_string()),
+            ("empty".to_string(), String::new()),
+        ]);
+    }
+
+    #[test]
+    fn parses_schemes() {
+        assert_eq!(Authorization::parse("Basic TXVmYXNhOkNpcmNsZSBvZiBMaWZl"), Some(Authorization::Basic {
+            username: "Mufasa".to_string(),
+            password: "Circle of Life".to_string(),
+        }));
+        assert_eq!(Authorization::parse("bearer abc"), Some(Authorization::Bearer("abc".to_string())));
+        assert_eq!(Authorization::parse("Bearer"), None);
+        assert_eq!(Authorization::parse("Basic !!!"), None);
+        assert_eq!(Authorization::parse("Digest username=\"x\""), None);
+    }
+
+    #[test]
+    fn basic_credentials_are_verified() {
+        let mut auth = middleware();
+        let request = |header: &str| HttpRequest::builder().path("/").header(HEADER_AUTHORIZATION, header).build();
+
+        assert_eq!(auth.handle(&request("Basic TXVmYXNhOkNpcmNsZSBvZiBMaWZl")).status, Status::Ok);
+        assert_eq!(auth.handle(&request("Basic TXVmYXNhOndyb25n")).status, Status::Unauthorized);
+    }
+
+    #[test]
+    fn digest_nonce_counts_must_increase() {
+        let mut auth = middleware();
+        let (request, nonce) = digest_request(&mut auth, Method::Get, 1);
+        assert_eq!(auth.handle(&request).status, Status::Ok);
+
+        // the same request again is a replay
+        let replay = auth.handle(&request);
+        assert_eq!(replay.status, Status::Unauthorized);
+        assert!(replay.header(HEADER_WWW_AUTHENTICATE).unwrap().contains("stale=true"));
+
+        assert_eq!(auth.handle(&authorized(&nonce, Method::Get, 2)).status, Status::Ok);
+        assert_eq!(auth.handle(&authorized(&nonce, Method::Get, 2)).status, Status::Unauthorized);
+    }
+
+    #[test]
+    fn digest_without_qop_is_refused() {
+        let mut auth = middleware();
+        let (request, _) = digest_request(&mut auth, Method::Get, 1);
+        let header = request.header(HEADER_AUTHORIZATION).unwrap().replace(", qop=auth", "");
+        let request = HttpRequest::builder().path("/dir/index.html").header(HEADER_AUTHORIZATION, &header).build();
+
+        let response = auth.handle(&request);
+        assert_eq!(response.status, Status::Unauthorized);
+        assert!(!response.header(HEADER_WWW_AUTHENTICATE).unwrap().contains("stale"));
+    }
+
+    #[test]
+    fn digest_covers_the_method_the_client_sent() {
+        let mut auth = middleware();
+
+        // the server answers HEAD as GET, the client signed HEAD
+        let (mut request, _) = digest_request(&mut auth, Method::Head, 1);
+        request.set_method(Method::Get);
+        assert_eq!(auth.handle(&request).status, Status::Ok);
+    }
+}
@@ -0,0 +1,367 @@
+use std::{error, fmt, io};
+use std::io::{BufRead, Read};
+use async_std::io::prelude::{BufReadExt, ReadExt};
+use crate::http::Header;
+
+const MAX_LINE_LEN: usize = 8 * 1024;
+// Trailers read by `ChunkedReader`, which has no configured limit
+const MAX_TRAILER_SIZE: usize = 16 * 1024;
+pub(crate) const READ_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Size,
+    Data(u64),
+    DataEnd,
+    Done,
+}
+
+// Decodes a chunked transfer-coded body. Reading stops after the last chunk;
+// the trailer section is parsed and kept for `trailers`.
+pub struct ChunkedReader<R: BufRead> {
+    reader: R,
+    state: State,
+    trailers: Vec<Header>,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+
+    pub fn new(reader: R) -> Self {
+        ChunkedReader {
+            reader,
+            state: State::Size,
+            trailers: Vec::new(),
+        }
+    }
+
+    pub fn trailers(&self) -> &[Header] {
+        &self.trailers
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        (&mut self.reader).take(MAX_LINE_LEN as u64 + 1).read_until(b'\n', &mut line)?;
+        decode_line(line)
+    }
+
+    fn read_size(&mut self) -> io::Result<u64> {
+        parse_size(&self.read_line()?)
+    }
+
+    fn read_trailers(&mut self) -> io::Result<()> {
+        let mut size = 0;
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                return Ok(());
+            }
+
+            size += line.len();
+            if size > MAX_TRAILER_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, TooLarge::Trailers));
+            }
+            self.trailers.push(Header::parse(line));
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.state {
+                State::Done => return Ok(0),
+                State::Size => {
+                    let size = self.read_size()?;
+                    if size == 0 {
+                        self.read_trailers()?;
+                        self.state = State::Done;
+                    } else {
+                        self.state = State::Data(size);
+                    }
+                }
+                State::Data(remaining) => {
+                    if buf.is_empty() {
+                        return Ok(0);
+                    }
+
+                    let limit = remaining.min(buf.len() as u64) as usize;
+                    let count = self.reader.read(&mut buf[..limit])?;
+                    if count == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunked body ended early"));
+                    }
+
+                    let remaining = remaining - count as u64;
+                    self.state = if remaining == 0 { State::DataEnd } else { State::Data(remaining) };
+                    return Ok(count);
+                }
+                State::DataEnd => {
+                    if !self.read_line()?.is_empty() {
+                        return Err(invalid("missing CRLF after chunk data"));
+                    }
+                    self.state = State::Size;
+                }
+            }
+        }
+    }
+}
+
+// Which limit a chunked body went over, carried inside the io::Error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TooLarge {
+    Body,
+    Trailers,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TooLarge::Body => write!(f, "chunked body too large"),
+            TooLarge::Trailers => write!(f, "chunked trailers too large"),
+        }
+    }
+}
+
+impl error::Error for TooLarge {}
+
+// Returns the limit behind an error from a chunked reader or decoder, if any
+pub(crate) fn too_large(error: &io::Error) -> Option<TooLarge> {
+    error.get_ref().and_then(|inner| inner.downcast_ref::<TooLarge>()).copied()
+}
+
+// Decodes a chunked body from an async reader a piece at a time, so the data
+// can be passed on while it arrives. The reader is left at the first byte
+// after the body.
+pub(crate) struct ChunkedDecoder {
+    state: State,
+    trailers: Vec<Header>,
+    max_trailer_size: usize,
+}
+
+impl ChunkedDecoder {
+
+    pub(crate) fn new(max_trailer_size: usize) -> Self {
+        ChunkedDecoder {
+            state: State::Size,
+            trailers: Vec::new(),
+            max_trailer_size,
+        }
+    }
+
+    // Returns up to `max` bytes of data, or None once the last chunk and the
+    // trailers have been read
+    pub(crate) async fn next<R>(&mut self, reader: &mut R, max: usize) -> io::Result<Option<Vec<u8>>> where R: async_std::io::BufRead + Unpin {
+        loop {
+            match self.state {
+                State::Done => return Ok(None),
+                State::Size => {
+                    let size = parse_size(&read_line_async(reader).await?)?;
+                    if size == 0 {
+                        self.read_trailers(reader).await?;
+                        self.state = State::Done;
+                    } else {
+                        self.state = State::Data(size);
+                    }
+                }
+                State::Data(remaining) => {
+                    let mut data = Vec::new();
+                    let count = (&mut *reader).take(remaining.min(max as u64)).read_to_end(&mut data).await?;
+                    if count == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunked body ended early"));
+                    }
+
+                    let remaining = remaining - count as u64;
+                    self.state = if remaining == 0 { State::DataEnd } else { State::Data(remaining) };
+                    return Ok(Some(data));
+                }
+                State::DataEnd => {
+                    if !read_line_async(reader).await?.is_empty() {
+                        return Err(invalid("missing CRLF after chunk data"));
+                    }
+                    self.state = State::Size;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn into_trailers(self) -> Vec<Header> {
+        self.trailers
+    }
+
+    async fn read_trailers<R>(&mut self, reader: &mut R) -> io::Result<()> where R: async_std::io::BufRead + Unpin {
+        let mut size = 0;
+        loop {
+            let line = read_line_async(reader).await?;
+            if line.is_empty() {
+                return Ok(());
+            }
+
+            size += line.len();
+            if size > self.max_trailer_size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, TooLarge::Trailers));
+            }
+            self.trailers.push(Header::parse(line));
+        }
+    }
+}
+
+// Reads a whole chunked body from an async reader, returning the data and the
+// trailers. Going over either limit fails with a `TooLarge` error.
+pub(crate) async fn read_body<R>(reader: &mut R, max_size: usize, max_trailer_size: usize) -> io::Result<(Vec<u8>, Vec<Header>)> where R: async_std::io::BufRead + Unpin {
+    let mut decoder = ChunkedDecoder::new(max_trailer_size);
+    let mut body = Vec::new();
+    while let Some(data) = decoder.next(reader, READ_SIZE).await? {
+        if body.len() + data.len() > max_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, TooLarge::Body));
+        }
+        body.extend_from_slice(&data);
+    }
+    Ok((body, decoder.into_trailers()))
+}
+
+async fn read_line_async<R>(reader: &mut R) -> io::Result<String> where R: async_std::io::BufRead + Unpin {
+    let mut line = Vec::new();
+    (&mut *reader).take(MAX_LINE_LEN as u64 + 1).read_until(b'\n', &mut line).await?;
+    decode_line(line)
+}
+
+fn decode_line(line: Vec<u8>) -> io::Result<String> {
+    if line.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunked body ended early"));
+    }
+    if line.len() > MAX_LINE_LEN || !line.ends_with(b"\n") {
+        return Err(invalid("chunk line too long"));
+    }
+
+    let line = String::from_utf8(line).map_err(|_| invalid("chunk line is not valid utf-8"))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn parse_size(line: &str) -> io::Result<u64> {
+    // chunk extensions are allowed after a ';' and ignored
+    let size = line.split(';').next().unwrap_or_default().trim();
+    if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(invalid("invalid chunk size"));
+    }
+
+    u64::from_str_radix(size, 16).map_err(|_| invalid("chunk size too large"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    fn read_all(body: &[u8]) -> io::Result<(Vec<u8>, Vec<Header>)> {
+        let mut reader = ChunkedReader::new(body);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok((data, reader.trailers().to_vec()))
+    }
+
+    fn read_async(body: &[u8], max_size: usize, max_trailer_size: usize) -> io::Result<(Vec<u8>, Vec<Header>)> {
+        task::block_on(read_body(&mut async_std::io::BufReader::new(body), max_size, max_trailer_size))
+    }
+
+    #[test]
+    fn decodes_chunks_with_extensions_and_trailers() {
+        let body = b"4;name=value\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\nExpires: never\r\n\r\n";
+        let expected = (b"Wikipedia in\r\n\r\nchunks.".to_vec(), vec![Header::new("Expires", "never")]);
+
+        assert_eq!(read_all(body).unwrap(), expected);
+        assert_eq!(read_async(body, usize::MAX, usize::MAX).unwrap(), expected);
+    }
+
+    #[test]
+    fn reading_stops_after_the_last_chunk() {
+        let mut reader = ChunkedReader::new(&b"1\r\na\r\n0\r\n\r\nnext"[..]);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+
+        assert!(reader.is_done());
+        assert_eq!(reader.into_inner(), b"next");
+    }
+
+    #[test]
+    fn malformed_bodies_are_invalid_data() {
+        let cases: [&[u8]; 5] = [
+            b"x\r\n\r\n",
+            b"\r\n\r\n",
+            b"-1\r\n\r\n",
+            b"1\r\naX\r\n0\r\n\r\n",
+            b"10000000000000000\r\n",
+        ];
+
+        for body in cases {
+            assert_eq!(read_all(body).unwrap_err().kind(), io::ErrorKind::InvalidData, "{:?}", body);
+            assert_eq!(read_async(body, usize::MAX, usize::MAX).unwrap_err().kind(), io::ErrorKind::InvalidData, "{:?}", body);
+        }
+
+        let long_line = format!("1;{}\r\na\r\n0\r\n\r\n", "x".repeat(MAX_LINE_LEN));
+        assert_eq!(read_all(long_line.as_bytes()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_bodies_end_early() {
+        for body in [&b"5\r\nhel"[..], b"5\r\nhello\r\n", b"0\r\nExpires: never\r\n"] {
+            assert_eq!(read_all(body).unwrap_err().kind(), io::ErrorKind::UnexpectedEof, "{:?}", body);
+            assert_eq!(read_async(body, usize::MAX, usize::MAX).unwrap_err().kind(), io::ErrorKind::UnexpectedEof, "{:?}", body);
+        }
+    }
+
+    #[test]
+    fn limits_tell_the_body_from_the_trailers() {
+        let body = b"5\r\nhello\r\n6\r\n world\r\n0\r\nChecksum: abc\r\n\r\n";
+        assert!(read_async(body, 11, 13).is_ok());
+
+        let error = read_async(body, 10, 13).unwrap_err();
+        assert_eq!(too_large(&error), Some(TooLarge::Body));
+
+        let error = read_async(body, 11, 12).unwrap_err();
+        assert_eq!(too_large(&error), Some(TooLarge::Trailers));
+
+        assert_eq!(too_large(&invalid("invalid chunk size")), None);
+    }
+
+    #[test]
+    fn the_reader_limits_the_trailers() {
+        let mut body = b"0\r\n".to_vec();
+        for index in 0..MAX_TRAILER_SIZE / 16 {
+            body.extend_from_slice(format!("X-Trailer: {:05}\r\n", index).as_bytes());
+        }
+        body.extend_from_slice(b"\r\n");
+        assert_eq!(read_all(&body).unwrap().1.len(), MAX_TRAILER_SIZE / 16);
+
+        let mut body = body[..body.len() - 2].to_vec();
+        body.extend_from_slice(b"X-One-More: 1\r\n\r\n");
+        let error = read_all(&body).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(too_large(&error), Some(TooLarge::Trailers));
+    }
+
+    #[test]
+    fn the_decoder_hands_out_data_in_pieces() {
+        let mut reader = async_std::io::BufReader::new(&b"a\r\n0123456789\r\n0\r\n\r\n"[..]);
+        let mut decoder = ChunkedDecoder::new(usize::MAX);
+
+        let pieces: Vec<Vec<u8>> = task::block_on(async {
+            let mut pieces = Vec::new();
+            while let Some(piece) = decoder.next(&mut reader, 4).await.unwrap() {
+                pieces.push(piece);
+            }
+            pieces
+        });
+        assert_eq!(pieces, [b"0123".to_vec(), b"4567".to_vec(), b"89".to_vec()]);
+    }
+}
@@ -0,0 +1,540 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
+use std::io::{BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::debug;
+use crate::decoder::{Framing, ResponseHead};
+use crate::http::{Header, HEADER_AUTHORIZATION, HEADER_CONTENT_LENGTH, HEADER_CONTENT_TYPE, HEADER_COOKIE, HEADER_HOST, HEADER_LOCATION, HEADER_USER_AGENT, Method, Status};
+use crate::message::{HttpRequest, HttpResponse};
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const DEFAULT_MAX_IDLE_PER_HOST: usize = 8;
+const DEFAULT_MAX_BODY_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug)]
+pub enum ClientError {
+    InvalidUrl(String),
+    UnsupportedScheme(String),
+    Connect(io::Error),
+    Timeout,
+    Io(io::Error),
+    InvalidResponse(String),
+    TooManyRedirects,
+    BodyTooLarge,
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::InvalidUrl(url) => write!(f, "invalid url: {}", url),
+            ClientError::UnsupportedScheme(scheme) => write!(f, "unsupported scheme: {}", scheme),
+            ClientError::Connect(error) => write!(f, "failed to connect: {}", error),
+            ClientError::Timeout => write!(f, "request timed out"),
+            ClientError::Io(error) => write!(f, "{}", error),
+            ClientError::InvalidResponse(message) => write!(f, "invalid response: {}", message),
+            ClientError::TooManyRedirects => write!(f, "too many redirects"),
+            ClientError::BodyTooLarge => write!(f, "response body too large"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ClientError::Timeout,
+            io::ErrorKind::InvalidData => ClientError::InvalidResponse(error.to_string()),
+            _ => ClientError::Io(error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Url {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+
+    fn parse(url: &str) -> Result<Self, ClientError> {
+        let invalid = || ClientError::InvalidUrl(url.to_string());
+
+        let (scheme, rest) = url.trim().split_once("://").ok_or_else(invalid)?;
+        if !scheme.eq_ignore_ascii_case("http") {
+            return Err(ClientError::UnsupportedScheme(scheme.to_string()));
+        }
+
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+        if authority.is_empty() || authority.contains('@') {
+            return Err(invalid());
+        }
+
+        // the last ':' belongs to an IPv6 literal unless it follows the ']'
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse::<u16>().map_err(|_| invalid())?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        let path = match path {
+            "" => "/".to_string(),
+            path if path.starts_with('?') => format!("/{}", path),
+            path => path.to_string(),
+        };
+
+        Ok(Url {
+            host: host.to_ascii_lowercase(),
+            port,
+            path,
+        })
+    }
+
+    // Resolves a Location header against this url
+    fn join(&self, location: &str) -> Result<Self, ClientError> {
+        let location = location.trim();
+
+        if has_scheme(location) {
+            return Url::parse(location);
+        }
+        if let Some(rest) = location.strip_prefix("//") {
+            return Url::parse(&format!("http://{}", rest));
+        }
+
+        let location = location.split('#').next().unwrap_or_default();
+        let base = self.path.split('?').next().unwrap_or_default();
+
+        let path = if location.starts_with('/') {
+            location.to_string()
+        } else if location.starts_with('?') {
+            format!("{}{}", base, location)
+        } else {
+            let directory = &base[..base.rfind('/').map_or(0, |index| index + 1)];
+            format!("{}{}", directory, location)
+        };
+
+        Ok(Url {
+            host: self.host.clone(),
+            port: self.port,
+            path,
+        })
+    }
+
+    fn authority(&self) -> String {
+        match self.port {
+            80 => self.host.clone(),
+            port => format!("{}:{}", self.host, port),
+        }
+    }
+
+    fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+struct IdleConnection {
+    reader: BufReader<TcpStream>,
+    idle_since: Instant,
+}
+
+#[derive(Clone)]
+struct ClientConfig {
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_redirects: usize,
+    idle_timeout: Duration,
+    max_idle_per_host: usize,
+    max_body_size: u64,
+    user_agent: String,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_idle_per_host: DEFAULT_MAX_IDLE_PER_HOST,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+        }
+    }
+}
+
+// A blocking HTTP/1.1 client. Connections are kept alive and reused per host,
+// and clones of a client share the same pool.
+#[derive(Clone, Default)]
+pub struct Client {
+    config: ClientConfig,
+    pool: Arc<Mutex<HashMap<String, Vec<IdleConnection>>>>,
+}
+
+impl Client {
+
+    pub fn new() -> Self {
+        Client::default()
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder {
+            client: Client::default(),
+        }
+    }
+
+    pub fn get<U>(&self, url: U) -> RequestBuilder<'_> where U: Into<String> {
+        self.request(Method::Get, url)
+    }
+
+    pub fn post<U>(&self, url: U) -> RequestBuilder<'_> where U: Into<String> {
+        self.request(Method::Post, url)
+    }
+
+    pub fn put<U>(&self, url: U) -> RequestBuilder<'_> where U: Into<String> {
+        self.request(Method::Put, url)
+    }
+
+    pub fn delete<U>(&self, url: U) -> RequestBuilder<'_> where U: Into<String> {
+        self.request(Method::Delete, url)
+    }
+
+    pub fn head<U>(&self, url: U) -> RequestBuilder<'_> where U: Into<String> {
+        self.request(Method::Head, url)
+    }
+
+    pub fn request<U>(&self, method: Method, url: U) -> RequestBuilder<'_> where U: Into<String> {
+        RequestBuilder {
+            client: self,
+            method,
+            url: url.into(),
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    fn execute(&self, method: &Method, url: &str, headers: &[Header], body: Option<&[u8]>) -> Result<HttpResponse, ClientError> {
+        let mut method = method.clone();
+        let mut url = Url::parse(url)?;
+        let mut headers = headers.to_vec();
+        let mut body = body;
+        let mut redirects = 0;
+
+        loop {
+            let response = self.send(&method, &url, &headers, body)?;
+
+            let code = response.status.as_u16();
+            let location = match response.header(HEADER_LOCATION) {
+                Some(location) if matches!(code, 301 | 302 | 303 | 307 | 308) => location,
+                _ => return Ok(response),
+            };
+
+            // a limit of zero hands redirects back to the caller
+            if self.config.max_redirects == 0 {
+                return Ok(response);
+            }
+            if redirects == self.config.max_redirects {
+                return Err(ClientError::TooManyRedirects);
+            }
+            redirects += 1;
+
+            let next = url.join(location)?;
+            debug!("Following {} redirect to {}{}", code, next.authority(), next.path);
+
+            // 303, and historically 301/302 after a POST, continue as a GET without the body
+            if (code == 303 && method != Method::Head) || (matches!(code, 301 | 302) && method == Method::Post) {
+                method = Method::Get;
+                body = None;
+                headers.retain(|header| !header.key().eq_ignore_ascii_case(HEADER_CONTENT_TYPE));
+            }
+
+            // credentials are only meant for the host they were given to
+            if next.address() != url.address() {
+                headers.retain(|header| {
+                    !header.key().eq_ignore_ascii_case(HEADER_AUTHORIZATION) && !header.key().eq_ignore_ascii_case(HEADER_COOKIE)
+                });
+            }
+
+            url = next;
+        }
+    }
+
+    fn send(&self, method: &Method, url: &Url, headers: &[Header], body: Option<&[u8]>) -> Result<HttpResponse, ClientError> {
+        let request = self.build_request(method, url, headers, body);
+
+        // the server may have closed a pooled connection while it sat idle,
+        // which only shows once we use it, so requests that are safe to
+        // repeat get another go on a fresh connection
+        if let Some(reader) = self.checkout(url) {
+            match self.exchange(reader, &request) {
+                Ok((response, reader)) => {
+                    self.checkin(url, reader);
+                    return Ok(response);
+                }
+                Err(error) if is_stale(&error) && method.is_idempotent() => {
+                    debug!("Pooled connection to {} was closed, reconnecting", url.address());
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        let reader = BufReader::new(self.connect(url)?);
+        let (response, reader) = self.exchange(reader, &request)?;
+        self.checkin(url, reader);
+        Ok(response)
+    }
+
+    fn connect(&self, url: &Url) -> Result<TcpStream, ClientError> {
+        let addresses = url.address().to_socket_addrs().map_err(ClientError::Connect)?;
+
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "host did not resolve");
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, self.config.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(error) if error.kind() == io::ErrorKind::TimedOut => return Err(ClientError::Timeout),
+                Err(error) => last_error = error,
+            }
+        }
+
+        Err(ClientError::Connect(last_error))
+    }
+
+    fn checkout(&self, url: &Url) -> Option<BufReader<TcpStream>> {
+        let mut pool = self.pool.lock().unwrap();
+        let idle = pool.get_mut(&url.address())?;
+
+        while let Some(connection) = idle.pop() {
+            if connection.idle_since.elapsed() < self.config.idle_timeout {
+                return Some(connection.reader);
+            }
+        }
+
+        None
+    }
+
+    fn checkin(&self, url: &Url, reader: Option<BufReader<TcpStream>>) {
+        let Some(reader) = reader else { return };
+
+        let mut pool = self.pool.lock().unwrap();
+        let idle = pool.entry(url.address()).or_default();
+
+        idle.retain(|connection| connection.idle_since.elapsed() < self.config.idle_timeout);
+        if idle.len() < self.config.max_idle_per_host {
+            idle.push(IdleConnection { reader, idle_since: Instant::now() });
+        }
+    }
+
+    fn build_request(&self, method: &Method, url: &Url, headers: &[Header], body: Option<&[u8]>) -> HttpRequest {
+        let mut builder = HttpRequest::builder();
+        builder.method(method.clone()).path(url.path.clone());
+
+        if header_value(headers, HEADER_HOST).is_none() {
+            builder.header(HEADER_HOST, &url.authority());
+        }
+
+        // the builder works out the Content-Length from the body
+        for header in headers.iter().filter(|header| !header.key().eq_ignore_ascii_case(HEADER_CONTENT_LENGTH)) {
+            builder.header(header.key(), header.value());
+        }
+
+        if header_value(headers, HEADER_USER_AGENT).is_none() {
+            builder.header(HEADER_USER_AGENT, &self.config.user_agent);
+        }
+
+        if let Some(body) = body {
+            builder.body(body.to_vec());
+        } else if matches!(method, Method::Post | Method::Put | Method::Patch) {
+            builder.header(HEADER_CONTENT_LENGTH, "0");
+        }
+
+        builder.build()
+    }
+
+    // Sends one request and reads its response, handing the connection back
+    // when it can carry another request
+    fn exchange(&self, mut reader: BufReader<TcpStream>, request: &HttpRequest) -> Result<(HttpResponse, Option<BufReader<TcpStream>>), ClientError> {
+        let mut stream = reader.get_ref();
+        stream.set_read_timeout(Some(self.config.timeout))?;
+        stream.set_write_timeout(Some(self.config.timeout))?;
+
+        stream.write_all(&request.to_bytes())?;
+        stream.flush()?;
+
+        // interim responses carry nothing the caller could act on
+        let head = loop {
+            let head = ResponseHead::read(&mut reader)?;
+            if !head.is_interim() {
+                break head;
+            }
+        };
+
+        let reusable = head.status != Status::SwitchingProtocols
+            && head.keeps_alive()
+            && head.framing(request.method())? != Framing::Close;
+
+        // the rest of an oversized body is never read, so the connection is dropped
+        let response = HttpResponse::decode_limited(head, &mut reader, request.method(), self.config.max_body_size)?
+            .ok_or(ClientError::BodyTooLarge)?;
+        Ok((response, if reusable { Some(reader) } else { None }))
+    }
+}
+
+pub struct ClientBuilder {
+    client: Client,
+}
+
+impl ClientBuilder {
+
+    pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.client.config.connect_timeout = timeout;
+        self
+    }
+
+    // Applies to every read and write on a connection
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.client.config.timeout = timeout;
+        self
+    }
+
+    // Zero returns redirect responses instead of following them
+    pub fn max_redirects(&mut self, max_redirects: usize) -> &mut Self {
+        self.client.config.max_redirects = max_redirects;
+        self
+    }
+
+    pub fn idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.client.config.idle_timeout = timeout;
+        self
+    }
+
+    pub fn max_idle_per_host(&mut self, max_idle: usize) -> &mut Self {
+        self.client.config.max_idle_per_host = max_idle;
+        self
+    }
+
+    // Responses with a larger body fail with BodyTooLarge. Defaults to 64 MiB.
+    pub fn max_body_size(&mut self, max_body_size: u64) -> &mut Self {
+        self.client.config.max_body_size = max_body_size;
+        self
+    }
+
+    pub fn user_agent<U>(&mut self, user_agent: U) -> &mut Self where U: Into<String> {
+        self.client.config.user_agent = user_agent.into();
+        self
+    }
+
+    pub fn build(&self) -> Client {
+        Client {
+            config: self.client.config.clone(),
+            pool: Arc::default(),
+        }
+    }
+}
+
+pub struct RequestBuilder<'a> {
+    client: &'a Client,
+    method: Method,
+    url: String,
+    headers: Vec<Header>,
+    body: Option<Vec<u8>>,
+}
+
+impl RequestBuilder<'_> {
+
+    pub fn header(&mut self, key: &str, value: &str) -> &mut Self {
+        self.headers.push(Header::new(key, value));
+        self
+    }
+
+    pub fn body(&mut self, body: Vec<u8>) -> &mut Self {
+        self.body = Some(body);
+        self
+    }
+
+    pub fn send(&self) -> Result<HttpResponse, ClientError> {
+        self.client.execute(&self.method, &self.url, &self.headers, self.body.as_deref())
+    }
+}
+
+fn is_stale(error: &ClientError) -> bool {
+    match error {
+        ClientError::Io(error) => matches!(error.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted),
+        _ => false,
+    }
+}
+
+fn has_scheme(location: &str) -> bool {
+    match location.split_once(':') {
+        Some((scheme, _)) => scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')),
+        None => false,
+    }
+}
+
+fn header_value<'a>(headers: &'a [Header], key: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|header| header.key().eq_ignore_ascii_case(key))
+        .map(|header| header.value())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn join(base: &str, location: &str) -> String {
+        let url = Url::parse(base).unwrap().join(location).unwrap();
+        format!("{}{}", url.address(), url.path)
+    }
+
+    #[test]
+    fn parses_urls() {
+        let url = Url::parse("http://Example.org:8080/a/b?c=d#frag").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("example.org", 8080, "/a/b?c=d"));
+        assert_eq!(url.authority(), "example.org:8080");
+
+        assert_eq!(Url::parse("http://example.org").unwrap().path, "/");
+        assert_eq!(Url::parse("http://example.org?q").unwrap().path, "/?q");
+        assert_eq!(Url::parse("http://[::1]:81/").unwrap().address(), "[::1]:81");
+        assert_eq!(Url::parse("http://[::1]/").unwrap().address(), "[::1]:80");
+
+        assert!(matches!(Url::parse("https://example.org/"), Err(ClientError::UnsupportedScheme(_))));
+        assert!(matches!(Url::parse("http://user@example.org/"), Err(ClientError::InvalidUrl(_))));
+        assert!(matches!(Url::parse("http://example.org:port/"), Err(ClientError::InvalidUrl(_))));
+        assert!(matches!(Url::parse("example.org/"), Err(ClientError::InvalidUrl(_))));
+    }
+
+    #[test]
+    fn joins_locations() {
+        let base = "http://example.org/docs/guide/intro?page=1";
+
+        assert_eq!(join(base, "http://other.org:81/x"), "other.org:81/x");
+        assert_eq!(join(base, "//other.org/x"), "other.org:80/x");
+        assert_eq!(join(base, "/root"), "example.org:80/root");
+        assert_eq!(join(base, "next"), "example.org:80/docs/guide/next");
+        assert_eq!(join(base, "next#section"), "example.org:80/docs/guide/next");
+        assert_eq!(join(base, "?page=2"), "example.org:80/docs/guide/intro?page=2");
+        assert_eq!(join("http://example.org:8080", "relative"), "example.org:8080/relative");
+
+        assert!(Url::parse(base).unwrap().join("ftp://example.org/").is_err());
+    }
+
+    #[test]
+    fn recognizes_schemes() {
+        assert!(has_scheme("http://example.org"));
+        assert!(has_scheme("svn+ssh:x"));
+        assert!(!has_scheme("/path:with:colons"));
+        assert!(!has_scheme("1abc:x"));
+        assert!(!has_scheme("relative"));
+    }
+}
@@ -0,0 +1,564 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, Payload};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use crate::http::is_token;
+
+const COOKIE_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+const SIGNING_KEY_CONTEXT: &[u8] = b"libhttp cookie signing key";
+const ENCRYPTION_KEY_CONTEXT: &[u8] = b"libhttp cookie encryption key";
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl Display for SameSite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SameSite::Strict => write!(f, "Strict"),
+            SameSite::Lax => write!(f, "Lax"),
+            SameSite::None => write!(f, "None"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CookieError {
+    InvalidName(String),
+    InvalidValue(String),
+    InvalidDomain(String),
+    InvalidPath(String),
+}
+
+impl Display for CookieError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CookieError::InvalidName(name) => write!(f, "invalid cookie name: {:?}", name),
+            CookieError::InvalidValue(value) => write!(f, "invalid cookie value: {:?}", value),
+            CookieError::InvalidDomain(domain) => write!(f, "invalid cookie domain: {:?}", domain),
+            CookieError::InvalidPath(path) => write!(f, "invalid cookie path: {:?}", path),
+        }
+    }
+}
+
+impl Error for CookieError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    domain: Option<String>,
+    path: Option<String>,
+    expires: Option<DateTime<Utc>>,
+    max_age: Option<i64>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+    partitioned: bool,
+}
+
+impl Cookie {
+
+    // Names must be tokens and values cookie-octets (RFC 6265 4.1.1), so
+    // nothing can break out of the Set-Cookie header or add attributes
+    pub fn new<N, V>(name: N, value: V) -> Result<Self, CookieError>
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        let cookie = Self::unchecked(name, value);
+        cookie.validate()?;
+        Ok(cookie)
+    }
+
+    // Cookies sent by clients are taken as they come
+    fn unchecked<N, V>(name: N, value: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        Cookie {
+            name: name.into(),
+            value: value.into(),
+            domain: None,
+            path: None,
+            expires: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+            partitioned: false,
+        }
+    }
+
+    pub fn builder<N, V>(name: N, value: V) -> CookieBuilder
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        CookieBuilder {
+            cookie: Cookie::unchecked(name, value),
+        }
+    }
+
+    // Parses the value of a Cookie request header, e.g. "a=1; b=2"
+    pub fn parse(header_value: &str) -> Vec<Cookie> {
+        header_value.split(';')
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                let name = name.trim();
+                if name.is_empty() {
+                    return None;
+                }
+
+                // strip optional DQUOTEs around the value
+                let value = value.trim();
+                let value = value.strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value);
+
+                Some(Cookie::unchecked(name, value))
+            })
+            .collect()
+    }
+
+    fn validate(&self) -> Result<(), CookieError> {
+        if !is_token(&self.name) {
+            return Err(CookieError::InvalidName(self.name.clone()));
+        }
+
+        // the value may be wrapped in DQUOTEs
+        let value = self.value.strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(&self.value);
+        if !value.bytes().all(is_cookie_octet) {
+            return Err(CookieError::InvalidValue(self.value.clone()));
+        }
+
+        if let Some(domain) = self.domain.as_ref().filter(|domain| !is_attribute_value(domain)) {
+            return Err(CookieError::InvalidDomain(domain.clone()));
+        }
+        if let Some(path) = self.path.as_ref().filter(|path| !is_attribute_value(path)) {
+            return Err(CookieError::InvalidPath(path.clone()));
+        }
+
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    pub fn expires(&self) -> Option<DateTime<Utc>> {
+        self.expires
+    }
+
+    pub fn max_age(&self) -> Option<i64> {
+        self.max_age
+    }
+
+    pub fn secure(&self) -> bool {
+        self.secure
+    }
+
+    pub fn http_only(&self) -> bool {
+        self.http_only
+    }
+
+    pub fn same_site(&self) -> Option<&SameSite> {
+        self.same_site.as_ref()
+    }
+
+    pub fn partitioned(&self) -> bool {
+        self.partitioned
+    }
+}
+
+// Formats the cookie as a Set-Cookie header value
+impl Display for Cookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+
+        if let Some(expires) = &self.expires {
+            write!(f, "; Expires={}", expires.format(COOKIE_DATE_FORMAT))?;
+        }
+
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+
+        if let Some(same_site) = &self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+
+        if self.partitioned {
+            write!(f, "; Partitioned")?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct CookieBuilder {
+    cookie: Cookie,
+}
+
+impl CookieBuilder {
+
+    pub fn domain<D>(&mut self, domain: D) -> &mut Self where D: Into<String> {
+        self.cookie.domain = Some(domain.into());
+        self
+    }
+
+    pub fn path<P>(&mut self, path: P) -> &mut Self where P: Into<String> {
+        self.cookie.path = Some(path.into());
+        self
+    }
+
+    pub fn expires(&mut self, expires: DateTime<Utc>) -> &mut Self {
+        self.cookie.expires = Some(expires);
+        self
+    }
+
+    pub fn max_age(&mut self, seconds: i64) -> &mut Self {
+        self.cookie.max_age = Some(seconds);
+        self
+    }
+
+    pub fn secure(&mut self, secure: bool) -> &mut Self {
+        self.cookie.secure = secure;
+        self
+    }
+
+    pub fn http_only(&mut self, http_only: bool) -> &mut Self {
+        self.cookie.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(&mut self, same_site: SameSite) -> &mut Self {
+        self.cookie.same_site = Some(same_site);
+        self
+    }
+
+    // Partitioned cookies are only accepted by browsers when also marked Secure
+    pub fn partitioned(&mut self, partitioned: bool) -> &mut Self {
+        self.cookie.partitioned = partitioned;
+        if partitioned {
+            self.cookie.secure = true;
+        }
+        self
+    }
+
+    pub fn build(&self) -> Result<Cookie, CookieError> {
+        self.cookie.validate()?;
+        Ok(self.cookie.clone())
+    }
+}
+
+// US-ASCII visible characters other than DQUOTE, comma, semicolon and backslash
+fn is_cookie_octet(byte: u8) -> bool {
+    matches!(byte, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+// Domain and Path may hold anything but control characters and semicolons
+fn is_attribute_value(value: &str) -> bool {
+    value.bytes().all(|byte| !byte.is_ascii_control() && byte != b';')
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+
+    pub fn new() -> Self {
+        CookieJar::default()
+    }
+
+    pub fn add(&mut self, cookie: Cookie) {
+        self.cookies.push(cookie);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Cookie> {
+        self.cookies.iter().find(|cookie| cookie.name == name)
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Cookie> + 'a {
+        self.cookies.iter().filter(move |cookie| cookie.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cookie> {
+        self.cookies.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cookies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+
+    // Returns the cookie with its signature verified and removed
+    pub fn get_signed(&self, name: &str, keys: &CookieKeys) -> Option<Cookie> {
+        self.get_all(name).find_map(|cookie| keys.verify(cookie))
+    }
+
+    // Returns the cookie with its value decrypted and authenticated
+    pub fn get_private(&self, name: &str, keys: &CookieKeys) -> Option<Cookie> {
+        self.get_all(name).find_map(|cookie| keys.decrypt(cookie))
+    }
+}
+
+#[derive(Clone)]
+pub struct CookieKey {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl CookieKey {
+
+    // Derives independent signing and encryption keys from a server secret
+    pub fn from_secret(secret: &[u8]) -> Self {
+        CookieKey {
+            signing: Self::derive(secret, SIGNING_KEY_CONTEXT),
+            encryption: Self::derive(secret, ENCRYPTION_KEY_CONTEXT),
+        }
+    }
+
+    pub fn generate() -> Self {
+        let mut secret = [0u8; 64];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self::from_secret(&secret)
+    }
+
+    fn derive(secret: &[u8], context: &[u8]) -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("hmac accepts any key length");
+        mac.update(context);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn mac(&self, name: &str, value: &str) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.signing).expect("hmac accepts any key length");
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new_from_slice(&self.encryption).expect("key is 32 bytes")
+    }
+}
+
+// The first key signs and encrypts new cookies, any of the keys is accepted
+// when reading them back so that secrets can be rotated without logging
+// everyone out.
+#[derive(Clone)]
+pub struct CookieKeys {
+    keys: Vec<CookieKey>,
+}
+
+impl CookieKeys {
+
+    pub fn new(current: CookieKey) -> Self {
+        CookieKeys {
+            keys: vec![current],
+        }
+    }
+
+    pub fn with_previous(mut self, previous: CookieKey) -> Self {
+        self.keys.push(previous);
+        self
+    }
+
+    // Makes a new key current, keeping the old one around for verification
+    pub fn rotate(&mut self, current: CookieKey) {
+        self.keys.insert(0, current);
+    }
+
+    pub fn sign(&self, mut cookie: Cookie) -> Cookie {
+        let tag = self.keys[0].mac(&cookie.name, &cookie.value).finalize().into_bytes();
+        cookie.value = format!("{}.{}", cookie.value, URL_SAFE_NO_PAD.encode(tag));
+        cookie
+    }
+
+    pub fn verify(&self, cookie: &Cookie) -> Option<Cookie> {
+        let (value, tag) = cookie.value.rsplit_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+
+        self.keys.iter()
+            .find(|key| key.mac(&cookie.name, value).verify_slice(&tag).is_ok())
+            .map(|_| {
+                let mut verified = cookie.clone();
+                verified.value = value.to_string();
+                verified
+            })
+    }
+
+    pub fn encrypt(&self, mut cookie: Cookie) -> Cookie {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        // the cookie name is authenticated so values can't be moved between cookies
+        let payload = Payload {
+            msg: cookie.value.as_bytes(),
+            aad: cookie.name.as_bytes(),
+        };
+        let ciphertext = self.keys[0].cipher()
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .expect("encryption to succeed");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        cookie.value = URL_SAFE_NO_PAD.encode(sealed);
+        cookie
+    }
+
+    pub fn decrypt(&self, cookie: &Cookie) -> Option<Cookie> {
+        let sealed = URL_SAFE_NO_PAD.decode(&cookie.value).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+        let plaintext = self.keys.iter().find_map(|key| {
+            let payload = Payload {
+                msg: ciphertext,
+                aad: cookie.name.as_bytes(),
+            };
+            key.cipher().decrypt(Nonce::from_slice(nonce), payload).ok()
+        })?;
+
+        let mut decrypted = cookie.clone();
+        decrypted.value = String::from_utf8(plaintext).ok()?;
+        Some(decrypted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_names_that_are_not_tokens() {
+        assert!(matches!(Cookie::new("a b", "1"), Err(CookieError::InvalidName(_))));
+        assert!(matches!(Cookie::new("a=b", "1"), Err(CookieError::InvalidName(_))));
+        assert!(matches!(Cookie::new("", "1"), Err(CookieError::InvalidName(_))));
+    }
+
+    #[test]
+    fn rejects_values_that_could_inject_headers_or_attributes() {
+        assert!(matches!(Cookie::new("id", "1\r\nSet-Cookie: admin=1"), Err(CookieError::InvalidValue(_))));
+        assert!(matches!(Cookie::new("id", "1; Domain=evil.example"), Err(CookieError::InvalidValue(_))));
+        assert!(matches!(Cookie::new("id", "a,b"), Err(CookieError::InvalidValue(_))));
+        assert!(matches!(Cookie::new("id", "caf\u{e9}"), Err(CookieError::InvalidValue(_))));
+    }
+
+    #[test]
+    fn rejects_attributes_with_semicolons_or_controls() {
+        assert!(matches!(Cookie::builder("id", "1").path("/; Secure").build(), Err(CookieError::InvalidPath(_))));
+        assert!(matches!(Cookie::builder("id", "1").domain("a.example\n").build(), Err(CookieError::InvalidDomain(_))));
+    }
+
+    #[test]
+    fn accepts_cookie_octets_and_quoted_values() {
+        assert_eq!(Cookie::new("id", "abc-123_./:").unwrap().value(), "abc-123_./:");
+        assert_eq!(Cookie::new("id", "\"quoted\"").unwrap().value(), "\"quoted\"");
+        assert_eq!(Cookie::new("empty", "").unwrap().to_string(), "empty=");
+    }
+
+    #[test]
+    fn formats_set_cookie_attributes() {
+        let cookie = Cookie::builder("id", "42")
+            .path("/")
+            .max_age(60)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .partitioned(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(cookie.to_string(), "id=42; Path=/; Max-Age=60; Secure; HttpOnly; SameSite=Lax; Partitioned");
+    }
+
+    #[test]
+    fn parses_request_cookies_leniently() {
+        let cookies = Cookie::parse("a=1; b=\"two\"; =skipped; c=x y");
+        let pairs: Vec<(&str, &str)> = cookies.iter().map(|cookie| (cookie.name(), cookie.value())).collect();
+        assert_eq!(pairs, vec![("a", "1"), ("b", "two"), ("c", "x y")]);
+    }
+
+    #[test]
+    fn signed_cookies_round_trip_and_detect_tampering() {
+        let keys = CookieKeys::new(CookieKey::from_secret(b"secret"));
+        let signed = keys.sign(Cookie::new("id", "42").unwrap());
+        assert!(Cookie::new(signed.name(), signed.value()).is_ok());
+        assert_eq!(keys.verify(&signed).unwrap().value(), "42");
+
+        let (_, tag) = signed.value().rsplit_once('.').unwrap();
+        let forged = Cookie::new("id", format!("43.{}", tag)).unwrap();
+        assert!(keys.verify(&forged).is_none());
+    }
+
+    #[test]
+    fn private_cookies_round_trip_and_are_bound_to_the_name() {
+        let keys = CookieKeys::new(CookieKey::from_secret(b"secret"));
+        let sealed = keys.encrypt(Cookie::new("id", "42").unwrap());
+        assert_ne!(sealed.value(), "42");
+        assert_eq!(keys.decrypt(&sealed).unwrap().value(), "42");
+
+        let moved = Cookie::new("other", sealed.value()).unwrap();
+        assert!(keys.decrypt(&moved).is_none());
+    }
+
+    #[test]
+    fn rotated_keys_still_verify_old_cookies() {
+        let old = CookieKey::from_secret(b"old");
+        let signed = CookieKeys::new(old.clone()).sign(Cookie::new("id", "42").unwrap());
+
+        let mut keys = CookieKeys::new(old);
+        keys.rotate(CookieKey::from_secret(b"new"));
+        assert_eq!(keys.verify(&signed).unwrap().value(), "42");
+    }
+}
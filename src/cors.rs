@@ -0,0 +1,346 @@
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::http::{Header, HEADER_ACCESS_CONTROL_ALLOW_CREDENTIALS, HEADER_ACCESS_CONTROL_ALLOW_HEADERS, HEADER_ACCESS_CONTROL_ALLOW_METHODS, HEADER_ACCESS_CONTROL_ALLOW_ORIGIN, HEADER_ACCESS_CONTROL_EXPOSE_HEADERS, HEADER_ACCESS_CONTROL_MAX_AGE, HEADER_ACCESS_CONTROL_REQUEST_HEADERS, HEADER_ACCESS_CONTROL_REQUEST_METHOD, HEADER_ORIGIN, HEADER_VARY, Method, Status};
+use crate::message::{HttpRequest, HttpResponse};
+use crate::server::{HttpHandler, options_response};
+
+#[derive(Clone)]
+pub enum AllowedOrigins {
+    Any,
+    Exact(String),
+    List(Vec<String>),
+    Predicate(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl AllowedOrigins {
+    fn allows(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigins::Any => true,
+            AllowedOrigins::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+            AllowedOrigins::List(allowed) => allowed.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)),
+            AllowedOrigins::Predicate(predicate) => predicate(origin),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum AllowedHeaders {
+    Any,
+    List(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CorsError {
+    // credentials may only be shared with origins that were named or checked
+    CredentialsWithAnyOrigin,
+}
+
+impl Display for CorsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CorsError::CredentialsWithAnyOrigin => write!(f, "credentials can't be allowed for any origin"),
+        }
+    }
+}
+
+impl std::error::Error for CorsError {}
+
+#[derive(Clone)]
+struct CorsConfig {
+    origins: AllowedOrigins,
+    methods: Vec<Method>,
+    headers: AllowedHeaders,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            origins: AllowedOrigins::Any,
+            methods: vec![Method::Get, Method::Head, Method::Post],
+            headers: AllowedHeaders::List(Vec::new()),
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+// Wraps a handler, answering CORS preflight requests itself and adding the
+// Access-Control-* headers to the responses of allowed cross-origin requests.
+pub struct Cors {
+    handler: Arc<Mutex<dyn HttpHandler>>,
+    config: CorsConfig,
+}
+
+impl Cors {
+
+    pub fn builder(handler: Arc<Mutex<dyn HttpHandler>>) -> CorsBuilder {
+        CorsBuilder {
+            handler,
+            config: CorsConfig::default(),
+        }
+    }
+
+    fn is_preflight(request: &HttpRequest) -> bool {
+        *request.method() == Method::Options && request.header(HEADER_ACCESS_CONTROL_REQUEST_METHOD).is_some()
+    }
+
+    fn preflight(&self, request: &HttpRequest, origin: &str) -> HttpResponse {
+
+        let vary = Header::new(HEADER_VARY, format!("{}, {}, {}", HEADER_ORIGIN, HEADER_ACCESS_CONTROL_REQUEST_METHOD, HEADER_ACCESS_CONTROL_REQUEST_HEADERS));
+
+        let method_allowed = request.header(HEADER_ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| method.parse::<Method>().ok())
+            .is_some_and(|method| self.config.methods.contains(&method));
+
+        let requested_headers: Vec<&str> = request.header(HEADER_ACCESS_CONTROL_REQUEST_HEADERS)
+            .map(|value| value.split(',').map(str::trim).filter(|header| !header.is_empty()).collect())
+            .unwrap_or_default();
+
+        let headers_allowed = match &self.config.headers {
+            AllowedHeaders::Any => true,
+            AllowedHeaders::List(allowed) => requested_headers.iter()
+                .all(|requested| allowed.iter().any(|header| header.eq_ignore_ascii_case(requested))),
+        };
+
+        if !self.config.origins.allows(origin) || !method_allowed || !headers_allowed {
+            return HttpResponse::new(Status::Forbidden, vec![vary], None);
+        }
+
+        let mut headers = self.origin_headers(origin);
+
+        let methods: Vec<String> = self.config.methods.iter().map(|method| method.to_string()).collect();
+        headers.push(Header::new(HEADER_ACCESS_CONTROL_ALLOW_METHODS, methods.join(", ")));
+
+        let allow_headers = match &self.config.headers {
+            AllowedHeaders::Any => requested_headers.join(", "),
+            AllowedHeaders::List(allowed) => allowed.join(", "),
+        };
+        if !allow_headers.is_empty() {
+            headers.push(Header::new(HEADER_ACCESS_CONTROL_ALLOW_HEADERS, allow_headers));
+        }
+
+        if let Some(max_age) = self.config.max_age {
+            headers.push(Header::new(HEADER_ACCESS_CONTROL_MAX_AGE, max_age.as_secs().to_string()));
+        }
+
+        headers.push(vary);
+
+        HttpResponse::new(Status::NoContent, headers, None)
+    }
+
+    fn origin_headers(&self, origin: &str) -> Vec<Header> {
+        let mut headers = Vec::new();
+
+        let allow_origin = match self.config.origins {
+            AllowedOrigins::Any => "*",
+            _ => origin,
+        };
+        headers.push(Header::new(HEADER_ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin));
+
+        if self.config.credentials {
+            headers.push(Header::new(HEADER_ACCESS_CONTROL_ALLOW_CREDENTIALS, "true"));
+        }
+
+        headers
+    }
+
+    // The response depends on the Origin unless every origin gets the same "*"
+    fn varies_by_origin(&self) -> bool {
+        !matches!(self.config.origins, AllowedOrigins::Any)
+    }
+}
+
+impl HttpHandler for Cors {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+
+        let origin = request.header(HEADER_ORIGIN).map(str::to_string);
+
+        if let Some(origin) = &origin {
+            if Self::is_preflight(request) {
+                return self.preflight(request, origin);
+            }
+        }
+
+        let mut response = {
+            let mut handler = self.handler.lock().unwrap();
+            // plain OPTIONS requests the inner handler leaves to the server
+            if *request.method() == Method::Options && !handler.supports_method(&Method::Options) {
+                options_response(&handler.allowed_methods(request.path()))
+            } else {
+                handler.handle(request)
+            }
+        };
+
+        if self.varies_by_origin() {
+            response.add_vary(HEADER_ORIGIN);
+        }
+
+        if let Some(origin) = origin.filter(|origin| self.config.origins.allows(origin)) {
+            for header in self.origin_headers(&origin) {
+                response.set_header(header.key, header.value);
+            }
+
+            if !self.config.expose_headers.is_empty() {
+                response.set_header(HEADER_ACCESS_CONTROL_EXPOSE_HEADERS, self.config.expose_headers.join(", "));
+            }
+        }
+
+        response
+    }
+
+    // preflight requests arrive as OPTIONS, so they must reach this handler
+    fn supports_method(&self, method: &Method) -> bool {
+        *method == Method::Options || self.handler.lock().unwrap().supports_method(method)
+    }
+
+    fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        self.handler.lock().unwrap().allowed_methods(path)
+    }
+
+    fn expect_continue(&mut self, request: &HttpRequest) -> Option<HttpResponse> {
+        self.handler.lock().unwrap().expect_continue(request)
+    }
+
+    fn stream_body(&self, request: &HttpRequest) -> bool {
+        self.handler.lock().unwrap().stream_body(request)
+    }
+
+    fn fork(&self) -> Option<Box<dyn HttpHandler>> {
+        let handler = self.handler.lock().unwrap().fork()?;
+        Some(Box::new(Cors {
+            handler: Arc::new(Mutex::new(handler)),
+            config: self.config.clone(),
+        }))
+    }
+}
+
+pub struct CorsBuilder {
+    handler: Arc<Mutex<dyn HttpHandler>>,
+    config: CorsConfig,
+}
+
+impl CorsBuilder {
+
+    pub fn allow_any_origin(&mut self) -> &mut Self {
+        self.config.origins = AllowedOrigins::Any;
+        self
+    }
+
+    pub fn allow_origin<O>(&mut self, origin: O) -> &mut Self where O: Into<String> {
+        self.config.origins = AllowedOrigins::Exact(origin.into());
+        self
+    }
+
+    pub fn allow_origins<O>(&mut self, origins: Vec<O>) -> &mut Self where O: Into<String> {
+        self.config.origins = AllowedOrigins::List(origins.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn allow_origin_fn<F>(&mut self, predicate: F) -> &mut Self where F: Fn(&str) -> bool + Send + Sync + 'static {
+        self.config.origins = AllowedOrigins::Predicate(Arc::new(predicate));
+        self
+    }
+
+    pub fn allow_methods(&mut self, methods: Vec<Method>) -> &mut Self {
+        self.config.methods = methods;
+        self
+    }
+
+    pub fn allow_headers<H>(&mut self, headers: Vec<H>) -> &mut Self where H: Into<String> {
+        self.config.headers = AllowedHeaders::List(headers.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn allow_any_header(&mut self) -> &mut Self {
+        self.config.headers = AllowedHeaders::Any;
+        self
+    }
+
+    pub fn expose_headers<H>(&mut self, headers: Vec<H>) -> &mut Self where H: Into<String> {
+        self.config.expose_headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn allow_credentials(&mut self, credentials: bool) -> &mut Self {
+        self.config.credentials = credentials;
+        self
+    }
+
+    pub fn max_age(&mut self, max_age: Duration) -> &mut Self {
+        self.config.max_age = Some(max_age);
+        self
+    }
+
+    pub fn build(&self) -> Result<Cors, CorsError> {
+        if self.config.credentials && matches!(self.config.origins, AllowedOrigins::Any) {
+            return Err(CorsError::CredentialsWithAnyOrigin);
+        }
+
+        Ok(Cors {
+            handler: self.handler.clone(),
+            config: self.config.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Hello;
+
+    impl HttpHandler for Hello {
+        fn handle(&mut self, _request: &HttpRequest) -> HttpResponse {
+            HttpResponse::new(Status::Ok, vec![], Some(b"hello".to_vec()))
+        }
+    }
+
+    fn handler() -> Arc<Mutex<dyn HttpHandler>> {
+        Arc::new(Mutex::new(Hello))
+    }
+
+    fn request(origin: &str) -> HttpRequest {
+        HttpRequest::builder().method(Method::Get).path("/").header(HEADER_ORIGIN, origin).build()
+    }
+
+    #[test]
+    fn credentials_require_named_origins() {
+        let error = Cors::builder(handler()).allow_credentials(true).build().err();
+        assert_eq!(error, Some(CorsError::CredentialsWithAnyOrigin));
+
+        assert!(Cors::builder(handler()).allow_credentials(true).allow_origin("https://a.example").build().is_ok());
+        assert!(Cors::builder(handler()).allow_credentials(true).allow_origin_fn(|origin| origin.ends_with(".example")).build().is_ok());
+    }
+
+    #[test]
+    fn credentials_echo_only_allowed_origins() {
+        let mut cors = Cors::builder(handler())
+            .allow_origins(vec!["https://a.example"])
+            .allow_credentials(true)
+            .build()
+            .unwrap();
+
+        let response = cors.handle(&request("https://a.example"));
+        assert_eq!(response.header(HEADER_ACCESS_CONTROL_ALLOW_ORIGIN), Some("https://a.example"));
+        assert_eq!(response.header(HEADER_ACCESS_CONTROL_ALLOW_CREDENTIALS), Some("true"));
+        assert_eq!(response.header(HEADER_VARY), Some(HEADER_ORIGIN));
+
+        let response = cors.handle(&request("https://evil.example"));
+        assert_eq!(response.header(HEADER_ACCESS_CONTROL_ALLOW_ORIGIN), None);
+        assert_eq!(response.header(HEADER_ACCESS_CONTROL_ALLOW_CREDENTIALS), None);
+    }
+
+    #[test]
+    fn any_origin_gets_a_wildcard() {
+        let mut cors = Cors::builder(handler()).build().unwrap();
+
+        let response = cors.handle(&request("https://a.example"));
+        assert_eq!(response.header(HEADER_ACCESS_CONTROL_ALLOW_ORIGIN), Some("*"));
+        assert_eq!(response.header(HEADER_VARY), None);
+    }
+}
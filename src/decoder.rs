@@ -0,0 +1,268 @@
+use std::io;
+use std::io::{BufRead, Read, Take};
+use crate::chunked::ChunkedReader;
+use crate::http::{CONNECTION_CLOSE, CONNECTION_KEEP_ALIVE, Header, HEADER_CONNECTION, HEADER_CONTENT_LENGTH, HEADER_TRANSFER_ENCODING, HTTP_VERSION_1_0, Method, Status};
+
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+// The status line and headers of a response read from the wire
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: Status,
+    pub reason: String,
+    pub headers: Vec<Header>,
+}
+
+impl ResponseHead {
+
+    pub fn read<R>(reader: &mut R) -> io::Result<Self> where R: BufRead {
+        let status_line = read_line(reader)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before response"))?;
+
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or_default().to_string();
+        let status = parts.next()
+            .filter(|code| code.len() == 3)
+            .and_then(|code| code.parse::<u16>().ok())
+            .and_then(|code| Status::try_from(code).ok())
+            .filter(|_| version.starts_with("HTTP/1."))
+            .ok_or_else(|| invalid(&format!("invalid status line: {}", status_line)))?;
+        let reason = parts.next().unwrap_or_default().to_string();
+
+        let headers = read_headers(reader)?;
+
+        Ok(ResponseHead { version, status, reason, headers })
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|header| header.key().eq_ignore_ascii_case(key))
+            .map(|header| header.value())
+    }
+
+    // 1xx responses other than 101 are followed by another response
+    pub fn is_interim(&self) -> bool {
+        matches!(self.status.as_u16(), 100..=199) && self.status != Status::SwitchingProtocols
+    }
+
+    pub fn keeps_alive(&self) -> bool {
+        let tokens: Vec<String> = self.headers.iter()
+            .filter(|header| header.key().eq_ignore_ascii_case(HEADER_CONNECTION))
+            .flat_map(|header| header.value().split(',').map(|token| token.trim().to_ascii_lowercase()).collect::<Vec<_>>())
+            .collect();
+
+        if tokens.iter().any(|token| token == CONNECTION_CLOSE) {
+            return false;
+        }
+
+        // HTTP/1.0 closes unless the server asked otherwise
+        self.version != HTTP_VERSION_1_0 || tokens.iter().any(|token| token == CONNECTION_KEEP_ALIVE)
+    }
+
+    // How the body after this head is delimited, given the request it answers
+    pub fn framing(&self, method: &Method) -> io::Result<Framing> {
+        if *method == Method::Head || matches!(self.status.as_u16(), 100..=199 | 204 | 304) {
+            return Ok(Framing::Empty);
+        }
+
+        // without any framing headers a response runs until the connection closes
+        body_framing(&self.headers, Framing::Close)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    Empty,
+    Length(u64),
+    Chunked,
+    // the body runs until the connection is closed
+    Close,
+}
+
+enum Body<R: BufRead> {
+    Empty(R),
+    Length(Take<R>),
+    Chunked(ChunkedReader<R>),
+    Close(R),
+}
+
+// Reads a message body according to its framing, decoding chunked bodies
+pub struct BodyReader<R: BufRead> {
+    body: Body<R>,
+}
+
+impl<R: BufRead> BodyReader<R> {
+
+    pub fn new(reader: R, framing: Framing) -> Self {
+        let body = match framing {
+            Framing::Empty => Body::Empty(reader),
+            Framing::Length(length) => Body::Length(reader.take(length)),
+            Framing::Chunked => Body::Chunked(ChunkedReader::new(reader)),
+            Framing::Close => Body::Close(reader),
+        };
+
+        BodyReader { body }
+    }
+
+    // Only chunked bodies carry trailers, and only once they are read to the end
+    pub fn trailers(&self) -> &[Header] {
+        match &self.body {
+            Body::Chunked(reader) => reader.trailers(),
+            _ => &[],
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        match self.body {
+            Body::Empty(reader) | Body::Close(reader) => reader,
+            Body::Length(reader) => reader.into_inner(),
+            Body::Chunked(reader) => reader.into_inner(),
+        }
+    }
+}
+
+impl<R: BufRead> Read for BodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.body {
+            Body::Empty(_) => Ok(0),
+            Body::Length(reader) => {
+                let count = reader.read(buf)?;
+                if count == 0 && !buf.is_empty() && reader.limit() > 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body ended early"));
+                }
+                Ok(count)
+            }
+            Body::Chunked(reader) => reader.read(buf),
+            Body::Close(reader) => reader.read(buf),
+        }
+    }
+}
+
+// Decides between chunked and Content-Length framing, falling back to
+// `default` when neither is present. Transfer codings only frame the body
+// when chunked is the final one; it may not be applied more than once.
+pub(crate) fn body_framing(headers: &[Header], default: Framing) -> io::Result<Framing> {
+    let codings = transfer_codings(headers);
+    let chunked = codings.iter().filter(|coding| *coding == "chunked").count();
+    if chunked > 1 || (chunked == 1 && codings.last().is_some_and(|coding| coding != "chunked")) {
+        return Err(invalid("chunked is not the final transfer coding"));
+    }
+    if chunked == 1 {
+        return Ok(Framing::Chunked);
+    }
+
+    // repeated lengths are only acceptable when they all agree
+    let lengths = headers.iter()
+        .filter(|header| header.key().eq_ignore_ascii_case(HEADER_CONTENT_LENGTH))
+        .flat_map(|header| header.value().split(','))
+        .map(|length| length.trim().parse::<u64>().map_err(|_| invalid("invalid Content-Length")))
+        .collect::<io::Result<Vec<u64>>>()?;
+
+    match lengths.first() {
+        Some(length) if lengths.iter().all(|other| other == length) => Ok(Framing::Length(*length)),
+        Some(_) => Err(invalid("conflicting Content-Length values")),
+        None => Ok(default),
+    }
+}
+
+// The transfer codings of every Transfer-Encoding field combined, in the order
+// they were applied, lowercased and without parameters
+pub(crate) fn transfer_codings(headers: &[Header]) -> Vec<String> {
+    headers.iter()
+        .filter(|header| header.key().eq_ignore_ascii_case(HEADER_TRANSFER_ENCODING))
+        .flat_map(|header| header.value().split(','))
+        .map(|coding| coding.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty())
+        .collect()
+}
+
+// Reads header lines up to and including the blank line that ends them
+pub(crate) fn read_headers<R>(reader: &mut R) -> io::Result<Vec<Header>> where R: BufRead {
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| invalid("message head ended early"))?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(invalid("too many headers"));
+        }
+        headers.push(Header::parse(line));
+    }
+}
+
+// Returns None at the end of the input
+pub(crate) fn read_line<R>(reader: &mut R) -> io::Result<Option<String>> where R: BufRead {
+    let mut line = Vec::new();
+    let count = reader.by_ref().take(MAX_LINE_LEN + 1).read_until(b'\n', &mut line)?;
+
+    if count == 0 {
+        return Ok(None);
+    }
+    if count as u64 > MAX_LINE_LEN || !line.ends_with(b"\n") {
+        return Err(invalid("header line too long or incomplete"));
+    }
+
+    let line = String::from_utf8(line).map_err(|_| invalid("header line is not valid utf-8"))?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(fields: &[(&str, &str)]) -> Vec<Header> {
+        fields.iter().map(|(key, value)| Header::new(*key, *value)).collect()
+    }
+
+    fn framing(fields: &[(&str, &str)]) -> io::Result<Framing> {
+        body_framing(&headers(fields), Framing::Close)
+    }
+
+    #[test]
+    fn transfer_codings_combine_every_field() {
+        let fields = headers(&[("Transfer-Encoding", "gzip ;q=1, Chunked"), ("Content-Type", "text/plain"), ("transfer-encoding", " , identity")]);
+        assert_eq!(transfer_codings(&fields), vec!["gzip", "chunked", "identity"]);
+    }
+
+    #[test]
+    fn chunked_must_be_the_final_coding() {
+        assert_eq!(framing(&[("Transfer-Encoding", "chunked")]).unwrap(), Framing::Chunked);
+        assert_eq!(framing(&[("Transfer-Encoding", "gzip, chunked")]).unwrap(), Framing::Chunked);
+        assert_eq!(framing(&[("Transfer-Encoding", "gzip"), ("Transfer-Encoding", "chunked")]).unwrap(), Framing::Chunked);
+
+        assert!(framing(&[("Transfer-Encoding", "chunked"), ("Transfer-Encoding", "gzip")]).is_err());
+        assert!(framing(&[("Transfer-Encoding", "chunked, identity")]).is_err());
+        assert!(framing(&[("Transfer-Encoding", "chunked, chunked")]).is_err());
+
+        // not chunked at all, so the body runs until the connection closes
+        assert_eq!(framing(&[("Transfer-Encoding", "xchunked")]).unwrap(), Framing::Close);
+        assert_eq!(framing(&[("Transfer-Encoding", "gzip")]).unwrap(), Framing::Close);
+    }
+
+    #[test]
+    fn content_lengths_must_agree() {
+        assert_eq!(framing(&[("Content-Length", "42")]).unwrap(), Framing::Length(42));
+        assert_eq!(framing(&[("Content-Length", "42, 42"), ("Content-Length", "42")]).unwrap(), Framing::Length(42));
+        assert!(framing(&[("Content-Length", "42"), ("Content-Length", "43")]).is_err());
+        assert!(framing(&[("Content-Length", "-1")]).is_err());
+        assert_eq!(framing(&[]).unwrap(), Framing::Close);
+    }
+
+    #[test]
+    fn chunked_bodies_decode_with_trailers() {
+        let mut reader = BodyReader::new(&b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nChecksum: abc\r\n\r\nnext"[..], Framing::Chunked);
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+
+        assert_eq!(body, "hello world");
+        assert_eq!(reader.trailers(), &[Header::new("Checksum", "abc")]);
+        assert_eq!(reader.into_inner(), b"next");
+    }
+}
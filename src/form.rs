@@ -0,0 +1,204 @@
+use std::fmt::Display;
+use crate::http::{CONTENT_TYPE_APPLICATION_X_WWW_FORM_URLENCODED, Status};
+use crate::message::{HttpRequest, HttpResponse};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormError {
+    UnsupportedMediaType,
+    InvalidEncoding,
+}
+
+impl FormError {
+    pub fn status(&self) -> Status {
+        match self {
+            FormError::UnsupportedMediaType => Status::UnsupportedMediaType,
+            FormError::InvalidEncoding => Status::BadRequest,
+        }
+    }
+}
+
+impl Display for FormError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormError::UnsupportedMediaType => write!(f, "expected content type {}", CONTENT_TYPE_APPLICATION_X_WWW_FORM_URLENCODED),
+            FormError::InvalidEncoding => write!(f, "malformed form body"),
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+impl From<FormError> for HttpResponse {
+    fn from(error: FormError) -> Self {
+        HttpResponse::new(error.status(), vec![], Some(error.to_string().into_bytes()))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+}
+
+impl Form {
+
+    pub fn parse(body: &[u8]) -> Result<Self, FormError> {
+        let mut fields = Vec::new();
+
+        for pair in body.split(|byte| *byte == b'&') {
+            if pair.is_empty() {
+                continue;
+            }
+
+            let mut parts = pair.splitn(2, |byte| *byte == b'=');
+            let key = decode(parts.next().unwrap_or_default())?;
+            let value = decode(parts.next().unwrap_or_default())?;
+
+            fields.push((key, value));
+        }
+
+        Ok(Form { fields })
+    }
+
+    pub fn from_request(request: &HttpRequest) -> Result<Self, FormError> {
+        let is_form = request.content_type()
+            .is_some_and(|content_type| content_type.eq_ignore_ascii_case(CONTENT_TYPE_APPLICATION_X_WWW_FORM_URLENCODED));
+
+        if !is_form {
+            return Err(FormError::UnsupportedMediaType);
+        }
+
+        match &request.body {
+            Some(body) => Self::parse(body),
+            None => Ok(Form::default()),
+        }
+    }
+
+    // Returns the first value for a key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Returns every value for a repeated key, in the order they were sent
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields.iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+// Percent-decodes a form component, treating '+' as a space
+fn decode(input: &[u8]) -> Result<String, FormError> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut bytes = input.iter();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => output.push(b' '),
+            b'%' => {
+                let high = bytes.next().and_then(|byte| hex_value(*byte));
+                let low = bytes.next().and_then(|byte| hex_value(*byte));
+                match (high, low) {
+                    (Some(high), Some(low)) => output.push(high << 4 | low),
+                    _ => return Err(FormError::InvalidEncoding),
+                }
+            }
+            byte => output.push(*byte),
+        }
+    }
+
+    String::from_utf8(output).map_err(|_| FormError::InvalidEncoding)
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HEADER_CONTENT_TYPE;
+
+    fn request(content_type: Option<&str>, body: Option<&[u8]>) -> HttpRequest {
+        let mut builder = HttpRequest::builder();
+        if let Some(content_type) = content_type {
+            builder.header(HEADER_CONTENT_TYPE, content_type);
+        }
+        if let Some(body) = body {
+            builder.body(body.to_vec());
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn decodes_plus_and_percent_escapes() {
+        let form = Form::parse(b"name=Jane+Doe&city=S%C3%A3o%20Paulo&sum=1%2B1%3d2").unwrap();
+        assert_eq!(form.get("name"), Some("Jane Doe"));
+        assert_eq!(form.get("city"), Some("S\u{e3}o Paulo"));
+        assert_eq!(form.get("sum"), Some("1+1=2"));
+
+        // keys are decoded too, and a missing '=' is an empty value
+        let form = Form::parse(b"first+name=Jane&flag&=empty").unwrap();
+        assert_eq!(form.iter().collect::<Vec<_>>(), vec![("first name", "Jane"), ("flag", ""), ("", "empty")]);
+    }
+
+    #[test]
+    fn rejects_invalid_escapes() {
+        for body in [&b"a=%"[..], b"a=%4", b"a=%zz", b"a=%4g&b=1", b"%=1", b"a=%C3"] {
+            assert_eq!(Form::parse(body), Err(FormError::InvalidEncoding), "{:?}", String::from_utf8_lossy(body));
+        }
+    }
+
+    #[test]
+    fn keeps_repeated_keys_in_order() {
+        let form = Form::parse(b"tag=a&other=x&tag=b&&tag=c").unwrap();
+        assert_eq!(form.get("tag"), Some("a"));
+        assert_eq!(form.get_all("tag").collect::<Vec<_>>(), vec!["a", "b", "c"]);
+        assert_eq!(form.len(), 4);
+        assert_eq!(form.get("missing"), None);
+    }
+
+    #[test]
+    fn empty_bodies_are_empty_forms() {
+        assert!(Form::parse(b"").unwrap().is_empty());
+        assert!(Form::parse(b"&&").unwrap().is_empty());
+
+        let content_type = Some(CONTENT_TYPE_APPLICATION_X_WWW_FORM_URLENCODED);
+        assert!(Form::from_request(&request(content_type, None)).unwrap().is_empty());
+        assert!(Form::from_request(&request(content_type, Some(b""))).unwrap().is_empty());
+    }
+
+    #[test]
+    fn requests_are_checked_for_the_content_type() {
+        let form = Form::from_request(&request(Some("Application/X-WWW-Form-Urlencoded; charset=utf-8"), Some(b"a=1"))).unwrap();
+        assert_eq!(form.get("a"), Some("1"));
+
+        for content_type in [None, Some("application/json"), Some("multipart/form-data; boundary=x")] {
+            let error = Form::from_request(&request(content_type, Some(b"a=1"))).unwrap_err();
+            assert_eq!(error, FormError::UnsupportedMediaType);
+            assert_eq!(HttpResponse::from(error).status, Status::UnsupportedMediaType);
+        }
+
+        let error = request(Some(CONTENT_TYPE_APPLICATION_X_WWW_FORM_URLENCODED), Some(b"a=%zz")).form().unwrap_err();
+        assert_eq!(error, FormError::InvalidEncoding);
+        assert_eq!(HttpResponse::from(error).status, Status::BadRequest);
+    }
+}
@@ -0,0 +1,336 @@
+use std::fmt::Display;
+use std::net::IpAddr;
+use crate::http::{HEADER_FORWARDED, HEADER_X_FORWARDED_FOR, HEADER_X_FORWARDED_PROTO};
+use crate::message::HttpRequest;
+
+const SCHEME_HTTP: &str = "http";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidProxyAddress(String);
+
+impl Display for InvalidProxyAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid trusted proxy address: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidProxyAddress {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+
+    fn parse(value: &str) -> Result<Self, InvalidProxyAddress> {
+        let invalid = || InvalidProxyAddress(value.to_string());
+
+        let (address, prefix) = match value.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse::<u8>().map_err(|_| invalid())?)),
+            None => (value.trim(), None),
+        };
+
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+
+        Ok(IpRange { network, prefix })
+    }
+
+    fn contains(&self, address: &IpAddr) -> bool {
+        match (self.network, canonical(*address)) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Peers whose forwarding headers are believed. Requests from any other peer
+// have X-Forwarded-* and Forwarded ignored, since a client can send anything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrustedProxies {
+    ranges: Vec<IpRange>,
+}
+
+impl TrustedProxies {
+
+    pub fn new() -> Self {
+        TrustedProxies::default()
+    }
+
+    // Accepts single addresses as well as CIDR ranges like "10.0.0.0/8"
+    pub fn parse(addresses: &[&str]) -> Result<Self, InvalidProxyAddress> {
+        let ranges = addresses.iter()
+            .map(|address| IpRange::parse(address))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(TrustedProxies { ranges })
+    }
+
+    pub fn is_trusted(&self, address: &IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(address))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn resolve(&self, request: &HttpRequest) -> ForwardedInfo {
+        let peer_ip = match request.source_addr() {
+            Some(peer_addr) => canonical(peer_addr.ip()),
+            None => return ForwardedInfo::default(),
+        };
+
+        let direct = ForwardedInfo {
+            client_ip: Some(peer_ip),
+            scheme: SCHEME_HTTP.to_string(),
+        };
+
+        if !self.is_trusted(&peer_ip) {
+            return direct;
+        }
+
+        let hops = forwarded_hops(request).unwrap_or_else(|| x_forwarded_hops(request));
+
+        // walk back from the nearest hop until we leave the trusted proxies
+        let mut resolved = direct;
+        for hop in hops.iter().rev() {
+            let trusted = resolved.client_ip.is_some_and(|ip| self.is_trusted(&ip));
+            if !trusted {
+                break;
+            }
+
+            if let Some(proto) = &hop.proto {
+                resolved.scheme = proto.to_ascii_lowercase();
+            }
+
+            // obfuscated or unknown identifiers end the chain
+            match hop.client_ip {
+                Some(ip) => resolved.client_ip = Some(ip),
+                None => break,
+            }
+        }
+
+        resolved
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardedInfo {
+    pub client_ip: Option<IpAddr>,
+    pub scheme: String,
+}
+
+impl Default for ForwardedInfo {
+    fn default() -> Self {
+        ForwardedInfo {
+            client_ip: None,
+            scheme: SCHEME_HTTP.to_string(),
+        }
+    }
+}
+
+struct Hop {
+    client_ip: Option<IpAddr>,
+    proto: Option<String>,
+}
+
+// RFC 7239, e.g. `for=192.0.2.60;proto=https, for="[2001:db8::1]:4711"`
+fn forwarded_hops(request: &HttpRequest) -> Option<Vec<Hop>> {
+    let values: Vec<&str> = header_values(request, HEADER_FORWARDED);
+    if values.is_empty() {
+        return None;
+    }
+
+    let hops = values.iter()
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            let mut hop = Hop { client_ip: None, proto: None };
+            for pair in element.split(';') {
+                let Some((key, value)) = pair.split_once('=') else { continue };
+                let value = value.trim().trim_matches('"');
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "for" => hop.client_ip = parse_node(value),
+                    "proto" => hop.proto = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            hop
+        })
+        .collect();
+
+    Some(hops)
+}
+
+fn x_forwarded_hops(request: &HttpRequest) -> Vec<Hop> {
+    let addresses: Vec<&str> = list_values(request, HEADER_X_FORWARDED_FOR);
+    let protos: Vec<&str> = list_values(request, HEADER_X_FORWARDED_PROTO);
+
+    addresses.iter().enumerate()
+        .map(|(index, address)| {
+            // a single proto applies to the whole chain, otherwise they pair up by position
+            let proto = match protos.len() {
+                1 => Some(protos[0]),
+                len if len == addresses.len() => Some(protos[index]),
+                _ => None,
+            };
+
+            Hop {
+                client_ip: parse_node(address),
+                proto: proto.map(str::to_string),
+            }
+        })
+        .collect()
+}
+
+// Parses a node identifier, stripping IPv6 brackets and any port
+fn parse_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+
+    if let Some(rest) = value.strip_prefix('[') {
+        let (address, _) = rest.split_once(']')?;
+        return address.parse().ok().map(canonical);
+    }
+
+    if let Ok(address) = value.parse::<IpAddr>() {
+        return Some(canonical(address));
+    }
+
+    let (address, _port) = value.rsplit_once(':')?;
+    address.parse().ok().map(canonical)
+}
+
+// Treats IPv4-mapped IPv6 addresses like the IPv4 address they contain
+fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+        address => address,
+    }
+}
+
+fn header_values<'a>(request: &'a HttpRequest, key: &str) -> Vec<&'a str> {
+    request.headers.iter()
+        .filter(|header| header.key().eq_ignore_ascii_case(key))
+        .map(|header| header.value())
+        .collect()
+}
+
+fn list_values<'a>(request: &'a HttpRequest, key: &str) -> Vec<&'a str> {
+    header_values(request, key).into_iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(peer: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        let mut builder = HttpRequest::builder();
+        builder.path("/");
+        for (key, value) in headers {
+            builder.header(key, value);
+        }
+        let mut request = builder.build();
+        request.set_addrs(peer.parse().unwrap(), "192.0.2.100:80".parse().unwrap());
+        request
+    }
+
+    fn resolve(trusted: &[&str], peer: &str, headers: &[(&str, &str)]) -> (String, String) {
+        let info = TrustedProxies::parse(trusted).unwrap().resolve(&request(peer, headers));
+        (info.client_ip.unwrap().to_string(), info.scheme)
+    }
+
+    fn resolved(client_ip: &str, scheme: &str) -> (String, String) {
+        (client_ip.to_string(), scheme.to_string())
+    }
+
+    #[test]
+    fn headers_from_untrusted_peers_are_ignored() {
+        let headers = [("X-Forwarded-For", "203.0.113.7"), ("X-Forwarded-Proto", "https"), ("Forwarded", "for=203.0.113.8;proto=https")];
+
+        assert_eq!(resolve(&[], "198.51.100.1:4000", &headers), resolved("198.51.100.1", "http"));
+        assert_eq!(resolve(&["10.0.0.1"], "198.51.100.1:4000", &headers), resolved("198.51.100.1", "http"));
+    }
+
+    #[test]
+    fn x_forwarded_for_is_walked_back_to_the_first_untrusted_hop() {
+        let trusted = ["10.0.0.0/8"];
+
+        let headers = [("X-Forwarded-For", "203.0.113.7, 10.0.0.2"), ("X-Forwarded-Proto", "https")];
+        assert_eq!(resolve(&trusted, "10.0.0.1:4000", &headers), resolved("203.0.113.7", "https"));
+
+        // a client prepending its own entries can't get past the untrusted hop
+        let headers = [("X-Forwarded-For", "1.1.1.1, 203.0.113.7, 10.0.0.2")];
+        assert_eq!(resolve(&trusted, "10.0.0.1:4000", &headers), resolved("203.0.113.7", "http"));
+
+        // the chain may be split over several headers
+        let headers = [("X-Forwarded-For", "1.1.1.1"), ("X-Forwarded-For", "203.0.113.7")];
+        assert_eq!(resolve(&trusted, "10.0.0.1:4000", &headers), resolved("203.0.113.7", "http"));
+
+        // an unparsable hop ends the chain at the last address known
+        let headers = [("X-Forwarded-For", "203.0.113.7, garbage, 10.0.0.2")];
+        assert_eq!(resolve(&trusted, "10.0.0.1:4000", &headers), resolved("10.0.0.2", "http"));
+    }
+
+    #[test]
+    fn forwarded_takes_precedence_over_x_forwarded() {
+        let headers = [
+            ("X-Forwarded-For", "198.51.100.9"),
+            ("X-Forwarded-Proto", "http"),
+            ("Forwarded", "for=203.0.113.7;proto=HTTPS"),
+        ];
+        assert_eq!(resolve(&["10.0.0.1"], "10.0.0.1:4000", &headers), resolved("203.0.113.7", "https"));
+    }
+
+    #[test]
+    fn parses_ipv6_and_mapped_addresses() {
+        let trusted = ["10.0.0.1", "2001:db8::/32"];
+
+        let headers = [("Forwarded", r#"for="[2001:db8:cafe::17]:4711""#)];
+        assert_eq!(resolve(&trusted, "10.0.0.1:4000", &headers), resolved("2001:db8:cafe::17", "http"));
+
+        let headers = [("Forwarded", r#"for="[2001:db8:cafe::17]""#)];
+        assert_eq!(resolve(&trusted, "10.0.0.1:4000", &headers), resolved("2001:db8:cafe::17", "http"));
+
+        let headers = [("X-Forwarded-For", "203.0.113.7:1234")];
+        assert_eq!(resolve(&trusted, "10.0.0.1:4000", &headers), resolved("203.0.113.7", "http"));
+
+        // a dual-stack listener sees IPv4 peers as mapped addresses
+        let headers = [("X-Forwarded-For", "::ffff:203.0.113.7")];
+        assert_eq!(resolve(&trusted, "[::ffff:10.0.0.1]:4000", &headers), resolved("203.0.113.7", "http"));
+    }
+
+    #[test]
+    fn matches_cidr_ranges() {
+        let trusted = TrustedProxies::parse(&["10.0.0.0/8", "192.168.1.0/24", "2001:db8::/32"]).unwrap();
+        assert!(trusted.is_trusted(&"10.255.0.1".parse().unwrap()));
+        assert!(trusted.is_trusted(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!trusted.is_trusted(&"2001:db9::1".parse().unwrap()));
+
+        let trusted = TrustedProxies::parse(&["192.168.1.0/24", "2001:db8::1"]).unwrap();
+        assert!(trusted.is_trusted(&"192.168.1.200".parse().unwrap()));
+        assert!(trusted.is_trusted(&"::ffff:192.168.1.200".parse().unwrap()));
+        assert!(!trusted.is_trusted(&"192.168.2.1".parse().unwrap()));
+        assert!(trusted.is_trusted(&"2001:db8::1".parse().unwrap()));
+        assert!(!trusted.is_trusted(&"2001:db8::2".parse().unwrap()));
+
+        for invalid in ["10.0.0.0/33", "2001:db8::/129", "10.0.0/8", "localhost"] {
+            assert_eq!(TrustedProxies::parse(&[invalid]), Err(InvalidProxyAddress(invalid.to_string())));
+        }
+    }
+}
@@ -0,0 +1,458 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+
+// Every entry takes up this much beyond its name and value (RFC 7541 4.1)
+const ENTRY_OVERHEAD: usize = 32;
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// The code and its length in bits for every octet, followed by EOS (RFC 7541 Appendix B)
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28), (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28), (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12), (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11),
+    (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6), (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6),
+    (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8), (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7),
+    (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7), (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7),
+    (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6),
+    (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7), (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20), (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23),
+    (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23), (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24),
+    (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21), (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23),
+    (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25),
+    (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27), (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25),
+    (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27), (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21), (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27), (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+const EOS: u16 = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HpackError(&'static str);
+
+impl Display for HpackError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for HpackError {}
+
+// The error for a block that decodes to more than the header list size
+// allows. The block is still decoded in full, so the dynamic table stays in
+// step for the blocks after it.
+pub const HEADER_LIST_TOO_LARGE: HpackError = HpackError("header list too large");
+
+// Decodes header blocks, keeping the dynamic table shared by all the blocks
+// of a connection. `max_size` is the table size and `max_list_size` the
+// header list size advertised in SETTINGS, which counts every field the way
+// table entries are counted.
+pub struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    capacity: usize,
+    max_size: usize,
+    max_list_size: usize,
+}
+
+impl Decoder {
+
+    pub fn new(max_size: usize, max_list_size: usize) -> Self {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            capacity: max_size,
+            max_size,
+            max_list_size,
+        }
+    }
+
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, HpackError> {
+        let mut headers = Vec::new();
+        let mut list_size = 0;
+        let mut position = 0;
+
+        while let Some(&first) = block.get(position) {
+
+            // indexed field
+            if first & 0x80 != 0 {
+                let index = decode_integer(block, &mut position, 7)?;
+                let (name, value) = self.entry(index)?;
+
+                // a few bytes can name a large table entry over and over, so
+                // fields are only copied while they fit in the list
+                list_size += name.len() + value.len() + ENTRY_OVERHEAD;
+                if list_size <= self.max_list_size {
+                    headers.push((name.to_string(), value.to_string()));
+                }
+                continue;
+            }
+
+            // table size updates may only start a block
+            if first & 0xe0 == 0x20 {
+                if list_size > 0 {
+                    return Err(HpackError("table size update after a field"));
+                }
+                let capacity = decode_integer(block, &mut position, 5)?;
+                if capacity > self.max_size {
+                    return Err(HpackError("table size update above the limit"));
+                }
+                self.capacity = capacity;
+                self.evict(0);
+                continue;
+            }
+
+            // literal fields, added to the table or not
+            let (prefix, indexed) = if first & 0xc0 == 0x40 { (6, true) } else { (4, false) };
+
+            let name = match decode_integer(block, &mut position, prefix)? {
+                0 => decode_string(block, &mut position)?,
+                index => self.entry(index)?.0.to_string(),
+            };
+            let value = decode_string(block, &mut position)?;
+
+            if indexed {
+                self.insert(name.clone(), value.clone());
+            }
+            list_size += name.len() + value.len() + ENTRY_OVERHEAD;
+            if list_size <= self.max_list_size {
+                headers.push((name, value));
+            }
+        }
+
+        if list_size > self.max_list_size {
+            return Err(HEADER_LIST_TOO_LARGE);
+        }
+        Ok(headers)
+    }
+
+    fn entry(&self, index: usize) -> Result<(&str, &str), HpackError> {
+        match index {
+            0 => Err(HpackError("index 0 is not used")),
+            1..=61 => Ok(STATIC_TABLE[index - 1]),
+            _ => self.table.get(index - 62)
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .ok_or(HpackError("index beyond the dynamic table")),
+        }
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        let size = name.len() + value.len() + ENTRY_OVERHEAD;
+
+        // an entry larger than the table just empties it
+        self.evict(size);
+        if size <= self.capacity {
+            self.table.push_front((name, value));
+            self.size += size;
+        }
+    }
+
+    // Drops the oldest entries until `room` more bytes fit
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.capacity {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+// Encodes header blocks without the dynamic table or Huffman coding, so the
+// encoder keeps no state and the peer's table size doesn't matter
+pub fn encode<'a, I>(headers: I) -> Vec<u8> where I: IntoIterator<Item = (&'a str, &'a str)> {
+    let mut block = Vec::new();
+
+    for (name, value) in headers {
+        if let Some(index) = STATIC_TABLE.iter().position(|entry| *entry == (name, value)) {
+            encode_integer(index + 1, 7, 0x80, &mut block);
+            continue;
+        }
+
+        // literal without indexing, naming the static entry when there is one
+        match STATIC_TABLE.iter().position(|(entry, _)| *entry == name) {
+            Some(index) => encode_integer(index + 1, 4, 0x00, &mut block),
+            None => {
+                block.push(0x00);
+                encode_string(name.as_bytes(), &mut block);
+            }
+        }
+        encode_string(value.as_bytes(), &mut block);
+    }
+
+    block
+}
+
+fn decode_integer(block: &[u8], position: &mut usize, prefix: u8) -> Result<usize, HpackError> {
+    let mask = (1u8 << prefix) - 1;
+    let first = *block.get(*position).ok_or(HpackError("truncated integer"))?;
+    *position += 1;
+
+    let mut value = (first & mask) as usize;
+    if value < mask as usize {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let byte = *block.get(*position).ok_or(HpackError("truncated integer"))?;
+        *position += 1;
+
+        if shift > 28 {
+            return Err(HpackError("integer too large"));
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_integer(mut value: usize, prefix: u8, flags: u8, block: &mut Vec<u8>) {
+    let mask = (1usize << prefix) - 1;
+    if value < mask {
+        block.push(flags | value as u8);
+        return;
+    }
+
+    block.push(flags | mask as u8);
+    value -= mask;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn decode_string(block: &[u8], position: &mut usize) -> Result<String, HpackError> {
+    let huffman = block.get(*position).is_some_and(|byte| byte & 0x80 != 0);
+    let length = decode_integer(block, position, 7)?;
+
+    let end = position.checked_add(length).filter(|end| *end <= block.len()).ok_or(HpackError("truncated string"))?;
+    let bytes = &block[*position..end];
+    *position = end;
+
+    let bytes = if huffman { huffman_decode(bytes)? } else { bytes.to_vec() };
+
+    // octets that aren't utf-8 are read as latin-1, like HTTP/1 obs-text
+    Ok(match String::from_utf8(bytes) {
+        Ok(value) => value,
+        Err(error) => error.into_bytes().iter().map(|byte| *byte as char).collect(),
+    })
+}
+
+fn encode_string(bytes: &[u8], block: &mut Vec<u8>) {
+    encode_integer(bytes.len(), 7, 0x00, block);
+    block.extend_from_slice(bytes);
+}
+
+fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>, HpackError> {
+    static SYMBOLS: OnceLock<HashMap<(u8, u32), u16>> = OnceLock::new();
+    let symbols = SYMBOLS.get_or_init(|| {
+        HUFFMAN_CODES.iter()
+            .enumerate()
+            .map(|(symbol, (code, length))| ((*length, *code), symbol as u16))
+            .collect()
+    });
+
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let mut code = 0u32;
+    let mut length = 0u8;
+
+    for byte in bytes {
+        for bit in (0..8).rev() {
+            code = (code << 1) | ((byte >> bit) & 1) as u32;
+            length += 1;
+
+            match symbols.get(&(length, code)) {
+                Some(&EOS) => return Err(HpackError("EOS in huffman string")),
+                Some(symbol) => {
+                    decoded.push(*symbol as u8);
+                    code = 0;
+                    length = 0;
+                }
+                None if length >= 30 => return Err(HpackError("invalid huffman code")),
+                None => {}
+            }
+        }
+    }
+
+    // the string is padded with fewer than 8 of the most significant bits of EOS
+    if length > 7 || code != (1 << length) - 1 {
+        return Err(HpackError("invalid huffman padding"));
+    }
+
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    // RFC 7541 C.3, requests on one connection sharing the dynamic table
+    #[test]
+    fn decodes_requests_without_huffman_coding() {
+        let mut decoder = Decoder::new(4096, usize::MAX);
+
+        let first = decoder.decode(&hex("828684410f7777772e6578616d706c652e636f6d")).unwrap();
+        assert_eq!(first, fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")]));
+
+        let second = decoder.decode(&hex("828684be58086e6f2d6361636865")).unwrap();
+        assert_eq!(second, fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com"), ("cache-control", "no-cache")]));
+
+        let third = decoder.decode(&hex("828785bf400a637573746f6d2d6b65790c637573746f6d2d76616c7565")).unwrap();
+        assert_eq!(third, fields(&[(":method", "GET"), (":scheme", "https"), (":path", "/index.html"), (":authority", "www.example.com"), ("custom-key", "custom-value")]));
+        assert_eq!(decoder.size, 164);
+    }
+
+    // RFC 7541 C.4.1
+    #[test]
+    fn decodes_huffman_coded_strings() {
+        let mut decoder = Decoder::new(4096, usize::MAX);
+        let headers = decoder.decode(&hex("828684418cf1e3c2e5f23a6ba0ab90f4ff")).unwrap();
+        assert_eq!(headers, fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")]));
+    }
+
+    #[test]
+    fn encoded_blocks_decode_to_the_same_fields() {
+        let headers = [(":status", "200"), ("content-type", "text/plain"), ("x-custom", "a value")];
+        let mut decoder = Decoder::new(4096, usize::MAX);
+        assert_eq!(decoder.decode(&encode(headers)).unwrap(), fields(&headers));
+    }
+
+    #[test]
+    fn oldest_entries_are_evicted_to_make_room() {
+        let mut decoder = Decoder::new(100, usize::MAX);
+        decoder.decode(&hex("4001610162")).unwrap();
+        decoder.decode(&hex("4001630164")).unwrap();
+        decoder.decode(&hex("4001650166")).unwrap();
+
+        // three entries of 34 bytes don't fit in 100, so "a" is gone
+        assert_eq!(decoder.decode(&hex("be")).unwrap(), fields(&[("e", "f")]));
+        assert_eq!(decoder.decode(&hex("bf")).unwrap(), fields(&[("c", "d")]));
+        assert!(decoder.decode(&hex("c0")).is_err());
+    }
+
+    #[test]
+    fn header_lists_over_the_limit_still_update_the_table() {
+        let mut decoder = Decoder::new(4096, 200);
+        let value = "a".repeat(100);
+
+        // the entry fits once, but naming it again goes over
+        let mut block = vec![0x40, 0x01, b'x', 100];
+        block.extend(value.as_bytes());
+        block.extend([0xbe, 0xbe]);
+        assert_eq!(decoder.decode(&block), Err(HEADER_LIST_TOO_LARGE));
+
+        assert_eq!(decoder.decode(&[0xbe]).unwrap(), vec![("x".to_string(), value)]);
+    }
+
+    #[test]
+    fn invalid_blocks_are_errors() {
+        let mut decoder = Decoder::new(4096, usize::MAX);
+
+        // index 0, an index past the tables, a truncated string and a size update after a field
+        for block in ["80", "ff00", "4003616263", "8220"] {
+            assert!(decoder.decode(&hex(block)).is_err(), "{}", block);
+        }
+
+        // a table size update above the advertised size
+        assert_eq!(decoder.decode(&hex("3fe21f")), Err(HpackError("table size update above the limit")));
+
+        // EOS and padding that isn't all ones
+        assert!(huffman_decode(&[0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(huffman_decode(&[0x00]).is_err());
+    }
+}
@@ -1,7 +1,9 @@
 use std::fmt::Display;
+use std::str::FromStr;
 
 pub const HTTP_VERSION_1_0: &str = "HTTP/1.0";
 pub const HTTP_VERSION_1_1: &str = "HTTP/1.1";
+pub const HTTP_VERSION_2_0: &str = "HTTP/2.0";
 
 // Commonly used HTTP headers
 pub const HEADER_ACCEPT: &str = "Accept";
@@ -34,7 +36,9 @@ pub const HEADER_DATE: &str = "Date";
 pub const HEADER_ETAG: &str = "ETag";
 pub const HEADER_EXPECT: &str = "Expect";
 pub const HEADER_EXPIRES: &str = "Expires";
+pub const HEADER_FORWARDED: &str = "Forwarded";
 pub const HEADER_HOST: &str = "Host";
+pub const HEADER_HTTP2_SETTINGS: &str = "HTTP2-Settings";
 pub const HEADER_IF_MATCH: &str = "If-Match";
 pub const HEADER_IF_MODIFIED_SINCE: &str = "If-Modified-Since";
 pub const HEADER_IF_NONE_MATCH: &str = "If-None-Match";
@@ -45,7 +49,7 @@ pub const HEADER_LOCATION: &str = "Location";
 pub const HEADER_ORIGIN: &str = "Origin";
 pub const HEADER_PRAGMA: &str = "Pragma";
 pub const HEADER_PROXY_AUTHENTICATE: &str = "Proxy-Authenticate";
-pub const HEADER_PROXY_AUTHORIZATION: &str = "Proxy-Author";
+pub const HEADER_PROXY_AUTHORIZATION: &str = "Proxy-Authorization";
 pub const HEADER_RANGE: &str = "Range";
 pub const HEADER_REFERER: &str = "Referer";
 pub const HEADER_RETRY_AFTER: &str = "Retry-After";
@@ -92,16 +96,20 @@ pub const CONTENT_TYPE_APPLICATION_X_WWW_FORM_URLENCODED: &str = "application/x-
 pub const CONTENT_TYPE_APPLICATION_FORM_DATA: &str = "application/form-data";
 pub const CONTENT_TYPE_MULTIPART_FORM_DATA: &str = "multipart/form-data";
 pub const CONTENT_TYPE_APPLICATION_JAVASCRIPT: &str = "application/javascript";
+pub const CONTENT_TYPE_MESSAGE_HTTP: &str = "message/http";
 
 // Commonly used connection types
 pub const CONNECTION_CLOSE: &str = "close";
 pub const CONNECTION_KEEP_ALIVE: &str = "keep-alive";
 pub const CONNECTION_UPGRADE: &str = "upgrade";
+pub const EXPECT_100_CONTINUE: &str = "100-continue";
+pub const TE_TRAILERS: &str = "trailers";
 
 pub const UPGRADE_WEBSOCKET: &str = "websocket";
+pub const UPGRADE_H2C: &str = "h2c";
 
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Method {
     Get,
     Post,
@@ -112,44 +120,93 @@ pub enum Method {
     Trace,
     Connect,
     Patch,
-    Brew,
+    // Any other method token, e.g. the WebDAV PROPFIND or MKCOL
+    Extension(String),
 }
 
 impl Method {
-    pub fn from_str(method: &str) -> Option<Method> {
+
+    // Safe methods are read-only and can be repeated or prefetched freely
+    pub fn is_safe(&self) -> bool {
+        matches!(self, Method::Get | Method::Head | Method::Options | Method::Trace)
+    }
+
+    // Nothing is known about extension methods, so they are never idempotent
+    pub fn is_idempotent(&self) -> bool {
+        self.is_safe() || matches!(self, Method::Put | Method::Delete)
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Head => "HEAD",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Patch => "PATCH",
+            Method::Extension(method) => method,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidMethod(String);
+
+impl Display for InvalidMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid method: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidMethod {}
+
+// Methods are case-sensitive, so "get" is an extension method rather than GET
+impl FromStr for Method {
+    type Err = InvalidMethod;
+
+    fn from_str(method: &str) -> Result<Self, Self::Err> {
         match method {
-            "GET" => Some(Method::Get),
-            "POST" => Some(Method::Post),
-            "PUT" => Some(Method::Put),
-            "DELETE" => Some(Method::Delete),
-            "OPTIONS" => Some(Method::Options),
-            "HEAD" => Some(Method::Head),
-            "TRACE" => Some(Method::Trace),
-            "CONNECT" => Some(Method::Connect),
-            "PATCH" => Some(Method::Patch),
-            "BREW" => Some(Method::Brew),
-            _ => None
+            "GET" => Ok(Method::Get),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "OPTIONS" => Ok(Method::Options),
+            "HEAD" => Ok(Method::Head),
+            "TRACE" => Ok(Method::Trace),
+            "CONNECT" => Ok(Method::Connect),
+            "PATCH" => Ok(Method::Patch),
+            method if is_token(method) => Ok(Method::Extension(method.to_string())),
+            method => Err(InvalidMethod(method.to_string())),
         }
     }
 }
 
+impl TryFrom<&str> for Method {
+    type Error = InvalidMethod;
+
+    fn try_from(method: &str) -> Result<Self, Self::Error> {
+        method.parse()
+    }
+}
+
 impl Display for Method {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Method::Get => write!(f, "GET"),
-            Method::Post => write!(f, "POST"),
-            Method::Put => write!(f, "PUT"),
-            Method::Delete => write!(f, "DELETE"),
-            Method::Options => write!(f, "OPTIONS"),
-            Method::Head => write!(f, "HEAD"),
-            Method::Trace => write!(f, "TRACE"),
-            Method::Connect => write!(f, "CONNECT"),
-            Method::Patch => write!(f, "PATCH"),
-            Method::Brew => write!(f, "BREW"),
-        }
+        write!(f, "{}", self.as_str())
     }
 }
 
+// RFC 9110 token: one or more visible ASCII characters other than delimiters
+pub fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(is_tchar)
+}
+
+pub(crate) fn is_tchar(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Status {
     // 100
@@ -310,6 +367,76 @@ impl Status {
         }
     }
 
+    // Falls back to `Custom` for codes without a variant
+    pub fn from_u16(code: u16) -> Status {
+        match code {
+            100 => Status::Continue,
+            101 => Status::SwitchingProtocols,
+            102 => Status::Processing,
+            103 => Status::EarlyHints,
+            200 => Status::Ok,
+            201 => Status::Created,
+            202 => Status::Accepted,
+            203 => Status::NonAuthoritativeInformation,
+            204 => Status::NoContent,
+            205 => Status::ResetContent,
+            206 => Status::PartialContent,
+            207 => Status::MultiStatus,
+            208 => Status::AlreadyReported,
+            226 => Status::IMUsed,
+            300 => Status::MultipleChoices,
+            301 => Status::MovedPermanently,
+            302 => Status::Found,
+            303 => Status::SeeOther,
+            304 => Status::NotModified,
+            305 => Status::UseProxy,
+            306 => Status::SwitchProxy,
+            307 => Status::TemporaryRedirect,
+            308 => Status::PermanentRedirect,
+            400 => Status::BadRequest,
+            401 => Status::Unauthorized,
+            402 => Status::PaymentRequired,
+            403 => Status::Forbidden,
+            404 => Status::NotFound,
+            405 => Status::MethodNotAllowed,
+            406 => Status::NotAcceptable,
+            407 => Status::ProxyAuthenticationRequired,
+            408 => Status::RequestTimeout,
+            409 => Status::Conflict,
+            410 => Status::Gone,
+            411 => Status::LengthRequired,
+            412 => Status::PreconditionFailed,
+            413 => Status::PayloadTooLarge,
+            414 => Status::URITooLong,
+            415 => Status::UnsupportedMediaType,
+            416 => Status::RangeNotSatisfiable,
+            417 => Status::ExpectationFailed,
+            418 => Status::ImATeapot,
+            421 => Status::MisdirectedRequest,
+            422 => Status::UnprocessableEntity,
+            423 => Status::Locked,
+            424 => Status::FailedDependency,
+            425 => Status::TooEarly,
+            426 => Status::UpgradeRequired,
+            428 => Status::PreconditionRequired,
+            429 => Status::TooManyRequests,
+            431 => Status::RequestHeaderFieldsTooLarge,
+            451 => Status::UnavailableForLegalReasons,
+            500 => Status::InternalServerError,
+            501 => Status::NotImplemented,
+            502 => Status::BadGateway,
+            503 => Status::ServiceUnavailable,
+            504 => Status::GatewayTimeout,
+            505 => Status::HTTPVersionNotSupported,
+            506 => Status::VariantAlsoNegotiates,
+            507 => Status::InsufficientStorage,
+            508 => Status::LoopDetected,
+            510 => Status::NotExtended,
+            511 => Status::NetworkAuthenticationRequired,
+            code => Status::Custom(code),
+        }
+    }
+
     pub fn reason_phrase(&self) -> &'static str {
         match *self {
             Status::Continue => "Continue",
@@ -375,7 +502,8 @@ impl Status {
             Status::LoopDetected => "Loop Detected",
             Status::NotExtended => "Not Extended",
             Status::NetworkAuthenticationRequired => "Network Authentication Required",
-            Status::Custom(_) => "Custom",
+            // the reason phrase is optional, see HttpResponse::set_reason
+            Status::Custom(_) => "",
         }
     }
 
@@ -393,7 +521,33 @@ impl Status {
 
 impl Display for Status {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} {}", self.as_u16(), self.reason_phrase())
+        match self.reason_phrase() {
+            "" => write!(f, "{}", self.as_u16()),
+            reason => write!(f, "{} {}", self.as_u16(), reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidStatusCode(u16);
+
+impl Display for InvalidStatusCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid status code: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidStatusCode {}
+
+// Unlike `from_u16` this only accepts the three digit codes HTTP allows
+impl TryFrom<u16> for Status {
+    type Error = InvalidStatusCode;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        match code {
+            100..=999 => Ok(Status::from_u16(code)),
+            code => Err(InvalidStatusCode(code)),
+        }
     }
 }
 
@@ -435,4 +589,107 @@ impl Header {
     pub fn value(&self) -> &str {
         &self.value
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::HttpResponse;
+
+    fn status_line(response: &HttpResponse) -> String {
+        let head = String::from_utf8(response.head_to_bytes()).unwrap();
+        head.split("\r\n").next().unwrap().to_string()
+    }
+
+    #[test]
+    fn parses_standard_and_extension_methods() {
+        assert_eq!("GET".parse(), Ok(Method::Get));
+        assert_eq!(Method::try_from("PATCH"), Ok(Method::Patch));
+        assert_eq!("PROPFIND".parse(), Ok(Method::Extension("PROPFIND".to_string())));
+        assert_eq!(Method::Extension("PROPFIND".to_string()).to_string(), "PROPFIND");
+
+        // methods are case-sensitive
+        assert_eq!("get".parse(), Ok(Method::Extension("get".to_string())));
+    }
+
+    #[test]
+    fn rejects_methods_that_are_not_tokens() {
+        for method in ["", "GE T", "GET\r\n", "M(E)", "\u{e9}T\u{e9}", "A/B"] {
+            assert!(!is_token(method), "{:?}", method);
+            assert_eq!(method.parse::<Method>(), Err(InvalidMethod(method.to_string())));
+        }
+        assert!(is_token("X-Custom.Method~1!"));
+    }
+
+    #[test]
+    fn classifies_safe_and_idempotent_methods() {
+        let extension = Method::Extension("PROPFIND".to_string());
+        for (method, safe, idempotent) in [
+            (Method::Get, true, true),
+            (Method::Head, true, true),
+            (Method::Options, true, true),
+            (Method::Trace, true, true),
+            (Method::Put, false, true),
+            (Method::Delete, false, true),
+            (Method::Post, false, false),
+            (Method::Patch, false, false),
+            (Method::Connect, false, false),
+            (extension, false, false),
+        ] {
+            assert_eq!((method.is_safe(), method.is_idempotent()), (safe, idempotent), "{}", method);
+        }
+    }
+
+    #[test]
+    fn known_codes_map_to_their_variants() {
+        for (code, status, reason) in [
+            (100, Status::Continue, "Continue"),
+            (200, Status::Ok, "OK"),
+            (404, Status::NotFound, "Not Found"),
+            (418, Status::ImATeapot, "I'm a teapot"),
+            (511, Status::NetworkAuthenticationRequired, "Network Authentication Required"),
+        ] {
+            assert_eq!(Status::from_u16(code), status);
+            assert_eq!(Status::try_from(code), Ok(status.clone()));
+            assert_eq!(status.as_u16(), code);
+            assert_eq!(status.reason_phrase(), reason);
+        }
+    }
+
+    #[test]
+    fn other_codes_are_custom_without_a_reason() {
+        let status = Status::from_u16(299);
+        assert_eq!(status, Status::Custom(299));
+        assert_eq!(status.as_u16(), 299);
+        assert_eq!(status.reason_phrase(), "");
+        assert_eq!(status.to_string(), "299");
+        assert_eq!(status.class(), StatusClass::Success);
+
+        // the space before the empty reason stays
+        let response = HttpResponse::new(status, vec![], None);
+        assert_eq!(status_line(&response), "HTTP/1.1 299 ");
+    }
+
+    #[test]
+    fn reasons_can_be_set() {
+        let mut response = HttpResponse::new(Status::from_u16(299), vec![], None);
+        response.set_reason("Mostly Fine");
+        assert_eq!(response.reason(), "Mostly Fine");
+        assert_eq!(status_line(&response), "HTTP/1.1 299 Mostly Fine");
+
+        // also for known codes, without anything that could end the line
+        let mut response = HttpResponse::new(Status::Ok, vec![], None);
+        response.set_reason("Fine\r\nSet-Cookie: a=1\tindeed");
+        assert_eq!(status_line(&response), "HTTP/1.1 200 FineSet-Cookie: a=1\tindeed");
+    }
+
+    #[test]
+    fn only_three_digit_codes_are_valid() {
+        assert_eq!(Status::try_from(100), Ok(Status::Continue));
+        assert_eq!(Status::try_from(999), Ok(Status::Custom(999)));
+
+        for code in [0, 99, 1000, u16::MAX] {
+            assert_eq!(Status::try_from(code), Err(InvalidStatusCode(code)));
+        }
+        assert_eq!(Status::from_u16(1000), Status::Custom(1000));
+    }
+}
@@ -0,0 +1,1113 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use async_std::channel;
+use async_std::channel::{Receiver, Sender};
+use async_std::io;
+use async_std::io::{BufReader, BufWriter, Cursor, Read};
+use async_std::net::{Shutdown, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use log::debug;
+use crate::hpack;
+use crate::http::{CONNECTION_UPGRADE, Header, is_token, HEADER_CONNECTION, HEADER_CONTENT_LENGTH, HEADER_COOKIE, HEADER_HOST, HEADER_HTTP2_SETTINGS, HEADER_TE, HEADER_TRANSFER_ENCODING, HEADER_UPGRADE, HTTP_VERSION_2_0, Method, Status, TE_TRAILERS, UPGRADE_H2C};
+use crate::message::{BodyStream, HttpRequest, HttpResponse, Reply};
+use crate::proxy_protocol::ProxyHeader;
+use crate::server::HttpServer;
+
+// What a client with prior knowledge opens the connection with, and what an
+// upgraded client sends after the 101 response
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_SIZE: usize = 9;
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+const MAX_FRAME_SIZE_LIMIT: usize = 16_777_215;
+const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+const HEADER_TABLE_SIZE: usize = 4096;
+const MAX_CONCURRENT_STREAMS: usize = 100;
+const MAX_HEADER_BLOCK_SIZE: usize = 64 * 1024;
+const MAX_HEADER_LIST_SIZE: usize = 64 * 1024;
+
+// frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// frame flags
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+// settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// Fields that only mean something to a single HTTP/1.1 connection
+const CONNECTION_SPECIFIC: [&str; 5] = [HEADER_CONNECTION, "Keep-Alive", "Proxy-Connection", HEADER_TRANSFER_ENCODING, HEADER_UPGRADE];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    CompressionError = 0x9,
+    EnhanceYourCalm = 0xb,
+}
+
+// Connection errors end the connection with GOAWAY, stream errors only reset the stream
+enum Http2Error {
+    Connection(ErrorCode),
+    Stream(u32, ErrorCode),
+    Io(io::Error),
+}
+
+impl From<io::Error> for Http2Error {
+    fn from(error: io::Error) -> Self {
+        Http2Error::Io(error)
+    }
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+enum Event {
+    Frame(Frame),
+    // the client closed the connection, or sent a frame too large to read
+    Closed(Option<ErrorCode>),
+    Interim(u32, HttpResponse),
+    // the final response, with a way to ask for the next piece of its body
+    // when the body is streamed
+    Final(u32, HttpResponse, Option<Sender<()>>),
+    // the pieces of a streamed response body, and how the body ended
+    Data(u32, Vec<u8>),
+    End(u32),
+    Failed(u32),
+    // the handler read this much of a request body
+    Consumed(u32, usize),
+}
+
+// A header block that continues in CONTINUATION frames
+struct HeaderBlock {
+    stream_id: u32,
+    end_stream: bool,
+    fragment: Vec<u8>,
+}
+
+struct Stream {
+    method: Method,
+    accepts_trailers: bool,
+    // the request body while it is still arriving
+    incoming: Option<Incoming>,
+    send_window: i64,
+    // the part of the response still waiting for flow control window
+    outgoing: Option<Outgoing>,
+}
+
+struct Incoming {
+    body: Option<Sender<io::Result<Vec<u8>>>>,
+    trailers: Arc<OnceLock<Vec<Header>>>,
+    content_length: Option<usize>,
+    received: usize,
+    // what the client may send before the handler has read more
+    window: i64,
+}
+
+impl Incoming {
+
+    // Ends the body with its trailers, unless it disagrees with its Content-Length
+    fn end(&mut self, trailers: Vec<Header>) -> bool {
+        if self.content_length.is_some_and(|length| length != self.received) {
+            return false;
+        }
+
+        let _ = self.trailers.set(trailers);
+        self.body = None;
+        true
+    }
+}
+
+// A body cut short by a reset or a malformed request mustn't look complete to the handler
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        if let Some(body) = self.body.take() {
+            let _ = body.try_send(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "request body incomplete")));
+        }
+    }
+}
+
+struct Outgoing {
+    // the body, or the piece of a streamed body being sent
+    data: Vec<u8>,
+    sent: usize,
+    // nothing follows `data`
+    end: bool,
+    trailers: Vec<Header>,
+    // asks for the next piece of a streamed body
+    next: Option<Sender<()>>,
+}
+
+// Reads a request body for the handler, giving the client window back as it goes
+struct WindowedBody {
+    body: BodyStream,
+    stream_id: u32,
+    events: Sender<Event>,
+}
+
+impl std::io::Read for WindowedBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.body.read(buf)?;
+        if count > 0 {
+            // readers run on blocking threads, so waiting here is fine
+            let _ = task::block_on(self.events.send(Event::Consumed(self.stream_id, count)));
+        }
+        Ok(count)
+    }
+}
+
+// The HTTP2-Settings payload of a request upgrading to h2c, or None when the
+// request doesn't ask for h2c
+pub(crate) fn upgrade_settings(request: &HttpRequest) -> Option<Vec<u8>> {
+    let has_token = |key: &str, token: &str| {
+        request.headers.iter()
+            .filter(|header| header.key().eq_ignore_ascii_case(key))
+            .flat_map(|header| header.value().split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+
+    let upgrade = has_token(HEADER_UPGRADE, UPGRADE_H2C)
+        && has_token(HEADER_CONNECTION, CONNECTION_UPGRADE)
+        && has_token(HEADER_CONNECTION, HEADER_HTTP2_SETTINGS);
+
+    let mut settings = request.headers.iter().filter(|header| header.key().eq_ignore_ascii_case(HEADER_HTTP2_SETTINGS));
+    match (upgrade, settings.next(), settings.next()) {
+        (true, Some(header), None) => URL_SAFE_NO_PAD.decode(header.value().trim().trim_end_matches('=')).ok(),
+        _ => None,
+    }
+}
+
+// Speaks HTTP/2 on a connection whose preface has been read. Requests upgraded
+// from HTTP/1.1 become stream 1, with the settings from their HTTP2-Settings.
+pub(crate) async fn serve(server: &HttpServer, stream: &TcpStream, reader: BufReader<&TcpStream>, proxy_header: Option<ProxyHeader>, upgrade: Option<(HttpRequest, Vec<u8>)>) -> io::Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let local_addr = stream.local_addr()?;
+    debug!("Serving HTTP/2 to {}", peer_addr);
+
+    // frames are read on their own task so that responses can be written as
+    // soon as handlers finish, starting with what HTTP/1.1 had already buffered
+    let (events, receiver) = channel::bounded(64);
+    let buffered = Cursor::new(reader.buffer().to_vec());
+    task::spawn(read_frames(buffered.chain(stream.clone()), events.clone()));
+
+    let mut connection = Connection {
+        server,
+        writer: BufWriter::new(stream),
+        events,
+        decoder: hpack::Decoder::new(HEADER_TABLE_SIZE, MAX_HEADER_LIST_SIZE),
+        streams: BTreeMap::new(),
+        last_stream_id: 0,
+        continuation: None,
+        send_window: DEFAULT_WINDOW_SIZE,
+        initial_window: DEFAULT_WINDOW_SIZE,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        peer_addr,
+        local_addr,
+        proxy_header,
+        closing: false,
+    };
+
+    let result = connection.run(receiver, upgrade).await;
+
+    // stops the frame reader
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+async fn read_frames<R>(reader: R, events: Sender<Event>) where R: Read + Unpin {
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        if reader.read_exact(&mut header).await.is_err() {
+            let _ = events.send(Event::Closed(None)).await;
+            return;
+        }
+
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if length > DEFAULT_MAX_FRAME_SIZE {
+            let _ = events.send(Event::Closed(Some(ErrorCode::FrameSizeError))).await;
+            return;
+        }
+
+        let mut payload = vec![0u8; length];
+        if reader.read_exact(&mut payload).await.is_err() {
+            let _ = events.send(Event::Closed(None)).await;
+            return;
+        }
+
+        let frame = Frame {
+            kind: header[3],
+            flags: header[4],
+            stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+            payload,
+        };
+        if events.send(Event::Frame(frame)).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Passes the responses of a stream's handler on to the connection
+fn forward_replies(stream_id: u32, replies: Receiver<Reply>, events: Sender<Event>) {
+    task::spawn(async move {
+        loop {
+            let (event, body) = match replies.recv().await {
+                Ok(Reply::Interim(response)) => (Event::Interim(stream_id, response), None),
+                Ok(Reply::Final(response)) => match response.stream().cloned() {
+                    Some(body) => {
+                        let (next, requests) = channel::bounded(1);
+                        (Event::Final(stream_id, response, Some(next)), Some((body, requests)))
+                    }
+                    None => (Event::Final(stream_id, response, None), None),
+                },
+                // the handler panicked
+                Err(_) => (Event::Final(stream_id, HttpResponse::new(Status::InternalServerError, vec![], None), None), None),
+            };
+
+            let last = matches!(event, Event::Final(..));
+            if events.send(event).await.is_err() {
+                break;
+            }
+            if let Some((body, requests)) = body {
+                send_stream(stream_id, body, requests, events).await;
+                break;
+            }
+            if last {
+                break;
+            }
+        }
+    });
+}
+
+// Reads a streamed response body a piece at a time, reading the next piece
+// once the connection has sent the last one. The connection stops asking
+// when the stream goes away.
+async fn send_stream(stream_id: u32, body: BodyStream, requests: Receiver<()>, events: Sender<Event>) {
+    loop {
+        let reader = body.clone();
+        let piece = task::spawn_blocking(move || {
+            let mut buffer = vec![0u8; DEFAULT_MAX_FRAME_SIZE];
+            let count = reader.read(&mut buffer)?;
+            buffer.truncate(count);
+            io::Result::Ok(buffer)
+        }).await;
+
+        let event = match piece {
+            Ok(piece) if piece.is_empty() => Event::End(stream_id),
+            Ok(piece) => Event::Data(stream_id, piece),
+            Err(error) => {
+                debug!("Error reading response body: {}", error);
+                Event::Failed(stream_id)
+            }
+        };
+
+        let last = !matches!(event, Event::Data(..));
+        if events.send(event).await.is_err() || last || requests.recv().await.is_err() {
+            return;
+        }
+    }
+}
+
+// Builds a request from a decoded header block, which carries the request line
+// in pseudo-header fields ahead of the regular ones
+fn request_from_fields(fields: Vec<(String, String)>) -> Result<HttpRequest, String> {
+    let mut method = None;
+    let mut scheme = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut cookies = Vec::new();
+    let mut builder = HttpRequest::builder();
+    let mut regular = false;
+
+    for (name, value) in fields {
+        if let Some(pseudo) = name.strip_prefix(':') {
+            // the request line is rebuilt from these, so they can't hold whitespace either
+            if value.bytes().any(|byte| byte <= b' ' || byte == 0x7f) {
+                return Err(format!("invalid {} value", name));
+            }
+            if regular {
+                return Err(format!("pseudo-header {} after regular fields", name));
+            }
+            let field = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                _ => return Err(format!("unknown pseudo-header {}", name)),
+            };
+            if field.replace(value).is_some() {
+                return Err(format!("repeated pseudo-header {}", name));
+            }
+            continue;
+        }
+        regular = true;
+
+        check_field(&name, &value)?;
+        if name.eq_ignore_ascii_case(HEADER_TE) && !value.eq_ignore_ascii_case(TE_TRAILERS) {
+            return Err(format!("te: {}", value));
+        }
+
+        // cookies may be split into one field per pair for better compression
+        if name.eq_ignore_ascii_case(HEADER_COOKIE) {
+            cookies.push(value);
+        } else {
+            builder.header(&name, &value);
+        }
+    }
+
+    let method: Method = method.ok_or("missing :method")?.parse().map_err(|_| "invalid :method")?;
+    if method != Method::Connect && scheme.is_none() {
+        return Err("missing :scheme".to_string());
+    }
+    let path = match (path, &method) {
+        (Some(path), _) if !path.is_empty() => path,
+        (None, Method::Connect) => authority.clone().ok_or("missing :authority")?,
+        _ => return Err("missing :path".to_string()),
+    };
+
+    if !cookies.is_empty() {
+        builder.header(HEADER_COOKIE, &cookies.join("; "));
+    }
+
+    let mut request = builder.method(method).path(path).build();
+    request.set_version(HTTP_VERSION_2_0);
+    if let (Some(authority), None) = (authority, request.header(HEADER_HOST)) {
+        request.headers.insert(0, Header::new(HEADER_HOST, authority));
+    }
+
+    Ok(request)
+}
+
+// Rejects the fields RFC 9113 8.2 calls malformed. Passed on to HTTP/1.1,
+// their CR, LF or NUL would let a client smuggle in fields of its own.
+fn check_field(name: &str, value: &str) -> Result<(), String> {
+    if !is_token(name) || name.bytes().any(|byte| byte.is_ascii_uppercase()) {
+        return Err(format!("invalid field name {:?}", name));
+    }
+    if value.bytes().any(|byte| matches!(byte, b'\r' | b'\n' | b'\0'))
+        || value.starts_with([' ', '\t'])
+        || value.ends_with([' ', '\t']) {
+        return Err(format!("invalid value for {}", name));
+    }
+    if CONNECTION_SPECIFIC.iter().any(|key| name.eq_ignore_ascii_case(key)) {
+        return Err(format!("connection-specific field {}", name));
+    }
+    Ok(())
+}
+
+// Encodes the status and headers of a response, leaving out connection-specific fields
+fn response_block(response: &HttpResponse) -> Vec<u8> {
+    let status = response.status.as_u16().to_string();
+    let headers: Vec<(String, &str)> = response.headers().iter()
+        .filter(|header| !CONNECTION_SPECIFIC.iter().any(|key| header.key().eq_ignore_ascii_case(key)))
+        .map(|header| (header.key().to_ascii_lowercase(), header.value()))
+        .collect();
+
+    hpack::encode([(":status", status.as_str())].into_iter().chain(headers.iter().map(|(name, value)| (name.as_str(), *value))))
+}
+
+fn trailer_block(trailers: &[Header]) -> Vec<u8> {
+    let trailers: Vec<(String, &str)> = trailers.iter().map(|trailer| (trailer.key().to_ascii_lowercase(), trailer.value())).collect();
+    hpack::encode(trailers.iter().map(|(name, value)| (name.as_str(), *value)))
+}
+
+// The payload of a DATA or HEADERS frame without its padding
+fn unpad(frame: &Frame) -> Result<&[u8], Http2Error> {
+    if frame.flags & FLAG_PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+
+    match frame.payload.split_first() {
+        Some((&padding, rest)) if (padding as usize) <= rest.len() => Ok(&rest[..rest.len() - padding as usize]),
+        _ => Err(Http2Error::Connection(ErrorCode::ProtocolError)),
+    }
+}
+
+struct Connection<'a> {
+    server: &'a HttpServer,
+    writer: BufWriter<&'a TcpStream>,
+    events: Sender<Event>,
+    decoder: hpack::Decoder,
+    streams: BTreeMap<u32, Stream>,
+    last_stream_id: u32,
+    continuation: Option<HeaderBlock>,
+    // the connection window and the initial stream window granted by the client
+    send_window: i64,
+    initial_window: i64,
+    max_frame_size: usize,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    proxy_header: Option<ProxyHeader>,
+    // the client sent GOAWAY
+    closing: bool,
+}
+
+impl Connection<'_> {
+
+    async fn run(&mut self, receiver: Receiver<Event>, upgrade: Option<(HttpRequest, Vec<u8>)>) -> io::Result<()> {
+        let mut settings = Vec::new();
+        settings.extend(SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes());
+        settings.extend((MAX_CONCURRENT_STREAMS as u32).to_be_bytes());
+        settings.extend(SETTINGS_MAX_HEADER_LIST_SIZE.to_be_bytes());
+        settings.extend((MAX_HEADER_LIST_SIZE as u32).to_be_bytes());
+        self.write_frame(SETTINGS, 0, 0, &settings).await?;
+
+        if let Some((request, settings)) = upgrade {
+            let result = match self.apply_settings(&settings) {
+                Ok(()) => self.open_upgraded(request),
+                Err(error) => Err(error),
+            };
+            if !self.handle_result(result).await? {
+                return Ok(());
+            }
+        }
+        self.writer.flush().await?;
+
+        while let Ok(event) = receiver.recv().await {
+            let result = match event {
+                Event::Frame(frame) => self.on_frame(frame).await,
+                Event::Interim(stream_id, response) => self.on_interim(stream_id, response).await,
+                Event::Final(stream_id, response, next) => self.on_final(stream_id, response, next).await,
+                Event::Data(stream_id, data) => self.on_body(stream_id, Some(data)).await,
+                Event::End(stream_id) => self.on_body(stream_id, None).await,
+                Event::Failed(stream_id) if self.streams.contains_key(&stream_id) => Err(Http2Error::Stream(stream_id, ErrorCode::InternalError)),
+                Event::Failed(_) => Ok(()),
+                Event::Consumed(stream_id, count) => self.on_consumed(stream_id, count).await,
+                Event::Closed(None) => break,
+                Event::Closed(Some(code)) => Err(Http2Error::Connection(code)),
+            };
+
+            if !self.handle_result(result).await? {
+                break;
+            }
+            self.writer.flush().await?;
+
+            // the client is going away and every response has been sent
+            if self.closing && self.streams.is_empty() {
+                self.go_away(ErrorCode::NoError).await?;
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Resets the stream or ends the connection after an error, returning
+    // whether to carry on
+    async fn handle_result(&mut self, result: Result<(), Http2Error>) -> io::Result<bool> {
+        match result {
+            Ok(()) => Ok(true),
+            Err(Http2Error::Stream(stream_id, code)) => {
+                debug!("Resetting stream {} from {}: {:?}", stream_id, self.peer_addr, code);
+                self.streams.remove(&stream_id);
+                self.write_frame(RST_STREAM, 0, stream_id, &(code as u32).to_be_bytes()).await?;
+                Ok(true)
+            }
+            Err(Http2Error::Connection(code)) => {
+                debug!("Closing HTTP/2 connection from {}: {:?}", self.peer_addr, code);
+                self.go_away(code).await?;
+                Ok(false)
+            }
+            Err(Http2Error::Io(error)) => Err(error),
+        }
+    }
+
+    async fn go_away(&mut self, code: ErrorCode) -> io::Result<()> {
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend((code as u32).to_be_bytes());
+        self.write_frame(GOAWAY, 0, 0, &payload).await?;
+        self.writer.flush().await
+    }
+
+    fn open_upgraded(&mut self, request: HttpRequest) -> Result<(), Http2Error> {
+        self.last_stream_id = 1;
+        self.dispatch(1, request, false)
+    }
+
+    async fn on_frame(&mut self, frame: Frame) -> Result<(), Http2Error> {
+
+        // nothing may come between the frames of a header block
+        if let Some(ref block) = self.continuation {
+            if frame.kind != CONTINUATION || frame.stream_id != block.stream_id {
+                return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+            }
+        }
+
+        match frame.kind {
+            DATA => self.on_data(frame).await,
+            HEADERS => self.on_headers(frame).await,
+            CONTINUATION => self.on_continuation(frame).await,
+            PRIORITY if frame.stream_id == 0 => Err(Http2Error::Connection(ErrorCode::ProtocolError)),
+            PRIORITY if frame.payload.len() != 5 => Err(Http2Error::Stream(frame.stream_id, ErrorCode::FrameSizeError)),
+            RST_STREAM => self.on_reset(frame),
+            SETTINGS => self.on_settings(frame).await,
+            PUSH_PROMISE => Err(Http2Error::Connection(ErrorCode::ProtocolError)),
+            PING => self.on_ping(frame).await,
+            GOAWAY => {
+                self.closing = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.on_window_update(frame).await,
+            // priorities are advisory and unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    async fn on_data(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        let stream_id = frame.stream_id;
+        if stream_id == 0 {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        }
+
+        // the whole frame counts against the connection window, whatever happens to the
+        // data, and is handed straight back; the stream windows bound what is held
+        let length = frame.payload.len();
+        if length > 0 {
+            self.write_frame(WINDOW_UPDATE, 0, 0, &(length as u32).to_be_bytes()).await?;
+        }
+
+        let data = unpad(&frame)?;
+        let end_stream = frame.flags & FLAG_END_STREAM != 0;
+
+        let incoming = match self.streams.get_mut(&stream_id) {
+            Some(Stream { incoming: Some(incoming), .. }) => incoming,
+            Some(_) => return Err(Http2Error::Stream(stream_id, ErrorCode::StreamClosed)),
+            None if stream_id > self.last_stream_id => return Err(Http2Error::Connection(ErrorCode::ProtocolError)),
+            // data still on its way to a stream that was reset
+            None => return Ok(()),
+        };
+
+        incoming.window -= length as i64;
+        if incoming.window < 0 {
+            return Err(Http2Error::Stream(stream_id, ErrorCode::FlowControlError));
+        }
+        incoming.received += data.len();
+        if incoming.content_length.is_some_and(|length| incoming.received > length) {
+            return Err(Http2Error::Stream(stream_id, ErrorCode::ProtocolError));
+        }
+
+        // the window comes back as the handler reads the data, and right away
+        // for the padding and for data the handler no longer wants
+        let mut refund = length - data.len();
+        if !data.is_empty() && incoming.body.as_ref().is_none_or(|body| body.try_send(Ok(data.to_vec())).is_err()) {
+            refund += data.len();
+        }
+
+        // the last frame can carry data too, which goes to the handler first
+        if end_stream {
+            return self.end_request(stream_id, Vec::new());
+        }
+        if refund > 0 {
+            incoming.window += refund as i64;
+            self.write_frame(WINDOW_UPDATE, 0, stream_id, &(refund as u32).to_be_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn on_headers(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        if frame.stream_id == 0 {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        }
+
+        let mut fragment = unpad(&frame)?;
+        if frame.flags & FLAG_PRIORITY != 0 {
+            fragment = fragment.get(5..).ok_or(Http2Error::Connection(ErrorCode::FrameSizeError))?;
+        }
+
+        let block = HeaderBlock {
+            stream_id: frame.stream_id,
+            end_stream: frame.flags & FLAG_END_STREAM != 0,
+            fragment: fragment.to_vec(),
+        };
+
+        if frame.flags & FLAG_END_HEADERS != 0 {
+            self.on_header_block(block).await
+        } else {
+            self.continuation = Some(block);
+            Ok(())
+        }
+    }
+
+    async fn on_continuation(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        let Some(mut block) = self.continuation.take() else {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        };
+
+        block.fragment.extend_from_slice(&frame.payload);
+        if block.fragment.len() > MAX_HEADER_BLOCK_SIZE {
+            return Err(Http2Error::Connection(ErrorCode::EnhanceYourCalm));
+        }
+
+        if frame.flags & FLAG_END_HEADERS != 0 {
+            self.on_header_block(block).await
+        } else {
+            self.continuation = Some(block);
+            Ok(())
+        }
+    }
+
+    async fn on_header_block(&mut self, block: HeaderBlock) -> Result<(), Http2Error> {
+        let stream_id = block.stream_id;
+
+        // every block is decoded, even for refused streams, to keep the table in step
+        let fields = match self.decoder.decode(&block.fragment) {
+            Ok(fields) => Some(fields),
+            Err(error) if error == hpack::HEADER_LIST_TOO_LARGE => None,
+            Err(error) => {
+                debug!("Invalid header block from {}: {}", self.peer_addr, error);
+                return Err(Http2Error::Connection(ErrorCode::CompressionError));
+            }
+        };
+
+        // a second block on a stream carries the trailers and ends the request
+        if let Some(stream) = self.streams.get(&stream_id) {
+            if stream.incoming.is_none() {
+                return Err(Http2Error::Stream(stream_id, ErrorCode::StreamClosed));
+            }
+            if !block.end_stream {
+                return Err(Http2Error::Stream(stream_id, ErrorCode::ProtocolError));
+            }
+
+            let fields = fields.ok_or_else(|| {
+                debug!("Trailers from {} too large", self.peer_addr);
+                Http2Error::Stream(stream_id, ErrorCode::ProtocolError)
+            })?;
+            if let Err(error) = fields.iter().try_for_each(|(name, value)| check_field(name, value)) {
+                debug!("Malformed trailers from {}: {}", self.peer_addr, error);
+                return Err(Http2Error::Stream(stream_id, ErrorCode::ProtocolError));
+            }
+
+            return self.end_request(stream_id, fields.into_iter().map(|(name, value)| Header::new(name, value)).collect());
+        }
+
+        // client streams are odd and each one opens above the last
+        if stream_id.is_multiple_of(2) || stream_id <= self.last_stream_id {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        }
+        self.last_stream_id = stream_id;
+
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS {
+            return Err(Http2Error::Stream(stream_id, ErrorCode::RefusedStream));
+        }
+
+        let Some(fields) = fields else {
+            debug!("Header list from {} too large", self.peer_addr);
+            return self.refuse(stream_id, Status::RequestHeaderFieldsTooLarge, block.end_stream).await;
+        };
+
+        let request = request_from_fields(fields).map_err(|error| {
+            debug!("Malformed request from {}: {}", self.peer_addr, error);
+            Http2Error::Stream(stream_id, ErrorCode::ProtocolError)
+        })?;
+
+        self.dispatch(stream_id, request, !block.end_stream)
+    }
+
+    // Hands a request to the handler, with its body to follow in DATA frames
+    // when there is one
+    fn dispatch(&mut self, stream_id: u32, mut request: HttpRequest, has_body: bool) -> Result<(), Http2Error> {
+        let content_length = match request.header(HEADER_CONTENT_LENGTH) {
+            Some(length) => Some(length.trim().parse::<usize>().map_err(|_| Http2Error::Stream(stream_id, ErrorCode::ProtocolError))?),
+            None => None,
+        };
+
+        // a body that disagrees with its Content-Length is malformed
+        let received = request.body.as_ref().map_or(0, Vec::len);
+        if !has_body && content_length.is_some_and(|length| length != received) {
+            return Err(Http2Error::Stream(stream_id, ErrorCode::ProtocolError));
+        }
+
+        request.set_addrs(self.peer_addr, self.local_addr);
+        if let Some(proxy_header) = self.proxy_header {
+            request.set_proxy_header(proxy_header);
+        }
+
+        let (incoming, body) = if has_body {
+            let (sender, body) = BodyStream::unbounded_channel();
+            let body = BodyStream::new(WindowedBody { body, stream_id, events: self.events.clone() });
+            let incoming = Incoming {
+                body: Some(sender),
+                trailers: request.pending_trailers(),
+                content_length,
+                received: 0,
+                window: DEFAULT_WINDOW_SIZE,
+            };
+            (Some(incoming), Some(body))
+        } else {
+            (None, None)
+        };
+
+        let in_flight = self.server.spawn_handler_with_body(request, body);
+        self.streams.insert(stream_id, Stream {
+            method: in_flight.method,
+            accepts_trailers: in_flight.accepts_trailers,
+            incoming,
+            send_window: self.initial_window,
+            outgoing: None,
+        });
+        forward_replies(stream_id, in_flight.replies, self.events.clone());
+        Ok(())
+    }
+
+    // Ends a request body once it has arrived in full
+    fn end_request(&mut self, stream_id: u32, trailers: Vec<Header>) -> Result<(), Http2Error> {
+        let Some(stream) = self.streams.get_mut(&stream_id) else {
+            return Ok(());
+        };
+
+        let Some(mut incoming) = stream.incoming.take() else {
+            return Err(Http2Error::Stream(stream_id, ErrorCode::StreamClosed));
+        };
+
+        // a body that disagrees with its Content-Length is malformed
+        match incoming.end(trailers) {
+            true => Ok(()),
+            false => Err(Http2Error::Stream(stream_id, ErrorCode::ProtocolError)),
+        }
+    }
+
+    // Answers a request without passing it to the handler
+    async fn refuse(&mut self, stream_id: u32, status: Status, end_stream: bool) -> Result<(), Http2Error> {
+        let response = HttpResponse::new(status, vec![], None);
+        self.write_headers(stream_id, &response_block(&response), true).await?;
+
+        // the client can stop sending the rest of the request
+        match end_stream {
+            true => Ok(()),
+            false => Err(Http2Error::Stream(stream_id, ErrorCode::NoError)),
+        }
+    }
+
+    async fn on_consumed(&mut self, stream_id: u32, count: usize) -> Result<(), Http2Error> {
+        // the window only matters while more of the body can come
+        let Some(Stream { incoming: Some(incoming), .. }) = self.streams.get_mut(&stream_id) else {
+            return Ok(());
+        };
+
+        incoming.window += count as i64;
+        self.write_frame(WINDOW_UPDATE, 0, stream_id, &(count as u32).to_be_bytes()).await?;
+        Ok(())
+    }
+
+    fn on_reset(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        if frame.stream_id == 0 || frame.stream_id > self.last_stream_id {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        }
+        if frame.payload.len() != 4 {
+            return Err(Http2Error::Connection(ErrorCode::FrameSizeError));
+        }
+
+        // the handler runs to the end, but its response is dropped
+        self.streams.remove(&frame.stream_id);
+        Ok(())
+    }
+
+    async fn on_settings(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        if frame.stream_id != 0 {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        }
+
+        if frame.flags & FLAG_ACK != 0 {
+            return match frame.payload.is_empty() {
+                true => Ok(()),
+                false => Err(Http2Error::Connection(ErrorCode::FrameSizeError)),
+            };
+        }
+
+        self.apply_settings(&frame.payload)?;
+        self.write_frame(SETTINGS, FLAG_ACK, 0, &[]).await?;
+
+        // a larger initial window may let waiting responses through
+        self.send_pending().await
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), Http2Error> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(Http2Error::Connection(ErrorCode::FrameSizeError));
+        }
+
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(Http2Error::Connection(ErrorCode::ProtocolError)),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let window = value as i64;
+                    if window > MAX_WINDOW_SIZE {
+                        return Err(Http2Error::Connection(ErrorCode::FlowControlError));
+                    }
+
+                    // the change applies to the windows of streams already open
+                    let delta = window - self.initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW_SIZE {
+                            return Err(Http2Error::Connection(ErrorCode::FlowControlError));
+                        }
+                    }
+                    self.initial_window = window;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    let size = value as usize;
+                    if !(DEFAULT_MAX_FRAME_SIZE..=MAX_FRAME_SIZE_LIMIT).contains(&size) {
+                        return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+                    }
+                    self.max_frame_size = size;
+                }
+                // push is never used, and responses are encoded without the dynamic table
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn on_ping(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        if frame.stream_id != 0 {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        }
+        if frame.payload.len() != 8 {
+            return Err(Http2Error::Connection(ErrorCode::FrameSizeError));
+        }
+
+        if frame.flags & FLAG_ACK == 0 {
+            self.write_frame(PING, FLAG_ACK, 0, &frame.payload).await?;
+        }
+        Ok(())
+    }
+
+    async fn on_window_update(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        let stream_id = frame.stream_id;
+        let [a, b, c, d] = frame.payload[..] else {
+            return Err(Http2Error::Connection(ErrorCode::FrameSizeError));
+        };
+        let increment = (u32::from_be_bytes([a, b, c, d]) & 0x7fff_ffff) as i64;
+
+        if stream_id == 0 {
+            if increment == 0 {
+                return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW_SIZE {
+                return Err(Http2Error::Connection(ErrorCode::FlowControlError));
+            }
+        } else if stream_id > self.last_stream_id {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError));
+        } else if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if increment == 0 {
+                return Err(Http2Error::Stream(stream_id, ErrorCode::ProtocolError));
+            }
+            stream.send_window += increment;
+            if stream.send_window > MAX_WINDOW_SIZE {
+                return Err(Http2Error::Stream(stream_id, ErrorCode::FlowControlError));
+            }
+        }
+
+        self.send_pending().await
+    }
+
+    async fn on_interim(&mut self, stream_id: u32, mut response: HttpResponse) -> Result<(), Http2Error> {
+        if !self.streams.contains_key(&stream_id) {
+            return Ok(());
+        }
+
+        response.remove_header(HEADER_CONTENT_LENGTH);
+        self.write_headers(stream_id, &response_block(&response), false).await?;
+        Ok(())
+    }
+
+    async fn on_final(&mut self, stream_id: u32, mut response: HttpResponse, next: Option<Sender<()>>) -> Result<(), Http2Error> {
+        // the client may have reset the stream in the meantime
+        let Some(stream) = self.streams.get(&stream_id) else {
+            return Ok(());
+        };
+
+        // the same body rules as HTTP/1.1, but trailers need no chunked coding here
+        let code = response.status.as_u16();
+        if matches!(code, 100..=199 | 204) {
+            response.remove_header(HEADER_CONTENT_LENGTH);
+        }
+        let send_body = stream.method != Method::Head && !matches!(code, 100..=199 | 204 | 304);
+
+        let streamed = send_body && next.is_some();
+        let body = match send_body && !streamed {
+            true => response.body().cloned().unwrap_or_default(),
+            false => Vec::new(),
+        };
+        let trailers = if send_body && stream.accepts_trailers { response.trailers().to_vec() } else { Vec::new() };
+
+        let end_stream = !streamed && body.is_empty() && trailers.is_empty();
+        self.write_headers(stream_id, &response_block(&response), end_stream).await?;
+
+        if end_stream {
+            return self.finish(stream_id).await;
+        }
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            let next = if streamed { next } else { None };
+            stream.outgoing = Some(Outgoing { data: body, sent: 0, end: !streamed, trailers, next });
+        }
+        self.send_pending().await
+    }
+
+    // Takes the next piece of a streamed response body, or its end
+    async fn on_body(&mut self, stream_id: u32, data: Option<Vec<u8>>) -> Result<(), Http2Error> {
+        let Some(Stream { outgoing: Some(outgoing), .. }) = self.streams.get_mut(&stream_id) else {
+            return Ok(());
+        };
+
+        match data {
+            Some(data) => {
+                outgoing.data = data;
+                outgoing.sent = 0;
+            }
+            None => outgoing.end = true,
+        }
+        self.send_pending().await
+    }
+
+    // Sends as much of the waiting response bodies as the flow control windows
+    // allow, oldest stream first
+    async fn send_pending(&mut self) -> Result<(), Http2Error> {
+        let waiting: Vec<u32> = self.streams.iter()
+            .filter(|(_, stream)| stream.outgoing.is_some())
+            .map(|(stream_id, _)| *stream_id)
+            .collect();
+
+        for stream_id in waiting {
+            let Some(stream) = self.streams.get_mut(&stream_id) else { continue };
+            let Some(ref mut outgoing) = stream.outgoing else { continue };
+
+            let mut finished = false;
+            while outgoing.sent < outgoing.data.len() {
+                let window = self.send_window.min(stream.send_window).max(0) as usize;
+                let size = (outgoing.data.len() - outgoing.sent).min(self.max_frame_size).min(window);
+                if size == 0 {
+                    break;
+                }
+
+                let end = outgoing.sent + size;
+                let end_stream = outgoing.end && end == outgoing.data.len() && outgoing.trailers.is_empty();
+                let flags = if end_stream { FLAG_END_STREAM } else { 0 };
+
+                write_frame(&mut self.writer, DATA, flags, stream_id, &outgoing.data[outgoing.sent..end]).await?;
+                outgoing.sent = end;
+                self.send_window -= size as i64;
+                stream.send_window -= size as i64;
+                finished = end_stream;
+            }
+
+            if outgoing.sent == outgoing.data.len() {
+                if !outgoing.end {
+                    // the piece is out, so the next one can be read
+                    if !outgoing.data.is_empty() {
+                        outgoing.data.clear();
+                        outgoing.sent = 0;
+                        if let Some(ref next) = outgoing.next {
+                            let _ = next.try_send(());
+                        }
+                    }
+                } else if !outgoing.trailers.is_empty() {
+                    // trailers follow the body and need no window
+                    let block = trailer_block(&outgoing.trailers);
+                    write_headers(&mut self.writer, stream_id, &block, true, self.max_frame_size).await?;
+                    finished = true;
+                } else if !finished {
+                    // a streamed body ends after its last piece has gone out
+                    write_frame(&mut self.writer, DATA, FLAG_END_STREAM, stream_id, &[]).await?;
+                    finished = true;
+                }
+            }
+
+            if finished {
+                self.finish(stream_id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Forgets a stream once its response has been sent. The client is told
+    // with a reset without error when the rest of its request isn't needed.
+    async fn finish(&mut self, stream_id: u32) -> Result<(), Http2Error> {
+        if let Some(stream) = self.streams.remove(&stream_id) {
+            if stream.incoming.is_some() {
+                self.write_frame(RST_STREAM, 0, stream_id, &(ErrorCode::NoError as u32).to_be_bytes()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> io::Result<()> {
+        write_frame(&mut self.writer, kind, flags, stream_id, payload).await
+    }
+
+    async fn write_headers(&mut self, stream_id: u32, block: &[u8], end_stream: bool) -> io::Result<()> {
+        write_headers(&mut self.writer, stream_id, block, end_stream, self.max_frame_size).await
+    }
+}
+
+async fn write_frame(writer: &mut BufWriter<&TcpStream>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> io::Result<()> {
+    let length = (payload.len() as u32).to_be_bytes();
+    let stream_id = stream_id.to_be_bytes();
+
+    let header = [length[1], length[2], length[3], kind, flags, stream_id[0], stream_id[1], stream_id[2], stream_id[3]];
+    writer.write_all(&header).await?;
+    writer.write_all(payload).await
+}
+
+// Writes a header block as HEADERS followed by as many CONTINUATION frames as it takes
+async fn write_headers(writer: &mut BufWriter<&TcpStream>, stream_id: u32, block: &[u8], end_stream: bool, max_frame_size: usize) -> io::Result<()> {
+    let mut fragments = block.chunks(max_frame_size).peekable();
+    let mut kind = HEADERS;
+    let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+
+    // an empty block still takes one frame
+    if fragments.peek().is_none() {
+        return write_frame(writer, kind, flags | FLAG_END_HEADERS, stream_id, &[]).await;
+    }
+
+    while let Some(fragment) = fragments.next() {
+        if fragments.peek().is_none() {
+            flags |= FLAG_END_HEADERS;
+        }
+        write_frame(writer, kind, flags, stream_id, fragment).await?;
+        kind = CONTINUATION;
+        flags = 0;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use std::fmt::Display;
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::http::{CONTENT_TYPE_APPLICATION_JSON, HEADER_CONTENT_TYPE, Status};
+use crate::message::{HttpRequest, HttpResponse, HttpResponseBuilder};
+
+#[derive(Debug)]
+pub enum JsonError {
+    UnsupportedMediaType,
+    Invalid(serde_json::Error),
+}
+
+impl JsonError {
+    pub fn status(&self) -> Status {
+        match self {
+            JsonError::UnsupportedMediaType => Status::UnsupportedMediaType,
+            JsonError::Invalid(_) => Status::BadRequest,
+        }
+    }
+}
+
+impl Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonError::UnsupportedMediaType => write!(f, "expected content type {}", CONTENT_TYPE_APPLICATION_JSON),
+            JsonError::Invalid(error) => write!(f, "invalid json body: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl From<JsonError> for HttpResponse {
+    fn from(error: JsonError) -> Self {
+        HttpResponse::new(error.status(), vec![], Some(error.to_string().into_bytes()))
+    }
+}
+
+// Accepts application/json as well as structured suffixes like application/problem+json
+fn is_json(content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    content_type == CONTENT_TYPE_APPLICATION_JSON
+        || (content_type.starts_with("application/") && content_type.ends_with("+json"))
+}
+
+impl HttpRequest {
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        if !self.content_type().is_some_and(is_json) {
+            return Err(JsonError::UnsupportedMediaType);
+        }
+
+        let body = self.body.as_deref().unwrap_or_default();
+        serde_json::from_slice(body).map_err(JsonError::Invalid)
+    }
+}
+
+impl HttpResponse {
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> HttpResponse {
+        HttpResponse::builder().json(value).build()
+    }
+}
+
+impl HttpResponseBuilder {
+
+    // A value that fails to serialize turns the response into a 500
+    pub fn json<T: Serialize + ?Sized>(&mut self, value: &T) -> &mut Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => self
+                .header(HEADER_CONTENT_TYPE, CONTENT_TYPE_APPLICATION_JSON)
+                .body(body),
+            Err(err) => {
+                error!("Failed to serialize json response: {}", err);
+                self.status(Status::InternalServerError)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use super::*;
+
+    fn request(content_type: Option<&str>, body: &[u8]) -> HttpRequest {
+        let mut builder = HttpRequest::builder();
+        if let Some(content_type) = content_type {
+            builder.header(HEADER_CONTENT_TYPE, content_type);
+        }
+        builder.body(body.to_vec()).build()
+    }
+
+    #[test]
+    fn deserializes_typed_bodies() {
+        let counts: BTreeMap<String, u32> = request(Some("application/json; charset=utf-8"), br#"{"apples": 3, "pears": 5}"#).json().unwrap();
+        assert_eq!(counts, BTreeMap::from([("apples".to_string(), 3), ("pears".to_string(), 5)]));
+
+        let (title, values): (String, Vec<u8>) = request(Some("application/problem+json"), br#"["title", [1, 2]]"#).json().unwrap();
+        assert_eq!((title.as_str(), values), ("title", vec![1, 2]));
+    }
+
+    #[test]
+    fn malformed_json_is_a_bad_request() {
+        for body in [&b"{\"apples\": "[..], b"", b"{\"apples\": \"three\"}"] {
+            let error = request(Some(CONTENT_TYPE_APPLICATION_JSON), body).json::<BTreeMap<String, u32>>().unwrap_err();
+            assert!(matches!(error, JsonError::Invalid(_)), "{:?}", error);
+            assert_eq!(HttpResponse::from(error).status, Status::BadRequest);
+        }
+    }
+
+    #[test]
+    fn other_content_types_are_unsupported() {
+        for content_type in [None, Some("text/plain"), Some("application/jsonp"), Some("text/x+json")] {
+            let error = request(content_type, b"{}").json::<BTreeMap<String, u32>>().unwrap_err();
+            assert!(matches!(error, JsonError::UnsupportedMediaType), "{:?}", error);
+            assert_eq!(HttpResponse::from(error).status, Status::UnsupportedMediaType);
+        }
+    }
+
+    #[test]
+    fn serialized_responses_are_json() {
+        let response = HttpResponse::json(&BTreeMap::from([("apples", 3)]));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.header(HEADER_CONTENT_TYPE), Some(CONTENT_TYPE_APPLICATION_JSON));
+        assert_eq!(response.body().unwrap(), br#"{"apples":3}"#);
+
+        // maps need string keys, so this can't be serialized
+        let response = HttpResponse::builder().json(&BTreeMap::from([((1, 2), 3)])).build();
+        assert_eq!(response.status, Status::InternalServerError);
+        assert_eq!(response.header(HEADER_CONTENT_TYPE), None);
+    }
+}
@@ -1,3 +1,21 @@
 pub mod message;
 pub mod http;
 pub mod server;
+pub mod cookie;
+pub mod session;
+pub mod form;
+pub mod multipart;
+pub mod negotiation;
+pub mod cors;
+pub mod auth;
+pub mod chunked;
+pub mod decoder;
+pub mod parser;
+pub mod hpack;
+pub mod http2;
+pub mod proxy;
+pub mod forwarded;
+pub mod proxy_protocol;
+pub mod client;
+#[cfg(feature = "json")]
+pub mod json;
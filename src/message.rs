@@ -1,30 +1,363 @@
-use std::fmt::Display;
-use crate::http::{CONNECTION_KEEP_ALIVE, Header, HEADER_CONNECTION, HEADER_CONTENT_LENGTH, HEADER_DATE, HEADER_SERVER, HTTP_VERSION_1_1, Method, Status};
+use std::fmt::{Debug, Display};
+use std::io;
+use std::io::{BufRead, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use async_std::channel;
+use async_std::channel::{Receiver, Sender};
+use async_std::task;
+use async_std::io::WriteExt;
+use crate::auth::Authorization;
+use crate::cookie::{Cookie, CookieJar};
+use crate::decoder;
+use crate::decoder::{BodyReader, Framing, ResponseHead};
+use crate::form::{Form, FormError};
+use crate::forwarded::ForwardedInfo;
+use crate::multipart::{Multipart, MultipartError, MultipartLimits};
+use crate::parser::RequestHead;
+use crate::proxy_protocol::ProxyHeader;
+use crate::session::Session;
+use crate::http::{CONNECTION_KEEP_ALIVE, Header, HEADER_AUTHORIZATION, HEADER_CONNECTION, HEADER_CONTENT_LENGTH, HEADER_CONTENT_TYPE, HEADER_COOKIE, HEADER_DATE, HEADER_SERVER, HEADER_SET_COOKIE, HEADER_TE, HEADER_TRANSFER_ENCODING, HEADER_VARY, HTTP_VERSION_1_1, Method, Status, TE_TRAILERS};
 
+#[derive(Clone)]
 pub struct HttpRequest {
     hostname: String,
     path: String,
     method: Method,
+    // set when the server answers the request as another method, e.g. HEAD as GET
+    original_method: Option<Method>,
+    version: String,
     pub headers: Vec<Header>,
     pub body: Option<Vec<u8>>,
+    // shared by clones, so the trailers of a streamed body can follow the request
+    trailers: Arc<OnceLock<Vec<Header>>>,
+    session: Option<Session>,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    proxy_header: Option<ProxyHeader>,
+    forwarded: Option<ForwardedInfo>,
+    replies: Option<Sender<Reply>>,
+    body_stream: Option<BodyStream>,
+    // request headers consulted by content negotiation, shared by clones
+    negotiated: Arc<Mutex<Vec<&'static str>>>,
+}
+
+// What a handler sends back to the connection serving its request
+pub(crate) enum Reply {
+    Interim(HttpResponse),
+    Final(HttpResponse),
 }
 
 impl HttpRequest {
     pub fn parse(request_line: String) -> Self {
 
-        let mut parts = request_line.trim().split_whitespace();
-        let method = Method::from_str(parts.next().unwrap()).unwrap();
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap().parse::<Method>().unwrap();
         let path = parts.next().unwrap().to_string();
-        let _ = parts.next().unwrap().to_string();
+        let version = parts.next().unwrap().to_string();
         
         HttpRequest {
             hostname: String::new(),
             method,
+            original_method: None,
+            version,
             path,
             headers: Vec::new(),
             body: None,
+            trailers: Arc::default(),
+            session: None,
+            peer_addr: None,
+            local_addr: None,
+            proxy_header: None,
+            forwarded: None,
+            replies: None,
+            body_stream: None,
+            negotiated: Arc::default(),
+        }
+    }
+
+    pub fn builder() -> HttpRequestBuilder {
+        HttpRequestBuilder::new()
+    }
+
+    pub(crate) fn from_head(head: &RequestHead) -> Self {
+        HttpRequest {
+            hostname: String::new(),
+            // the parser only accepts token methods, and any token is a method
+            method: head.method.parse().unwrap(),
+            original_method: None,
+            version: head.version.to_string(),
+            path: head.target.to_string(),
+            headers: head.headers.iter().map(Header::from).collect(),
+            body: None,
+            trailers: Arc::default(),
+            session: None,
+            peer_addr: None,
+            local_addr: None,
+            proxy_header: None,
+            forwarded: None,
+            replies: None,
+            body_stream: None,
+            negotiated: Arc::default(),
+        }
+    }
+
+    // Parses a complete request as written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = bytes;
+
+        let request_line = decoder::read_line(&mut reader)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "empty request"))?;
+
+        let parts: Vec<&str> = request_line.split_whitespace().collect();
+        let valid = parts.len() == 3 && parts[0].parse::<Method>().is_ok() && parts[2].starts_with("HTTP/1.");
+        if !valid {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid request line: {}", request_line)));
+        }
+
+        let mut request = HttpRequest::parse(request_line);
+        request.headers = decoder::read_headers(&mut reader)?;
+
+        // a request without framing headers has no body
+        let framing = decoder::body_framing(&request.headers, Framing::Empty)?;
+        if framing != Framing::Empty {
+            let mut decoder = BodyReader::new(reader, framing);
+            let mut body = Vec::new();
+            decoder.read_to_end(&mut body)?;
+            request.body = Some(body);
+            request.set_trailers(decoder.trailers().to_vec());
+        }
+
+        Ok(request)
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    // The method the client sent, which differs from `method` when the server
+    // answers the request as another method. Digest credentials cover this one.
+    pub fn original_method(&self) -> &Method {
+        self.original_method.as_ref().unwrap_or(&self.method)
+    }
+
+    pub(crate) fn set_method(&mut self, method: Method) {
+        let original = std::mem::replace(&mut self.method, method);
+        self.original_method.get_or_insert(original);
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // The protocol version from the request line, e.g. "HTTP/1.0"
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub(crate) fn set_version(&mut self, version: &str) {
+        self.version = version.to_string();
+    }
+
+    // The address of the directly connected client, which may be a proxy
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub(crate) fn set_addrs(&mut self, peer_addr: SocketAddr, local_addr: SocketAddr) {
+        self.peer_addr = Some(peer_addr);
+        self.local_addr = Some(local_addr);
+    }
+
+    // The original client address when the connection came through a load
+    // balancer speaking the PROXY protocol, otherwise the peer address
+    pub fn source_addr(&self) -> Option<SocketAddr> {
+        self.proxy_header
+            .and_then(|header| header.source)
+            .or(self.peer_addr)
+    }
+
+    // The address the client originally connected to, see `source_addr`
+    pub fn destination_addr(&self) -> Option<SocketAddr> {
+        self.proxy_header
+            .and_then(|header| header.destination)
+            .or(self.local_addr)
+    }
+
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.proxy_header.as_ref()
+    }
+
+    pub(crate) fn set_proxy_header(&mut self, proxy_header: ProxyHeader) {
+        self.proxy_header = Some(proxy_header);
+    }
+
+    pub(crate) fn set_replies(&mut self, replies: Sender<Reply>) {
+        self.replies = Some(replies);
+    }
+
+    // Sends an informational response, such as 103 Early Hints with Link
+    // headers, ahead of the final response. Only requests read by the server
+    // can do this, and only until the handler has returned. HTTP/1.0 clients
+    // don't understand them, so they only get the final response.
+    pub fn send_interim(&self, response: HttpResponse) -> io::Result<()> {
+        let code = response.status.as_u16();
+        if !(100..=199).contains(&code) || response.status == Status::SwitchingProtocols {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "interim responses must be 1xx other than 101"));
+        }
+
+        self.replies.as_ref()
+            .and_then(|replies| replies.try_send(Reply::Interim(response)).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "request has no open connection"))
+    }
+
+    // The originating client, taken from forwarding headers when the peer is
+    // a trusted proxy and the source address otherwise
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        match &self.forwarded {
+            Some(forwarded) => forwarded.client_ip,
+            None => self.source_addr().map(|address| address.ip()),
         }
     }
+
+    // The scheme the client used, "http" unless a trusted proxy said otherwise
+    pub fn scheme(&self) -> &str {
+        match &self.forwarded {
+            Some(forwarded) => &forwarded.scheme,
+            None => "http",
+        }
+    }
+
+    pub(crate) fn set_forwarded(&mut self, forwarded: ForwardedInfo) {
+        self.forwarded = Some(forwarded);
+    }
+
+    // Fields sent after a chunked body, available once the body has been read
+    pub fn trailers(&self) -> &[Header] {
+        self.trailers.get().map_or(&[], Vec::as_slice)
+    }
+
+    // The body as it is still arriving, when the handler asked for it to be
+    // streamed. `body` is None in that case.
+    pub fn body_stream(&self) -> Option<&BodyStream> {
+        self.body_stream.as_ref()
+    }
+
+    pub(crate) fn set_body_stream(&mut self, body_stream: BodyStream) {
+        self.body_stream = Some(body_stream);
+    }
+
+    // Headers that negotiation picked a representation by. The server lists
+    // them in the Vary header of the response.
+    pub(crate) fn negotiated(&self) -> Vec<&'static str> {
+        self.negotiated.lock().unwrap().clone()
+    }
+
+    pub(crate) fn add_negotiated(&self, header: &'static str) {
+        let mut negotiated = self.negotiated.lock().unwrap();
+        if !negotiated.contains(&header) {
+            negotiated.push(header);
+        }
+    }
+
+    pub(crate) fn set_trailers(&mut self, trailers: Vec<Header>) {
+        self.trailers = Arc::new(OnceLock::from(trailers));
+    }
+
+    // Where the trailers go once a body the handler streams has ended
+    pub(crate) fn pending_trailers(&self) -> Arc<OnceLock<Vec<Header>>> {
+        self.trailers.clone()
+    }
+
+    // Whether the client will accept trailer fields after a chunked response
+    pub fn accepts_trailers(&self) -> bool {
+        self.headers.iter()
+            .filter(|header| header.key().eq_ignore_ascii_case(HEADER_TE))
+            .flat_map(|header| header.value().split(','))
+            .any(|coding| coding.trim().eq_ignore_ascii_case(TE_TRAILERS))
+    }
+
+    // Only present when the request went through a SessionMiddleware
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    pub(crate) fn set_session(&mut self, session: Session) {
+        self.session = Some(session);
+    }
+
+    // Returns the value of the first header matching the key, ignoring case
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|header| header.key().eq_ignore_ascii_case(key))
+            .map(|header| header.value())
+    }
+
+    // Returns the media type of the body without any parameters
+    pub fn content_type(&self) -> Option<&str> {
+        self.header(HEADER_CONTENT_TYPE)
+            .map(|value| value.split(';').next().unwrap_or_default().trim())
+    }
+
+    pub fn form(&self) -> Result<Form, FormError> {
+        Form::from_request(self)
+    }
+
+    pub fn multipart(&self) -> Result<Multipart<Box<dyn Read + '_>>, MultipartError> {
+        Multipart::from_request(self, MultipartLimits::default())
+    }
+
+    pub fn authorization(&self) -> Option<Authorization> {
+        self.header(HEADER_AUTHORIZATION).and_then(Authorization::parse)
+    }
+
+    pub fn cookies(&self) -> CookieJar {
+        let mut jar = CookieJar::new();
+
+        self.headers.iter()
+            .filter(|header| header.key().eq_ignore_ascii_case(HEADER_COOKIE))
+            .flat_map(|header| Cookie::parse(header.value()))
+            .for_each(|cookie| jar.add(cookie));
+
+        jar
+    }
+
+    // Serializes the request line and headers including the blank line after them
+    pub fn head_to_bytes(&self) -> Vec<u8> {
+        let mut buffer = format!("{} {} {}\r\n", self.method, self.path, HTTP_VERSION_1_1).into_bytes();
+
+        for header in &self.headers {
+            buffer.extend(format!("{}: {}\r\n", header.key, header.value).as_bytes());
+        }
+
+        buffer.extend(b"\r\n");
+        buffer
+    }
+
+    // The body is written as it is, so it must match the framing headers
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = self.head_to_bytes();
+
+        if let Some(body) = &self.body {
+            buffer.extend(body);
+        }
+
+        buffer
+    }
+
+    pub async fn write_to<W>(&self, writer: &mut W) -> io::Result<()> where W: async_std::io::Write + Unpin {
+        writer.write_all(&self.head_to_bytes()).await?;
+
+        if let Some(body) = &self.body {
+            writer.write_all(body).await?;
+        }
+
+        writer.flush().await
+    }
 }
 
 impl Display for HttpRequest {
@@ -33,11 +366,180 @@ impl Display for HttpRequest {
     }
 }
 
+#[derive(Default)]
+pub struct HttpRequestBuilder {
+    method: Option<Method>,
+    path: Option<String>,
+    headers: Vec<Header>,
+    body: Option<Vec<u8>>,
+}
+
+impl HttpRequestBuilder {
+
+    pub fn new() -> Self {
+        HttpRequestBuilder::default()
+    }
+
+    pub fn method(&mut self, method: Method) -> &mut Self {
+        self.method = Some(method);
+        self
+    }
+
+    pub fn path<P>(&mut self, path: P) -> &mut Self where P: Into<String> {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn header(&mut self, key: &str, value: &str) -> &mut Self {
+        self.headers.push(Header::new(key, value));
+        self
+    }
+
+    // Cookies are collected into a single Cookie header
+    pub fn cookie(&mut self, cookie: Cookie) -> &mut Self {
+        let pair = format!("{}={}", cookie.name(), cookie.value());
+
+        match self.headers.iter_mut().find(|header| header.key().eq_ignore_ascii_case(HEADER_COOKIE)) {
+            Some(header) => header.value = format!("{}; {}", header.value, pair),
+            None => self.headers.push(Header::new(HEADER_COOKIE, pair)),
+        }
+
+        self
+    }
+
+    pub fn body(&mut self, body: Vec<u8>) -> &mut Self {
+        self.body = Some(body);
+        self
+    }
+
+    // Adds a Content-Length for the body unless the headers already frame it
+    pub fn build(&self) -> HttpRequest {
+        let mut headers = self.headers.clone();
+
+        let framed = headers.iter().any(|header| {
+            header.key().eq_ignore_ascii_case(HEADER_CONTENT_LENGTH) || header.key().eq_ignore_ascii_case(HEADER_TRANSFER_ENCODING)
+        });
+        if let (Some(body), false) = (&self.body, framed) {
+            headers.push(Header::new(HEADER_CONTENT_LENGTH, body.len().to_string()));
+        }
+
+        HttpRequest {
+            hostname: String::new(),
+            method: self.method.clone().unwrap_or(Method::Get),
+            original_method: None,
+            version: HTTP_VERSION_1_1.to_string(),
+            path: self.path.clone().unwrap_or_else(|| "/".to_string()),
+            headers,
+            body: self.body.clone(),
+            trailers: Arc::default(),
+            session: None,
+            peer_addr: None,
+            local_addr: None,
+            proxy_header: None,
+            forwarded: None,
+            replies: None,
+            body_stream: None,
+            negotiated: Arc::default(),
+        }
+    }
+}
+
+// A body that is produced while it is being written, e.g. relayed from another
+// server, or read while it is still arriving, instead of being held in memory
+// up front.
+#[derive(Clone)]
+pub struct BodyStream {
+    reader: Arc<Mutex<dyn Read + Send>>,
+}
+
+impl BodyStream {
+
+    pub fn new<R>(reader: R) -> Self where R: Read + Send + 'static {
+        BodyStream {
+            reader: Arc::new(Mutex::new(reader)),
+        }
+    }
+
+    // A stream fed through a channel by the connection receiving the body. The
+    // stream ends when the sender is dropped.
+    pub(crate) fn channel(capacity: usize) -> (Sender<io::Result<Vec<u8>>>, Self) {
+        let (sender, receiver) = channel::bounded(capacity);
+        (sender, BodyStream::from_receiver(receiver))
+    }
+
+    // The same without a limit on the pieces waiting, for senders that bound
+    // the data some other way and can't wait for the reader
+    pub(crate) fn unbounded_channel() -> (Sender<io::Result<Vec<u8>>>, Self) {
+        let (sender, receiver) = channel::unbounded();
+        (sender, BodyStream::from_receiver(receiver))
+    }
+
+    fn from_receiver(receiver: Receiver<io::Result<Vec<u8>>>) -> Self {
+        BodyStream::new(ChannelReader {
+            receiver,
+            chunk: Vec::new(),
+            position: 0,
+        })
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.lock().unwrap().read(buf)
+    }
+}
+
+impl Read for BodyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        BodyStream::read(self, buf)
+    }
+}
+
+struct ChannelReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            // readers run on blocking threads, so waiting here is fine
+            match task::block_on(self.receiver.recv()) {
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                Ok(Err(error)) => return Err(error),
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let count = buf.len().min(self.chunk.len() - self.position);
+        buf[..count].copy_from_slice(&self.chunk[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+impl Debug for BodyStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BodyStream")
+    }
+}
+
+impl PartialEq for BodyStream {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.reader, &other.reader)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct HttpResponse {
     pub status: Status,
+    reason: Option<String>,
     headers: Vec<Header>,
     body: Option<Vec<u8>>,
+    stream: Option<BodyStream>,
+    trailers: Vec<Header>,
 }
 
 impl HttpResponse {
@@ -45,8 +547,11 @@ impl HttpResponse {
     pub fn builder() -> HttpResponseBuilder {
         HttpResponseBuilder {
             status: Some(Status::Ok),
+            reason: None,
             headers: Vec::new(),
             body: None,
+            stream: None,
+            trailers: Vec::new(),
         }
     }
 
@@ -59,8 +564,8 @@ impl HttpResponse {
         
         let mut response_headers = vec![
             Header::new(HEADER_SERVER, "Rust Server"),
-            Header::new(HEADER_DATE, &chrono::Utc::now().to_rfc2822()),
-            Header::new(HEADER_CONTENT_LENGTH, &content_length.to_string()),
+            Header::new(HEADER_DATE, chrono::Utc::now().to_rfc2822()),
+            Header::new(HEADER_CONTENT_LENGTH, content_length.to_string()),
             Header::new(HEADER_CONNECTION, CONNECTION_KEEP_ALIVE),
         ];
         
@@ -68,16 +573,186 @@ impl HttpResponse {
         
         HttpResponse {
             status,
+            reason: None,
             headers: response_headers,
             body,
+            stream: None,
+            trailers: Vec::new(),
         }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
+    // Without a Content-Length in the headers the body is sent chunked
+    pub fn streaming(status: Status, headers: Vec<Header>, stream: BodyStream) -> Self {
+
+        let mut response_headers = vec![
+            Header::new(HEADER_SERVER, "Rust Server"),
+            Header::new(HEADER_DATE, chrono::Utc::now().to_rfc2822()),
+            Header::new(HEADER_CONNECTION, CONNECTION_KEEP_ALIVE),
+        ];
+
+        let has_length = headers.iter().any(|header| header.key().eq_ignore_ascii_case(HEADER_CONTENT_LENGTH));
+        if !has_length {
+            response_headers.push(Header::new(HEADER_TRANSFER_ENCODING, "chunked"));
+        }
+
+        response_headers.extend(headers);
+
+        HttpResponse {
+            status,
+            reason: None,
+            headers: response_headers,
+            body: None,
+            stream: Some(stream),
+            trailers: Vec::new(),
+        }
+    }
+
+    // Parses a complete response as it was sent on the wire
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = bytes;
+        let head = ResponseHead::read(&mut reader)?;
+        HttpResponse::decode(head, reader, &Method::Get)
+    }
+
+    // Reads the body that follows `head` into memory. Headers are kept as they
+    // were received, except that a decoded chunked body gets a Content-Length.
+    pub fn decode<R>(head: ResponseHead, reader: R, method: &Method) -> io::Result<Self> where R: BufRead {
+        Self::decode_limited(head, reader, method, u64::MAX)
+            .map(|response| response.expect("a body without a limit always fits"))
+    }
+
+    // Like `decode`, but gives up with None once the body exceeds `limit` bytes
+    pub(crate) fn decode_limited<R>(head: ResponseHead, reader: R, method: &Method, limit: u64) -> io::Result<Option<Self>> where R: BufRead {
+        let framing = head.framing(method)?;
+        if matches!(framing, Framing::Length(length) if length > limit) {
+            return Ok(None);
+        }
+
+        let mut decoder = BodyReader::new(reader, framing);
+        let mut body = Vec::new();
+        (&mut decoder).take(limit.saturating_add(1)).read_to_end(&mut body)?;
+        if body.len() as u64 > limit {
+            return Ok(None);
+        }
+
+        let mut headers = head.headers;
+        if framing == Framing::Chunked {
+            headers.retain(|header| {
+                !header.key().eq_ignore_ascii_case(HEADER_TRANSFER_ENCODING) && !header.key().eq_ignore_ascii_case(HEADER_CONTENT_LENGTH)
+            });
+            headers.push(Header::new(HEADER_CONTENT_LENGTH, body.len().to_string()));
+        }
+
+        // only keep the phrase when it says something the status doesn't
+        let reason = match head.reason.as_str() {
+            reason if reason == head.status.reason_phrase() => None,
+            reason => Some(reason.to_string()),
+        };
+
+        Ok(Some(HttpResponse {
+            status: head.status,
+            reason,
+            headers,
+            body: if framing == Framing::Empty { None } else { Some(body) },
+            stream: None,
+            trailers: decoder.trailers().to_vec(),
+        }))
+    }
+
+    // The reason phrase sent in the status line, the standard one unless set
+    pub fn reason(&self) -> &str {
+        self.reason.as_deref().unwrap_or(self.status.reason_phrase())
+    }
+
+    // Control characters are dropped, they could end the status line early
+    pub fn set_reason<R>(&mut self, reason: R) where R: Into<String> {
+        let reason: String = reason.into().chars()
+            .filter(|c| *c == '\t' || !c.is_control())
+            .collect();
+        self.reason = Some(reason);
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|header| header.key().eq_ignore_ascii_case(key))
+            .map(|header| header.value())
+    }
+
+    pub fn headers(&self) -> &[Header] {
+        &self.headers
+    }
+
+    pub fn add_header<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.headers.push(Header::new(key, value));
+    }
+
+    // Replaces every header with the same key, ignoring case
+    pub fn set_header<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let header = Header::new(key, value);
+        self.headers.retain(|existing| !existing.key().eq_ignore_ascii_case(header.key()));
+        self.headers.push(header);
+    }
+
+    // Removes every header with the key, ignoring case
+    pub fn remove_header(&mut self, key: &str) {
+        self.headers.retain(|header| !header.key().eq_ignore_ascii_case(key));
+    }
+
+    // Adds a request header to Vary unless it is already listed
+    pub fn add_vary(&mut self, key: &str) {
+        let mut vary: Vec<String> = self.header(HEADER_VARY)
+            .map(|value| value.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect())
+            .unwrap_or_default();
+
+        if vary.iter().any(|item| item == "*" || item.eq_ignore_ascii_case(key)) {
+            return;
+        }
+
+        vary.push(key.to_string());
+        self.set_header(HEADER_VARY, vary.join(", "));
+    }
+
+    pub fn body(&self) -> Option<&Vec<u8>> {
+        self.body.as_ref()
+    }
+
+    pub fn stream(&self) -> Option<&BodyStream> {
+        self.stream.as_ref()
+    }
+
+    pub fn trailers(&self) -> &[Header] {
+        &self.trailers
+    }
+
+    // Trailers are only sent to clients that accept them, see
+    // `HttpRequest::accepts_trailers`, and otherwise dropped
+    pub fn add_trailer<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.trailers.push(Header::new(key, value));
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.header(HEADER_TRANSFER_ENCODING)
+            .is_some_and(|value| value.to_ascii_lowercase().ends_with("chunked"))
+    }
+
+    // Serializes the status line and headers including the blank line after them
+    pub fn head_to_bytes(&self) -> Vec<u8> {
         let mut buffer: Vec<u8> = Vec::new();
 
-        // write status line
-        buffer.extend(format!("{} {}\r\n", HTTP_VERSION_1_1, self.status.as_u16()).as_bytes().to_vec());
+        // write status line, the space before an empty reason phrase is still required
+        buffer.extend(format!("{} {} {}\r\n", HTTP_VERSION_1_1, self.status.as_u16(), self.reason()).as_bytes().to_vec());
 
         // write headers
         for header in &self.headers {
@@ -87,6 +762,13 @@ impl HttpResponse {
         // write a blank line to separate headers from body
         buffer.extend("\r\n".as_bytes().to_vec());
 
+        buffer
+    }
+
+    // A streamed body is not included, see `stream`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = self.head_to_bytes();
+
         // write body
         if let Some(body) = &self.body {
             buffer.extend(body.to_vec());
@@ -96,10 +778,14 @@ impl HttpResponse {
     }
 }
 
+#[derive(Default)]
 pub struct HttpResponseBuilder {
     status: Option<Status>,
+    reason: Option<String>,
     headers: Vec<Header>,
     body: Option<Vec<u8>>,
+    stream: Option<BodyStream>,
+    trailers: Vec<Header>,
 }
 
 impl HttpResponseBuilder {
@@ -107,8 +793,11 @@ impl HttpResponseBuilder {
     pub fn new() -> Self {
         HttpResponseBuilder {
             status: None,
+            reason: None,
             headers: Vec::new(),
             body: None,
+            stream: None,
+            trailers: Vec::new(),
         }
     }
 
@@ -117,19 +806,124 @@ impl HttpResponseBuilder {
         self
     }
 
+    pub fn reason<R>(&mut self, reason: R) -> &mut Self where R: Into<String> {
+        self.reason = Some(reason.into());
+        self
+    }
+
     pub fn header(&mut self, key: &str, value: &str) -> &mut Self {
         self.headers.push(Header::new(key, value));
         self
     }
 
+    // Each cookie is sent as its own Set-Cookie header
+    pub fn cookie(&mut self, cookie: Cookie) -> &mut Self {
+        self.headers.push(Header::new(HEADER_SET_COOKIE, cookie.to_string()));
+        self
+    }
+
     pub fn body(&mut self, body: Vec<u8>) -> &mut Self {
         self.body = Some(body);
         self
     }
 
+    pub fn stream<R>(&mut self, reader: R) -> &mut Self where R: Read + Send + 'static {
+        self.stream = Some(BodyStream::new(reader));
+        self
+    }
+
+    pub fn trailer(&mut self, key: &str, value: &str) -> &mut Self {
+        self.trailers.push(Header::new(key, value));
+        self
+    }
+
     pub fn build(&self) -> HttpResponse {
-        HttpResponse::new(self.status.clone().unwrap_or(Status::Ok), self.headers.clone(), self.body.clone())
+        let status = self.status.clone().unwrap_or(Status::Ok);
+
+        let mut response = match &self.stream {
+            Some(stream) => HttpResponse::streaming(status, self.headers.clone(), stream.clone()),
+            None => HttpResponse::new(status, self.headers.clone(), self.body.clone()),
+        };
+
+        if let Some(reason) = &self.reason {
+            response.set_reason(reason.clone());
+        }
+        response.trailers = self.trailers.clone();
+
+        response
     }
 
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same(parsed: &HttpRequest, request: &HttpRequest) {
+        assert_eq!(parsed.method(), request.method());
+        assert_eq!(parsed.path(), request.path());
+        assert_eq!(parsed.headers, request.headers);
+        assert_eq!(parsed.body, request.body);
+    }
+
+    #[test]
+    fn built_requests_round_trip() {
+        let request = HttpRequest::builder()
+            .method(Method::Post)
+            .path("/upload?name=a%20b")
+            .header("Host", "example.org")
+            .header("Content-Type", "text/plain")
+            .body(b"hello\r\nworld".to_vec())
+            .build();
+        let bytes = request.to_bytes();
+        assert_eq!(bytes, b"POST /upload?name=a%20b HTTP/1.1\r\nHost: example.org\r\nContent-Type: text/plain\r\nContent-Length: 12\r\n\r\nhello\r\nworld");
+        assert_same(&HttpRequest::from_bytes(&bytes).unwrap(), &request);
+
+        // no body, and a method the server doesn't know
+        let request = HttpRequest::builder()
+            .method(Method::Extension("PURGE".to_string()))
+            .header("Host", "example.org")
+            .cookie(Cookie::new("a", "1").unwrap())
+            .cookie(Cookie::new("b", "2").unwrap())
+            .build();
+        let parsed = HttpRequest::from_bytes(&request.to_bytes()).unwrap();
+        assert_same(&parsed, &request);
+        assert_eq!(parsed.header(HEADER_COOKIE), Some("a=1; b=2"));
+        assert_eq!(parsed.body, None);
+    }
+
+    #[test]
+    fn chunked_requests_keep_their_trailers() {
+        let bytes = b"PUT /file HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nChecksum: abc\r\n\r\n";
+        let request = HttpRequest::from_bytes(bytes).unwrap();
+        assert_eq!(request.body.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(request.trailers(), &[Header::new("Checksum", "abc")]);
+    }
+
+    #[test]
+    fn malformed_request_lines_are_rejected() {
+        for bytes in [
+            &b"GET /\r\n\r\n"[..],
+            b"GET / HTTP/1.1 extra\r\n\r\n",
+            b"GET /a b HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/2.0\r\n\r\n",
+            b"GET / FTP/1.1\r\n\r\n",
+            b"G:T / HTTP/1.1\r\n\r\n",
+            b"\r\n",
+        ] {
+            let error = HttpRequest::from_bytes(bytes).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?}", String::from_utf8_lossy(bytes));
+        }
+
+        assert_eq!(HttpRequest::from_bytes(b"").err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn incomplete_requests_are_rejected() {
+        // the head has to end with a blank line
+        assert!(HttpRequest::from_bytes(b"GET / HTTP/1.1\r\nHost: example.org\r\n").is_err());
+        // and the body has to be as long as it says
+        assert!(HttpRequest::from_bytes(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort").is_err());
+    }
+}
@@ -0,0 +1,429 @@
+use std::fmt::Display;
+use std::io;
+use std::io::Read;
+use crate::http::{CONTENT_TYPE_MULTIPART_FORM_DATA, Header, HEADER_CONTENT_DISPOSITION, HEADER_CONTENT_TYPE, Status};
+use crate::message::{HttpRequest, HttpResponse};
+
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+const MAX_BOUNDARY_LEN: usize = 70;
+
+#[derive(Debug)]
+pub enum MultipartError {
+    UnsupportedMediaType,
+    MissingBoundary,
+    Malformed,
+    TooManyParts,
+    HeadersTooLarge,
+    PartTooLarge,
+    BodyTooLarge,
+    Io(io::Error),
+}
+
+impl MultipartError {
+    pub fn status(&self) -> Status {
+        match self {
+            MultipartError::UnsupportedMediaType => Status::UnsupportedMediaType,
+            MultipartError::MissingBoundary | MultipartError::Malformed => Status::BadRequest,
+            MultipartError::HeadersTooLarge => Status::RequestHeaderFieldsTooLarge,
+            MultipartError::TooManyParts | MultipartError::PartTooLarge | MultipartError::BodyTooLarge => Status::PayloadTooLarge,
+            MultipartError::Io(_) => Status::BadRequest,
+        }
+    }
+}
+
+impl Display for MultipartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MultipartError::UnsupportedMediaType => write!(f, "expected content type {}", CONTENT_TYPE_MULTIPART_FORM_DATA),
+            MultipartError::MissingBoundary => write!(f, "missing or invalid multipart boundary"),
+            MultipartError::Malformed => write!(f, "malformed multipart body"),
+            MultipartError::TooManyParts => write!(f, "too many multipart parts"),
+            MultipartError::HeadersTooLarge => write!(f, "multipart part headers too large"),
+            MultipartError::PartTooLarge => write!(f, "multipart part too large"),
+            MultipartError::BodyTooLarge => write!(f, "multipart body too large"),
+            MultipartError::Io(error) => write!(f, "failed to read multipart body: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for MultipartError {}
+
+// Part bodies are read through io::Read, so limit violations travel as
+// io::Errors and are unwrapped again here.
+impl From<io::Error> for MultipartError {
+    fn from(error: io::Error) -> Self {
+        if error.get_ref().is_some_and(|inner| inner.is::<MultipartError>()) {
+            return *error.into_inner().unwrap().downcast::<MultipartError>().unwrap();
+        }
+        MultipartError::Io(error)
+    }
+}
+
+impl From<MultipartError> for io::Error {
+    fn from(error: MultipartError) -> Self {
+        match error {
+            MultipartError::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+impl From<MultipartError> for HttpResponse {
+    fn from(error: MultipartError) -> Self {
+        HttpResponse::new(error.status(), vec![], Some(error.to_string().into_bytes()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultipartLimits {
+    pub max_parts: usize,
+    pub max_header_size: usize,
+    pub max_part_size: usize,
+    pub max_total_size: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        MultipartLimits {
+            max_parts: 128,
+            max_header_size: 8 * 1024,
+            max_part_size: 16 * 1024 * 1024,
+            max_total_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Preamble,
+    Part,
+    Delimiter,
+    Done,
+}
+
+// Incremental multipart/form-data reader. Only a small window of the body is
+// held in memory at a time; part bodies are streamed through `Part`.
+pub struct Multipart<R: Read> {
+    reader: R,
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    eof: bool,
+    state: State,
+    limits: MultipartLimits,
+    parts: usize,
+    part_size: usize,
+    total_size: usize,
+}
+
+impl<'a> Multipart<Box<dyn Read + 'a>> {
+
+    // Reads the body from the connection as it arrives when the handler asked
+    // for it to be streamed (see `HttpHandler::stream_body`), otherwise from
+    // the body already read into memory
+    pub fn from_request(request: &'a HttpRequest, limits: MultipartLimits) -> Result<Self, MultipartError> {
+        let content_type = request.header(HEADER_CONTENT_TYPE).ok_or(MultipartError::UnsupportedMediaType)?;
+        let boundary = boundary(content_type)?;
+        let body: Box<dyn Read + 'a> = match request.body_stream() {
+            Some(stream) => Box::new(stream.clone()),
+            None => Box::new(request.body.as_deref().unwrap_or_default()),
+        };
+
+        Ok(Multipart::with_limits(body, &boundary, limits))
+    }
+}
+
+impl<R: Read> Multipart<R> {
+
+    pub fn new(reader: R, boundary: &str) -> Self {
+        Self::with_limits(reader, boundary, MultipartLimits::default())
+    }
+
+    pub fn with_limits(reader: R, boundary: &str, limits: MultipartLimits) -> Self {
+        Multipart {
+            reader,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // the first boundary may start the body without a preceding CRLF
+            buffer: b"\r\n".to_vec(),
+            eof: false,
+            state: State::Preamble,
+            limits,
+            parts: 0,
+            part_size: 0,
+            total_size: 0,
+        }
+    }
+
+    // Advances to the next part, skipping whatever is left of the current one
+    pub fn next_part(&mut self) -> Result<Option<Part<'_, R>>, MultipartError> {
+
+        if self.state == State::Preamble || self.state == State::Part {
+            let mut sink = [0u8; READ_CHUNK_SIZE];
+            while self.read_body(&mut sink)? > 0 {}
+        }
+
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        // a close delimiter ends the body, anything after it is epilogue
+        self.fill_to(2)?;
+        if self.buffer.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+
+        // skip transport padding up to the end of the boundary line
+        let line_end = self.fill_until(b"\r\n", self.limits.max_header_size)?;
+        if self.buffer[..line_end].iter().any(|byte| *byte != b' ' && *byte != b'\t') {
+            return Err(MultipartError::Malformed);
+        }
+        self.consume(line_end + 2);
+
+        let headers = self.read_headers()?;
+
+        self.parts += 1;
+        if self.parts > self.limits.max_parts {
+            return Err(MultipartError::TooManyParts);
+        }
+
+        self.state = State::Part;
+        self.part_size = 0;
+
+        Ok(Some(Part::new(self, headers)))
+    }
+
+    fn read_headers(&mut self) -> Result<Vec<Header>, MultipartError> {
+        self.fill_to(2)?;
+        if self.buffer.starts_with(b"\r\n") {
+            self.consume(2);
+            return Ok(Vec::new());
+        }
+
+        let end = self.fill_until(b"\r\n\r\n", self.limits.max_header_size)?;
+        let block = String::from_utf8(self.buffer[..end].to_vec()).map_err(|_| MultipartError::Malformed)?;
+        self.consume(end + 4);
+
+        Ok(block.split("\r\n").map(|line| Header::parse(line.to_string())).collect())
+    }
+
+    fn read_body(&mut self, out: &mut [u8]) -> Result<usize, MultipartError> {
+
+        if self.state != State::Part && self.state != State::Preamble {
+            return Ok(0);
+        }
+
+        loop {
+            if let Some(position) = find(&self.buffer, &self.delimiter) {
+                if position == 0 {
+                    self.consume(self.delimiter.len());
+                    self.state = State::Delimiter;
+                    return Ok(0);
+                }
+                return self.take_body(position, out);
+            }
+
+            // keep enough bytes back to recognise a delimiter split across reads
+            let safe = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                return self.take_body(safe, out);
+            }
+
+            if self.eof {
+                return Err(MultipartError::Malformed);
+            }
+            self.fill()?;
+        }
+    }
+
+    fn take_body(&mut self, available: usize, out: &mut [u8]) -> Result<usize, MultipartError> {
+        let count = available.min(out.len());
+
+        if self.state == State::Part {
+            self.part_size += count;
+            if self.part_size > self.limits.max_part_size {
+                return Err(MultipartError::PartTooLarge);
+            }
+        }
+
+        out[..count].copy_from_slice(&self.buffer[..count]);
+        self.consume(count);
+        Ok(count)
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.buffer.drain(..count);
+    }
+
+    fn fill(&mut self) -> Result<usize, MultipartError> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        let count = self.reader.read(&mut chunk)?;
+
+        if count == 0 {
+            self.eof = true;
+            return Ok(0);
+        }
+
+        self.total_size += count;
+        if self.total_size > self.limits.max_total_size {
+            return Err(MultipartError::BodyTooLarge);
+        }
+
+        self.buffer.extend_from_slice(&chunk[..count]);
+        Ok(count)
+    }
+
+    fn fill_to(&mut self, len: usize) -> Result<(), MultipartError> {
+        while self.buffer.len() < len {
+            if self.fill()? == 0 {
+                return Err(MultipartError::Malformed);
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_until(&mut self, needle: &[u8], limit: usize) -> Result<usize, MultipartError> {
+        loop {
+            if let Some(position) = find(&self.buffer, needle) {
+                return Ok(position);
+            }
+            if self.buffer.len() > limit {
+                return Err(MultipartError::HeadersTooLarge);
+            }
+            if self.fill()? == 0 {
+                return Err(MultipartError::Malformed);
+            }
+        }
+    }
+}
+
+pub struct Part<'a, R: Read> {
+    multipart: &'a mut Multipart<R>,
+    headers: Vec<Header>,
+    name: Option<String>,
+    filename: Option<String>,
+}
+
+impl<'a, R: Read> Part<'a, R> {
+
+    fn new(multipart: &'a mut Multipart<R>, headers: Vec<Header>) -> Self {
+        let disposition = headers.iter()
+            .find(|header| header.key().eq_ignore_ascii_case(HEADER_CONTENT_DISPOSITION))
+            .map(|header| parse_params(header.value()))
+            .unwrap_or_default();
+
+        let param = |key: &str| disposition.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.clone());
+
+        Part {
+            name: param("name"),
+            filename: param("filename"),
+            multipart,
+            headers,
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub fn headers(&self) -> &[Header] {
+        &self.headers
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|header| header.key().eq_ignore_ascii_case(key))
+            .map(|header| header.value())
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header(HEADER_CONTENT_TYPE)
+    }
+
+    // Reads the rest of the part into memory, bounded by the part size limit
+    pub fn data(&mut self) -> Result<Vec<u8>, MultipartError> {
+        let mut body = Vec::new();
+        self.read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    pub fn text(&mut self) -> Result<String, MultipartError> {
+        String::from_utf8(self.data()?).map_err(|_| MultipartError::Malformed)
+    }
+}
+
+impl<R: Read> Read for Part<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(self.multipart.read_body(buf)?)
+    }
+}
+
+// Extracts and validates the boundary parameter of a multipart/form-data Content-Type
+pub fn boundary(content_type: &str) -> Result<String, MultipartError> {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    if !media_type.eq_ignore_ascii_case(CONTENT_TYPE_MULTIPART_FORM_DATA) {
+        return Err(MultipartError::UnsupportedMediaType);
+    }
+
+    let boundary = parse_params(content_type).into_iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value)
+        .ok_or(MultipartError::MissingBoundary)?;
+
+    if boundary.is_empty() || boundary.len() > MAX_BOUNDARY_LEN || boundary.ends_with(' ') {
+        return Err(MultipartError::MissingBoundary);
+    }
+
+    Ok(boundary)
+}
+
+// Parses `; key=value` parameters following a header value, unquoting quoted strings
+fn parse_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+
+    // skip the leading media type or disposition type
+    for c in chars.by_ref() {
+        if c == ';' {
+            break;
+        }
+    }
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ';').is_some() {}
+
+        let key: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=' && *c != ';')).collect();
+        if key.is_empty() {
+            break;
+        }
+
+        let mut param_value = String::new();
+        if chars.next_if_eq(&'=').is_some() {
+            if chars.next_if_eq(&'"').is_some() {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => param_value.extend(chars.next()),
+                        c => param_value.push(c),
+                    }
+                }
+                while chars.next_if(|c| *c != ';').is_some() {}
+            } else {
+                param_value = std::iter::from_fn(|| chars.next_if(|c| *c != ';')).collect();
+            }
+        }
+
+        params.push((key.trim().to_string(), param_value.trim().to_string()));
+    }
+
+    params
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
@@ -0,0 +1,287 @@
+use std::fmt::Display;
+use crate::http::{HEADER_ACCEPT, HEADER_ACCEPT_CHARSET, HEADER_ACCEPT_LANGUAGE, Status};
+use crate::message::{HttpRequest, HttpResponse};
+
+// A single entry of an Accept-style header together with its q-value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preference {
+    pub value: String,
+    pub params: Vec<(String, String)>,
+    pub quality: f32,
+}
+
+// Parses a comma separated Accept-style header into preferences ordered from
+// most to least preferred. Entries with an invalid q-value are dropped.
+pub fn parse_preferences(header_value: &str) -> Vec<Preference> {
+    let mut preferences: Vec<Preference> = header_value.split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let value = parts.next()?.trim();
+            if value.is_empty() {
+                return None;
+            }
+
+            let mut quality = 1.0;
+            let mut params = Vec::new();
+
+            for param in parts {
+                let (key, param_value) = param.split_once('=').unwrap_or((param, ""));
+                let key = key.trim();
+                let param_value = param_value.trim().trim_matches('"');
+
+                if key.eq_ignore_ascii_case("q") {
+                    quality = parse_quality(param_value)?;
+                } else {
+                    params.push((key.to_ascii_lowercase(), param_value.to_string()));
+                }
+            }
+
+            Some(Preference {
+                value: value.to_string(),
+                params,
+                quality,
+            })
+        })
+        .collect();
+
+    // stable, so equally weighted entries keep the client's order
+    preferences.sort_by(|a, b| b.quality.total_cmp(&a.quality));
+    preferences
+}
+
+fn parse_quality(value: &str) -> Option<f32> {
+    let quality = value.parse::<f32>().ok()?;
+    if (0.0..=1.0).contains(&quality) && value.len() <= 5 {
+        Some(quality)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotAcceptable {
+    vary: Vec<&'static str>,
+}
+
+impl Display for NotAcceptable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no acceptable representation available")
+    }
+}
+
+impl std::error::Error for NotAcceptable {}
+
+impl From<NotAcceptable> for HttpResponse {
+    fn from(error: NotAcceptable) -> Self {
+        let mut response = HttpResponse::new(Status::NotAcceptable, vec![], Some(error.to_string().into_bytes()));
+        add_vary(&mut response, &error.vary);
+        response
+    }
+}
+
+// Picks representations for a request and remembers which request headers
+// were consulted. The server lists them in the Vary header of the response to
+// the request; `apply` does the same for responses built outside of it.
+pub struct Negotiation<'a> {
+    request: &'a HttpRequest,
+    vary: Vec<&'static str>,
+}
+
+impl<'a> Negotiation<'a> {
+
+    pub fn new(request: &'a HttpRequest) -> Self {
+        Negotiation {
+            request,
+            vary: Vec::new(),
+        }
+    }
+
+    pub fn media_type<'o>(&mut self, offered: &[&'o str]) -> Result<&'o str, NotAcceptable> {
+        self.negotiate(HEADER_ACCEPT, offered, media_type_specificity)
+    }
+
+    pub fn language<'o>(&mut self, offered: &[&'o str]) -> Result<&'o str, NotAcceptable> {
+        self.negotiate(HEADER_ACCEPT_LANGUAGE, offered, language_specificity)
+    }
+
+    pub fn charset<'o>(&mut self, offered: &[&'o str]) -> Result<&'o str, NotAcceptable> {
+        self.negotiate(HEADER_ACCEPT_CHARSET, offered, charset_specificity)
+    }
+
+    // Adds the consulted headers to the Vary header of the response
+    pub fn apply(&self, response: &mut HttpResponse) {
+        add_vary(response, &self.vary);
+    }
+
+    fn negotiate<'o>(&mut self, header: &'static str, offered: &[&'o str], specificity: fn(&str, &str) -> Option<usize>) -> Result<&'o str, NotAcceptable> {
+
+        if !self.vary.contains(&header) {
+            self.vary.push(header);
+        }
+        self.request.add_negotiated(header);
+
+        let values: Vec<&str> = self.request.headers.iter()
+            .filter(|h| h.key().eq_ignore_ascii_case(header))
+            .map(|h| h.value())
+            .collect();
+
+        // without the header any representation is acceptable
+        if values.is_empty() {
+            return offered.first().copied().ok_or_else(|| self.not_acceptable());
+        }
+
+        let preferences = parse_preferences(&values.join(","));
+
+        let mut best: Option<(&'o str, f32)> = None;
+
+        for candidate in offered {
+            // the most specific matching range decides the quality
+            let quality = preferences.iter()
+                .filter_map(|preference| specificity(&preference.value, candidate).map(|score| (score, preference.quality)))
+                .max_by_key(|(score, _)| *score)
+                .map(|(_, quality)| quality)
+                .unwrap_or(0.0);
+
+            if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((candidate, quality));
+            }
+        }
+
+        best.map(|(candidate, _)| candidate).ok_or_else(|| self.not_acceptable())
+    }
+
+    fn not_acceptable(&self) -> NotAcceptable {
+        NotAcceptable {
+            vary: self.vary.clone(),
+        }
+    }
+}
+
+fn media_type_specificity(range: &str, offered: &str) -> Option<usize> {
+    let (range_type, range_subtype) = range.split_once('/')?;
+    let offered = offered.split(';').next().unwrap_or_default().trim();
+    let (offered_type, offered_subtype) = offered.split_once('/')?;
+
+    match (range_type.trim(), range_subtype.trim()) {
+        ("*", "*") => Some(0),
+        (t, "*") if t.eq_ignore_ascii_case(offered_type) => Some(1),
+        (t, s) if t.eq_ignore_ascii_case(offered_type) && s.eq_ignore_ascii_case(offered_subtype) => Some(2),
+        _ => None,
+    }
+}
+
+// Basic filtering from RFC 4647: "en" matches "en" and "en-GB"
+fn language_specificity(range: &str, offered: &str) -> Option<usize> {
+    if range == "*" {
+        return Some(0);
+    }
+
+    let range = range.to_ascii_lowercase();
+    let offered = offered.to_ascii_lowercase();
+
+    if offered == range || offered.starts_with(&format!("{}-", range)) {
+        Some(range.len())
+    } else {
+        None
+    }
+}
+
+fn charset_specificity(range: &str, offered: &str) -> Option<usize> {
+    match range {
+        "*" => Some(0),
+        range if range.eq_ignore_ascii_case(offered) => Some(1),
+        _ => None,
+    }
+}
+
+fn add_vary(response: &mut HttpResponse, headers: &[&str]) {
+    for header in headers {
+        response.add_vary(header);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HEADER_VARY;
+
+    fn accepting(accept: &str) -> HttpRequest {
+        HttpRequest::builder().header(HEADER_ACCEPT, accept).build()
+    }
+
+    fn qualities(header_value: &str) -> Vec<(String, f32)> {
+        parse_preferences(header_value).into_iter().map(|preference| (preference.value, preference.quality)).collect()
+    }
+
+    #[test]
+    fn parses_and_orders_q_values() {
+        assert_eq!(qualities("text/html;q=0.5, application/json, text/plain; Q=0.8, */*;q=0"), vec![
+            ("application/json".to_string(), 1.0),
+            ("text/plain".to_string(), 0.8),
+            ("text/html".to_string(), 0.5),
+            ("*/*".to_string(), 0.0),
+        ]);
+
+        // equal weights keep the client's order
+        assert_eq!(qualities("b;q=0.5, a;q=0.5, c"), vec![("c".to_string(), 1.0), ("b".to_string(), 0.5), ("a".to_string(), 0.5)]);
+    }
+
+    #[test]
+    fn drops_entries_with_invalid_q_values() {
+        assert_eq!(qualities("a;q=1.5, b;q=-0.1, c;q=high, d;q=0.0001, e;q=, f;q=0.001"), vec![("f".to_string(), 0.001)]);
+        assert!(parse_preferences(" , ;q=1").is_empty());
+    }
+
+    #[test]
+    fn keeps_other_parameters() {
+        let preferences = parse_preferences("text/plain; Format=\"flowed\"; q=0.7");
+        assert_eq!(preferences[0].params, vec![("format".to_string(), "flowed".to_string())]);
+        assert_eq!(preferences[0].quality, 0.7);
+    }
+
+    #[test]
+    fn the_most_specific_range_decides() {
+        let request = accepting("*/*;q=0.1, text/*;q=0.5, text/html");
+        let mut negotiation = Negotiation::new(&request);
+        assert_eq!(negotiation.media_type(&["image/png", "text/plain", "text/html"]), Ok("text/html"));
+        assert_eq!(negotiation.media_type(&["image/png", "text/plain"]), Ok("text/plain"));
+        assert_eq!(negotiation.media_type(&["image/png"]), Ok("image/png"));
+
+        // a specific q=0 excludes what a wider range would allow
+        let request = accepting("text/*, text/html;q=0");
+        let mut negotiation = Negotiation::new(&request);
+        assert_eq!(negotiation.media_type(&["text/html", "text/plain"]), Ok("text/plain"));
+        assert!(negotiation.media_type(&["text/html"]).is_err());
+    }
+
+    #[test]
+    fn matches_languages_by_prefix_and_charsets_exactly() {
+        let request = HttpRequest::builder()
+            .header(HEADER_ACCEPT_LANGUAGE, "en;q=0.5, en-GB, *;q=0.1")
+            .header(HEADER_ACCEPT_CHARSET, "UTF-8, *;q=0")
+            .build();
+        let mut negotiation = Negotiation::new(&request);
+
+        assert_eq!(negotiation.language(&["en-US", "en-GB"]), Ok("en-GB"));
+        assert_eq!(negotiation.language(&["de", "en-US"]), Ok("en-US"));
+        assert_eq!(negotiation.language(&["de"]), Ok("de"));
+        assert_eq!(negotiation.charset(&["iso-8859-1", "utf-8"]), Ok("utf-8"));
+        assert!(negotiation.charset(&["iso-8859-1"]).is_err());
+    }
+
+    #[test]
+    fn nothing_acceptable_is_a_406_with_vary() {
+        let request = accepting("application/json");
+        let mut negotiation = Negotiation::new(&request);
+
+        let error = negotiation.media_type(&["text/html", "text/plain"]).unwrap_err();
+        let response = HttpResponse::from(error);
+        assert_eq!(response.status, Status::NotAcceptable);
+        assert_eq!(response.header(HEADER_VARY), Some(HEADER_ACCEPT));
+
+        // nothing offered can't be acceptable either, even without the header
+        let request = HttpRequest::builder().build();
+        assert!(Negotiation::new(&request).media_type(&[]).is_err());
+        assert_eq!(Negotiation::new(&request).media_type(&["text/html"]), Ok("text/html"));
+    }
+}
@@ -0,0 +1,317 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str;
+use crate::http::{Header, is_tchar};
+
+// A header field borrowed from the buffer it was parsed from. Values are kept
+// as bytes since obs-text (0x80-0xff) is allowed in them, and a value continued
+// with obsolete line folding still contains the line breaks.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RawHeader<'b> {
+    pub name: &'b str,
+    pub value: &'b [u8],
+}
+
+impl From<&RawHeader<'_>> for Header {
+    fn from(header: &RawHeader) -> Self {
+
+        // obs-text is read as latin-1 unless the value is valid utf-8
+        let value = match str::from_utf8(header.value) {
+            Ok(value) => value.to_string(),
+            Err(_) => header.value.iter().map(|byte| *byte as char).collect(),
+        };
+
+        // folded lines are joined with a single space
+        let value = if value.contains('\n') {
+            value.lines().map(str::trim).collect::<Vec<_>>().join(" ")
+        } else {
+            value
+        };
+
+        Header {
+            key: header.name.to_string(),
+            value,
+        }
+    }
+}
+
+// The request line and headers of a request, borrowed from the buffer
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestHead<'b, 'h> {
+    pub method: &'b str,
+    pub target: &'b str,
+    pub version: &'b str,
+    pub headers: &'h [RawHeader<'b>],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Parsed<T> {
+    // the value and the number of bytes it took up
+    Complete(T, usize),
+    // the buffer ends before the value does
+    Partial,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    Method,
+    Target,
+    Version,
+    HeaderName,
+    HeaderValue,
+    NewLine,
+    ObsFold,
+    TooManyHeaders,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            ParseError::Method => "invalid method",
+            ParseError::Target => "invalid request target",
+            ParseError::Version => "invalid http version",
+            ParseError::HeaderName => "invalid header name",
+            ParseError::HeaderValue => "invalid header value",
+            ParseError::NewLine => "invalid line ending",
+            ParseError::ObsFold => "obsolete line folding",
+            ParseError::TooManyHeaders => "too many headers",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl Error for ParseError {}
+
+// Parses a request head from the start of `buffer` without copying, storing
+// the headers in `headers`. Returns Partial until the blank line ending the
+// head has arrived; parse again from the start once more bytes are appended.
+//
+// In strict mode the head must follow RFC 9112 to the letter: lines end in
+// CRLF, field names are tokens directly followed by the colon and obsolete line
+// folding is rejected. Intermediaries that are lenient about these in different
+// ways can be made to disagree on where a request ends.
+pub fn parse_request<'b, 'h>(buffer: &'b [u8], headers: &'h mut [RawHeader<'b>], strict: bool) -> Result<Parsed<RequestHead<'b, 'h>>, ParseError> {
+
+    // empty lines ahead of the request line are ignored
+    let start = scan(buffer, 0, |byte| byte == b'\r' || byte == b'\n');
+
+    let method_end = scan(buffer, start, is_tchar);
+    match buffer.get(method_end) {
+        None => return Ok(Parsed::Partial),
+        Some(b' ') if method_end > start => {}
+        Some(_) => return Err(ParseError::Method),
+    }
+
+    let target_start = method_end + 1;
+    let target_end = scan(buffer, target_start, |byte| byte.is_ascii_graphic());
+    match buffer.get(target_end) {
+        None => return Ok(Parsed::Partial),
+        Some(b' ') if target_end > target_start => {}
+        Some(_) => return Err(ParseError::Target),
+    }
+
+    let version_start = target_end + 1;
+    let version_end = scan(buffer, version_start, |byte| byte.is_ascii_graphic());
+    let Some(mut position) = line_end(buffer, version_end, strict)? else {
+        return Ok(Parsed::Partial);
+    };
+
+    let version = &buffer[version_start..version_end];
+    if !matches!(version, [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit()) {
+        return Err(ParseError::Version);
+    }
+
+    let mut count = 0;
+    let mut value_start = 0;
+
+    loop {
+        match buffer.get(position) {
+            None => return Ok(Parsed::Partial),
+
+            // a blank line ends the head
+            Some(b'\r' | b'\n') => {
+                let Some(end) = line_end(buffer, position, strict)? else {
+                    return Ok(Parsed::Partial);
+                };
+                position = end;
+                break;
+            }
+
+            // obsolete line folding continues the previous value
+            Some(b' ' | b'\t') => {
+                if strict {
+                    return Err(ParseError::ObsFold);
+                }
+                if count == 0 {
+                    return Err(ParseError::HeaderName);
+                }
+
+                let end = scan(buffer, position, is_value_byte);
+                let Some(next) = value_end(buffer, end, strict)? else {
+                    return Ok(Parsed::Partial);
+                };
+
+                headers[count - 1].value = trim_end(&buffer[value_start..end]);
+                position = next;
+            }
+
+            Some(_) => {
+                // strict names are tokens, without whitespace before the colon
+                let name_end = if strict {
+                    scan(buffer, position, is_tchar)
+                } else {
+                    scan(buffer, position, |byte| !matches!(byte, b':' | b'\r' | b'\n'))
+                };
+                match buffer.get(name_end) {
+                    None => return Ok(Parsed::Partial),
+                    Some(b':') => {}
+                    Some(_) => return Err(ParseError::HeaderName),
+                }
+
+                let name = str::from_utf8(trim_end(&buffer[position..name_end])).map_err(|_| ParseError::HeaderName)?;
+                if name.is_empty() {
+                    return Err(ParseError::HeaderName);
+                }
+
+                let start = scan(buffer, name_end + 1, |byte| byte == b' ' || byte == b'\t');
+                let end = scan(buffer, start, is_value_byte);
+                let Some(next) = value_end(buffer, end, strict)? else {
+                    return Ok(Parsed::Partial);
+                };
+
+                if count == headers.len() {
+                    return Err(ParseError::TooManyHeaders);
+                }
+
+                headers[count] = RawHeader { name, value: trim_end(&buffer[start..end]) };
+                count += 1;
+                value_start = start;
+                position = next;
+            }
+        }
+    }
+
+    // all three were checked to be ascii above
+    let ascii = |bytes| str::from_utf8(bytes).map_err(|_| ParseError::Target);
+
+    let head = RequestHead {
+        method: ascii(&buffer[start..method_end])?,
+        target: ascii(&buffer[target_start..target_end])?,
+        version: ascii(version)?,
+        headers: &headers[..count],
+    };
+
+    Ok(Parsed::Complete(head, position))
+}
+
+// Visible characters, spaces, tabs and obs-text
+fn is_value_byte(byte: u8) -> bool {
+    byte == b'\t' || byte >= 0x20 && byte != 0x7f
+}
+
+// The position of the first byte from `start` on that isn't accepted
+fn scan<F>(buffer: &[u8], start: usize, accept: F) -> usize where F: Fn(u8) -> bool {
+    buffer.iter()
+        .skip(start)
+        .position(|byte| !accept(*byte))
+        .map_or(buffer.len().max(start), |offset| start + offset)
+}
+
+// Expects CRLF, or outside strict mode a bare LF, at `position`, returning
+// where the next line starts
+fn line_end(buffer: &[u8], position: usize, strict: bool) -> Result<Option<usize>, ParseError> {
+    match (buffer.get(position), buffer.get(position + 1)) {
+        (None, _) | (Some(b'\r'), None) => Ok(None),
+        (Some(b'\r'), Some(b'\n')) => Ok(Some(position + 2)),
+        (Some(b'\n'), _) if !strict => Ok(Some(position + 1)),
+        _ => Err(ParseError::NewLine),
+    }
+}
+
+// A value stops at the line end or at a control character it may not contain
+fn value_end(buffer: &[u8], position: usize, strict: bool) -> Result<Option<usize>, ParseError> {
+    match buffer.get(position) {
+        Some(b'\r' | b'\n') | None => line_end(buffer, position, strict),
+        Some(_) => Err(ParseError::HeaderValue),
+    }
+}
+
+fn trim_end(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().rposition(|byte| *byte != b' ' && *byte != b'\t').map_or(0, |last| last + 1);
+    &bytes[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete<T>(parsed: Result<Parsed<T>, ParseError>) -> (T, usize) {
+        match parsed {
+            Ok(Parsed::Complete(value, length)) => (value, length),
+            parsed => panic!("{:?}", parsed.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn parses_a_head_and_reports_its_length() {
+        let buffer = b"\r\nGET /index.html?q=1 HTTP/1.1\r\nHost: example.com\r\nAccept:  text/html \r\n\r\nbody";
+        let mut headers = [RawHeader::default(); 4];
+        let (head, length) = complete(parse_request(buffer, &mut headers, true));
+
+        assert_eq!((head.method, head.target, head.version), ("GET", "/index.html?q=1", "HTTP/1.1"));
+        assert_eq!(head.headers, &[
+            RawHeader { name: "Host", value: b"example.com" },
+            RawHeader { name: "Accept", value: b"text/html" },
+        ]);
+        assert_eq!(&buffer[length..], b"body");
+    }
+
+    #[test]
+    fn every_prefix_of_a_head_is_partial() {
+        let buffer = b"POST / HTTP/1.1\r\nContent-Length: 0\r\n\r\n";
+        for end in 0..buffer.len() {
+            let mut headers = [RawHeader::default(); 4];
+            assert_eq!(parse_request(&buffer[..end], &mut headers, true), Ok(Parsed::Partial), "{}", end);
+        }
+    }
+
+    #[test]
+    fn strict_mode_rejects_what_rfc_9112_doesnt_allow() {
+        let cases: [(&[u8], ParseError); 8] = [
+            (b"GET / HTTP/1.1\nHost: a\r\n\r\n", ParseError::NewLine),
+            (b"GET / HTTP/1.1\r\nHost : a\r\n\r\n", ParseError::HeaderName),
+            (b"GET / HTTP/1.1\r\nX-A: a\r\n b\r\n\r\n", ParseError::ObsFold),
+            (b"GET / HTTP/1.1\r\nX-A: a\0b\r\n\r\n", ParseError::HeaderValue),
+            (b"GET / HTTP/1.1\r\nX-A: a\rb\r\n\r\n", ParseError::NewLine),
+            (b"GET / HTTP/1.10\r\n\r\n", ParseError::Version),
+            (b"G(T / HTTP/1.1\r\n\r\n", ParseError::Method),
+            (b"GET /a b HTTP/1.1\r\n\r\n", ParseError::NewLine),
+        ];
+
+        for (buffer, error) in cases {
+            let mut headers = [RawHeader::default(); 4];
+            assert_eq!(parse_request(buffer, &mut headers, true), Err(error), "{:?}", String::from_utf8_lossy(buffer));
+        }
+    }
+
+    #[test]
+    fn lenient_mode_accepts_bare_lf_and_folding() {
+        let mut headers = [RawHeader::default(); 4];
+        let (head, _) = complete(parse_request(b"GET / HTTP/1.1\nHost : a\nX-A: one\n  two\n\n", &mut headers, false));
+        assert_eq!(head.headers[0], RawHeader { name: "Host", value: b"a" });
+        assert_eq!(Header::from(&head.headers[1]), Header::new("X-A", "one two"));
+    }
+
+    #[test]
+    fn headers_beyond_the_slots_are_too_many() {
+        let buffer = b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\nE: 5\r\n\r\n";
+        let mut headers = [RawHeader::default(); 4];
+        assert_eq!(parse_request(buffer, &mut headers, true), Err(ParseError::TooManyHeaders));
+    }
+
+    #[test]
+    fn obs_text_is_read_as_latin_1() {
+        let header = RawHeader { name: "X-A", value: b"caf\xe9" };
+        assert_eq!(Header::from(&header).value(), "caf\u{e9}");
+    }
+}
@@ -0,0 +1,383 @@
+use std::io;
+use std::io::{BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use log::{debug, warn};
+use crate::decoder::{BodyReader, Framing, ResponseHead};
+use crate::forwarded::TrustedProxies;
+use crate::http::{CONNECTION_CLOSE, Header, HEADER_CONNECTION, HEADER_CONTENT_LENGTH, HEADER_DATE, HEADER_HOST, HEADER_PROXY_AUTHENTICATE, HEADER_PROXY_AUTHORIZATION, HEADER_SERVER, HEADER_TE, HEADER_TRAILER, HEADER_TRANSFER_ENCODING, HEADER_UPGRADE, HEADER_VIA, HEADER_X_FORWARDED_FOR, HEADER_X_FORWARDED_HOST, HEADER_X_FORWARDED_PORT, HEADER_X_FORWARDED_PROTO, HEADER_X_FORWARDED_SERVER, Method, Status};
+use crate::message::{BodyStream, HttpRequest, HttpResponse};
+use crate::server::HttpHandler;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_VIA: &str = "libhttp";
+const BODY_CHUNK_SIZE: usize = 16 * 1024;
+
+// Headers that only apply to a single connection and must not be forwarded
+// Forwarding headers that are only passed on from trusted proxies
+const FORWARDING_HEADERS: [&str; 4] = [
+    HEADER_X_FORWARDED_FOR,
+    HEADER_X_FORWARDED_PROTO,
+    HEADER_X_FORWARDED_HOST,
+    HEADER_X_FORWARDED_PORT,
+];
+
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    HEADER_CONNECTION,
+    "Keep-Alive",
+    "Proxy-Connection",
+    HEADER_TE,
+    HEADER_TRAILER,
+    HEADER_TRANSFER_ENCODING,
+    HEADER_UPGRADE,
+    HEADER_PROXY_AUTHORIZATION,
+    HEADER_PROXY_AUTHENTICATE,
+];
+
+enum ProxyError {
+    Connect(io::Error),
+    // the client's body could not be read
+    RequestBody(io::Error),
+    Timeout,
+    InvalidResponse,
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ProxyError::Timeout,
+            _ => ProxyError::InvalidResponse,
+        }
+    }
+}
+
+// Forwards every request to a single upstream HTTP/1.1 server and relays the
+// response back. Bodies are streamed in both directions as they arrive, and
+// requests are forwarded concurrently, each on its own upstream connection.
+#[derive(Clone)]
+pub struct ReverseProxy {
+    upstream: String,
+    connect_timeout: Duration,
+    timeout: Duration,
+    preserve_host: bool,
+    via: String,
+    trusted_proxies: TrustedProxies,
+}
+
+impl ReverseProxy {
+
+    pub fn new<U>(upstream: U) -> Self where U: Into<String> {
+        Self::builder(upstream).build()
+    }
+
+    pub fn builder<U>(upstream: U) -> ReverseProxyBuilder where U: Into<String> {
+        ReverseProxyBuilder {
+            proxy: ReverseProxy {
+                upstream: upstream.into(),
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                timeout: DEFAULT_TIMEOUT,
+                preserve_host: false,
+                via: DEFAULT_VIA.to_string(),
+                trusted_proxies: TrustedProxies::default(),
+            },
+        }
+    }
+
+    fn forward(&self, request: &HttpRequest) -> Result<HttpResponse, ProxyError> {
+
+        let stream = self.connect()?;
+        stream.set_read_timeout(Some(self.timeout)).map_err(ProxyError::Connect)?;
+        stream.set_write_timeout(Some(self.timeout)).map_err(ProxyError::Connect)?;
+
+        let mut writer = stream.try_clone().map_err(ProxyError::Connect)?;
+        let upstream_request = self.upstream_request(request);
+        writer.write_all(&upstream_request.to_bytes())?;
+        if let Some(body) = request.body_stream() {
+            let chunked = upstream_request.header(HEADER_TRANSFER_ENCODING).is_some();
+            send_body(&mut writer, body, chunked)?;
+        }
+        writer.flush()?;
+
+        let mut reader = BufReader::new(stream);
+
+        // skip interim responses, they can't be relayed through a handler
+        let head = loop {
+            let head = ResponseHead::read(&mut reader)?;
+            if !head.is_interim() {
+                break head;
+            }
+        };
+
+        let framing = head.framing(request.method())?;
+        let content_length = head.header(HEADER_CONTENT_LENGTH).map(str::to_string);
+        let status = head.status;
+        let reason = head.reason;
+
+        let mut response_headers: Vec<Header> = strip_hop_by_hop(head.headers).into_iter()
+            .filter(|header| !header.key().eq_ignore_ascii_case(HEADER_CONTENT_LENGTH))
+            .collect();
+        response_headers.push(Header::new(HEADER_VIA, self.via_value(response_headers.iter())));
+
+        if framing == Framing::Empty {
+            let mut response = relay(HttpResponse::new(status, vec![], None), &reason, response_headers);
+            if let Some(content_length) = content_length {
+                response.set_header(HEADER_CONTENT_LENGTH, content_length);
+            }
+            return Ok(response);
+        }
+
+        // only a known length is passed on as is, everything else is re-chunked
+        let length_header = match framing {
+            Framing::Length(length) => vec![Header::new(HEADER_CONTENT_LENGTH, length.to_string())],
+            _ => vec![],
+        };
+        let body = BodyStream::new(BodyReader::new(reader, framing));
+
+        Ok(relay(HttpResponse::streaming(status, length_header, body), &reason, response_headers))
+    }
+
+    fn connect(&self) -> Result<TcpStream, ProxyError> {
+        let addresses = self.upstream.to_socket_addrs().map_err(ProxyError::Connect)?;
+
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "upstream did not resolve");
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, self.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(error) if error.kind() == io::ErrorKind::TimedOut => return Err(ProxyError::Timeout),
+                Err(error) => last_error = error,
+            }
+        }
+
+        Err(ProxyError::Connect(last_error))
+    }
+
+    fn upstream_request(&self, request: &HttpRequest) -> HttpRequest {
+        let original_host = request.header(HEADER_HOST).map(str::to_string);
+        let mut headers = strip_hop_by_hop(request.headers.clone());
+
+        // anyone can send forwarding headers, so only a trusted proxy's are kept
+        let peer_trusted = request.source_addr().is_some_and(|address| self.trusted_proxies.is_trusted(&address.ip()));
+        if !peer_trusted {
+            headers.retain(|header| !FORWARDING_HEADERS.iter().any(|key| header.key().eq_ignore_ascii_case(key)));
+        }
+
+        let client_ip = request.source_addr().map(|address| address.ip().to_string());
+        if let Some(client_ip) = client_ip {
+            let forwarded_for = match header_value(&headers, HEADER_X_FORWARDED_FOR) {
+                Some(existing) => format!("{}, {}", existing, client_ip),
+                None => client_ip,
+            };
+            set_header(&mut headers, HEADER_X_FORWARDED_FOR, forwarded_for);
+        }
+
+        if header_value(&headers, HEADER_X_FORWARDED_PROTO).is_none() {
+            headers.push(Header::new(HEADER_X_FORWARDED_PROTO, request.scheme()));
+        }
+
+        if let Some(host) = &original_host {
+            if header_value(&headers, HEADER_X_FORWARDED_HOST).is_none() {
+                headers.push(Header::new(HEADER_X_FORWARDED_HOST, host.clone()));
+            }
+        }
+
+        if let Some(local_addr) = request.destination_addr() {
+            if header_value(&headers, HEADER_X_FORWARDED_PORT).is_none() {
+                headers.push(Header::new(HEADER_X_FORWARDED_PORT, local_addr.port().to_string()));
+            }
+        }
+
+        set_header(&mut headers, HEADER_X_FORWARDED_SERVER, self.via.clone());
+
+        let via = self.via_value(headers.iter());
+        set_header(&mut headers, HEADER_VIA, via);
+
+        let host = match (&original_host, self.preserve_host) {
+            (Some(host), true) => host.clone(),
+            _ => self.upstream.clone(),
+        };
+        set_header(&mut headers, HEADER_HOST, host);
+
+        // one connection per request keeps the framing of the response simple
+        headers.push(Header::new(HEADER_CONNECTION, CONNECTION_CLOSE));
+
+        // the body is framed anew, a streamed body keeps the client's length if it had one
+        headers.retain(|header| !header.key().eq_ignore_ascii_case(HEADER_CONTENT_LENGTH));
+        let length = match (&request.body, request.body_stream()) {
+            (Some(body), _) => Some(body.len().to_string()),
+            (None, Some(_)) if request.header(HEADER_TRANSFER_ENCODING).is_none() => request.header(HEADER_CONTENT_LENGTH).map(str::to_string),
+            (None, Some(_)) => {
+                headers.push(Header::new(HEADER_TRANSFER_ENCODING, "chunked"));
+                None
+            }
+            (None, None) => None,
+        };
+        if let Some(length) = length {
+            headers.push(Header::new(HEADER_CONTENT_LENGTH, length));
+        }
+
+        let mut builder = HttpRequest::builder();
+        builder.method(request.method().clone()).path(request.path());
+        for header in &headers {
+            builder.header(header.key(), header.value());
+        }
+        let mut upstream_request = builder.build();
+        upstream_request.body = request.body.clone();
+        upstream_request
+    }
+
+    fn via_value<'a>(&self, mut headers: impl Iterator<Item = &'a Header>) -> String {
+        let entry = format!("1.1 {}", self.via);
+        match headers.find(|header| header.key().eq_ignore_ascii_case(HEADER_VIA)) {
+            Some(existing) => format!("{}, {}", existing.value(), entry),
+            None => entry,
+        }
+    }
+}
+
+impl HttpHandler for ReverseProxy {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        debug!("Proxying {} to {}", request, self.upstream);
+
+        match self.forward(request) {
+            Ok(response) => response,
+            Err(ProxyError::Timeout) => {
+                warn!("Upstream {} timed out", self.upstream);
+                HttpResponse::new(Status::GatewayTimeout, vec![], None)
+            }
+            Err(ProxyError::Connect(error)) => {
+                warn!("Failed to connect to upstream {}: {}", self.upstream, error);
+                HttpResponse::new(Status::BadGateway, vec![], None)
+            }
+            Err(ProxyError::RequestBody(error)) => {
+                debug!("Failed to read request body for upstream {}: {}", self.upstream, error);
+                HttpResponse::new(Status::BadRequest, vec![], None)
+            }
+            Err(ProxyError::InvalidResponse) => {
+                warn!("Invalid response from upstream {}", self.upstream);
+                HttpResponse::new(Status::BadGateway, vec![], None)
+            }
+        }
+    }
+
+    // the upstream decides which methods it implements
+    fn supports_method(&self, _method: &Method) -> bool {
+        true
+    }
+
+    // bodies are relayed as they arrive rather than held in memory
+    fn stream_body(&self, _request: &HttpRequest) -> bool {
+        true
+    }
+
+    // every request gets its own upstream connection, so nothing is shared
+    fn fork(&self) -> Option<Box<dyn HttpHandler>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+pub struct ReverseProxyBuilder {
+    proxy: ReverseProxy,
+}
+
+impl ReverseProxyBuilder {
+
+    pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.proxy.connect_timeout = timeout;
+        self
+    }
+
+    // Applies to every read and write on the upstream connection
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.proxy.timeout = timeout;
+        self
+    }
+
+    // Sends the client's Host header upstream instead of the upstream address
+    pub fn preserve_host(&mut self, preserve_host: bool) -> &mut Self {
+        self.proxy.preserve_host = preserve_host;
+        self
+    }
+
+    pub fn via<V>(&mut self, via: V) -> &mut Self where V: Into<String> {
+        self.proxy.via = via.into();
+        self
+    }
+
+    // Peers whose X-Forwarded-* headers are passed on, everyone else's are replaced
+    pub fn trusted_proxies(&mut self, trusted_proxies: TrustedProxies) -> &mut Self {
+        self.proxy.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    pub fn build(&self) -> ReverseProxy {
+        self.proxy.clone()
+    }
+}
+
+// Copies a streamed request body upstream, chunked when its length is unknown
+fn send_body<W>(writer: &mut W, body: &BodyStream, chunked: bool) -> Result<(), ProxyError> where W: Write {
+    let mut buffer = vec![0u8; BODY_CHUNK_SIZE];
+
+    loop {
+        let count = body.read(&mut buffer).map_err(ProxyError::RequestBody)?;
+        if count == 0 {
+            break;
+        }
+
+        if chunked {
+            writer.write_all(format!("{:x}\r\n", count).as_bytes())?;
+            writer.write_all(&buffer[..count])?;
+            writer.write_all(b"\r\n")?;
+        } else {
+            writer.write_all(&buffer[..count])?;
+        }
+    }
+
+    if chunked {
+        writer.write_all(b"0\r\n\r\n")?;
+    }
+    Ok(())
+}
+
+fn strip_hop_by_hop(headers: Vec<Header>) -> Vec<Header> {
+    // headers listed in Connection are hop-by-hop as well
+    let listed: Vec<String> = headers.iter()
+        .filter(|header| header.key().eq_ignore_ascii_case(HEADER_CONNECTION))
+        .flat_map(|header| header.value().split(',').map(|name| name.trim().to_ascii_lowercase()).collect::<Vec<_>>())
+        .collect();
+
+    headers.into_iter()
+        .filter(|header| {
+            let key = header.key().to_ascii_lowercase();
+            !HOP_BY_HOP_HEADERS.iter().any(|hop| hop.eq_ignore_ascii_case(&key)) && !listed.contains(&key)
+        })
+        .collect()
+}
+
+fn relay(mut response: HttpResponse, reason: &str, headers: Vec<Header>) -> HttpResponse {
+    if reason != response.reason() {
+        response.set_reason(reason);
+    }
+
+    for header in headers {
+        // keep the upstream's Date and Server rather than sending them twice
+        if header.key().eq_ignore_ascii_case(HEADER_DATE) || header.key().eq_ignore_ascii_case(HEADER_SERVER) {
+            response.set_header(header.key, header.value);
+        } else {
+            response.add_header(header.key, header.value);
+        }
+    }
+    response
+}
+
+fn header_value<'a>(headers: &'a [Header], key: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|header| header.key().eq_ignore_ascii_case(key))
+        .map(|header| header.value())
+}
+
+fn set_header(headers: &mut Vec<Header>, key: &str, value: String) {
+    headers.retain(|header| !header.key().eq_ignore_ascii_case(key));
+    headers.push(Header::new(key, value));
+}
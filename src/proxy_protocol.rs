@@ -0,0 +1,305 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use async_std::io::BufRead;
+use async_std::prelude::*;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+const V2_COMMAND_LOCAL: u8 = 0x0;
+const V2_COMMAND_PROXY: u8 = 0x1;
+const V2_FAMILY_INET: u8 = 0x1;
+const V2_FAMILY_INET6: u8 = 0x2;
+const V2_TRANSPORT_STREAM: u8 = 0x1;
+
+// The connection details sent by a load balancer using the HAProxy PROXY
+// protocol. Addresses are absent for health checks (v2 LOCAL, v1 UNKNOWN)
+// and for anything other than TCP over IPv4/IPv6.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProxyHeader {
+    pub version: u8,
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+
+    // Parses a v1 line such as "PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n"
+    pub fn parse_v1(line: &str) -> io::Result<Self> {
+        let line = line.strip_suffix("\r\n").ok_or_else(|| invalid("PROXY v1 header must end with CRLF"))?;
+        let mut parts = line.split(' ');
+
+        if parts.next() != Some("PROXY") {
+            return Err(invalid("missing PROXY v1 prefix"));
+        }
+
+        let unknown = ProxyHeader { version: 1, source: None, destination: None };
+
+        let ipv6 = match parts.next() {
+            Some("TCP4") => false,
+            Some("TCP6") => true,
+            Some("UNKNOWN") => return Ok(unknown),
+            _ => return Err(invalid("unsupported PROXY v1 protocol")),
+        };
+
+        let fields: Vec<&str> = parts.collect();
+        let [source_ip, destination_ip, source_port, destination_port] = fields[..] else {
+            return Err(invalid("malformed PROXY v1 header"));
+        };
+
+        let address = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+            let ip: IpAddr = ip.parse().map_err(|_| invalid("invalid PROXY v1 address"))?;
+            if ip.is_ipv6() != ipv6 {
+                return Err(invalid("PROXY v1 address does not match protocol"));
+            }
+            // ports are plain decimal without leading zeros or signs
+            if port.len() > 1 && port.starts_with('0') || !port.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err(invalid("invalid PROXY v1 port"));
+            }
+            let port: u16 = port.parse().map_err(|_| invalid("invalid PROXY v1 port"))?;
+            Ok(SocketAddr::new(ip, port))
+        };
+
+        Ok(ProxyHeader {
+            version: 1,
+            source: Some(address(source_ip, source_port)?),
+            destination: Some(address(destination_ip, destination_port)?),
+        })
+    }
+
+    // Parses the v2 header following the 12 byte signature: the version and
+    // command byte, the family byte and the address block (including any TLVs)
+    pub fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Self> {
+        if version_command >> 4 != 2 {
+            return Err(invalid("unsupported PROXY protocol version"));
+        }
+
+        let unknown = ProxyHeader { version: 2, source: None, destination: None };
+
+        match version_command & 0x0f {
+            V2_COMMAND_LOCAL => return Ok(unknown),
+            V2_COMMAND_PROXY => {}
+            _ => return Err(invalid("unsupported PROXY v2 command")),
+        }
+
+        // addresses for datagrams or an unspecified transport are not a TCP peer
+        if family & 0x0f != V2_TRANSPORT_STREAM {
+            return Ok(unknown);
+        }
+
+        let (source, destination) = match family >> 4 {
+            V2_FAMILY_INET => {
+                let block = addresses.get(..12).ok_or_else(|| invalid("truncated PROXY v2 addresses"))?;
+                let source_ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+                let destination_ip = Ipv4Addr::new(block[4], block[5], block[6], block[7]);
+                (
+                    SocketAddr::new(source_ip.into(), u16::from_be_bytes([block[8], block[9]])),
+                    SocketAddr::new(destination_ip.into(), u16::from_be_bytes([block[10], block[11]])),
+                )
+            }
+            V2_FAMILY_INET6 => {
+                let block = addresses.get(..36).ok_or_else(|| invalid("truncated PROXY v2 addresses"))?;
+                let source_ip: [u8; 16] = block[..16].try_into().unwrap();
+                let destination_ip: [u8; 16] = block[16..32].try_into().unwrap();
+                (
+                    SocketAddr::new(Ipv6Addr::from(source_ip).into(), u16::from_be_bytes([block[32], block[33]])),
+                    SocketAddr::new(Ipv6Addr::from(destination_ip).into(), u16::from_be_bytes([block[34], block[35]])),
+                )
+            }
+            // unix sockets and unspecified families carry nothing we can use
+            _ => return Ok(unknown),
+        };
+
+        Ok(ProxyHeader {
+            version: 2,
+            source: Some(source),
+            destination: Some(destination),
+        })
+    }
+
+    // Reads a v1 or v2 header from the start of a connection. Both versions
+    // are at least 15 bytes long, so the first 12 are enough to tell them apart.
+    pub(crate) async fn read<R>(reader: &mut R) -> io::Result<Self> where R: BufRead + Unpin {
+        let mut start = [0u8; 12];
+        reader.read_exact(&mut start).await?;
+
+        if start == V2_SIGNATURE {
+            let mut fixed = [0u8; 4];
+            reader.read_exact(&mut fixed).await?;
+
+            let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+            let mut addresses = vec![0u8; len];
+            reader.read_exact(&mut addresses).await?;
+
+            return ProxyHeader::parse_v2(fixed[0], fixed[1], &addresses);
+        }
+
+        if !start.starts_with(V1_PREFIX) {
+            return Err(invalid("connection did not start with a PROXY header"));
+        }
+
+        let mut line = start.to_vec();
+        let limit = (V1_MAX_LEN - line.len()) as u64;
+        (&mut *reader).take(limit).read_until(b'\n', &mut line).await?;
+
+        if !line.ends_with(b"\n") {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+
+        let line = String::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not valid ascii"))?;
+        ProxyHeader::parse_v1(&line)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::{BufReader, ReadExt};
+    use async_std::task;
+
+    // Reads a header and whatever follows it on the connection
+    fn read(input: &[u8]) -> io::Result<(ProxyHeader, Vec<u8>)> {
+        task::block_on(async {
+            let mut reader = BufReader::new(input);
+            let header = ProxyHeader::read(&mut reader).await?;
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).await?;
+            Ok((header, rest))
+        })
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn addresses(source: &str, destination: &str) -> (Option<SocketAddr>, Option<SocketAddr>) {
+        (Some(source.parse().unwrap()), Some(destination.parse().unwrap()))
+    }
+
+    #[test]
+    fn parses_v1_headers() {
+        let (header, rest) = read(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET /").unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!((header.source, header.destination), addresses("192.0.2.1:56324", "192.0.2.2:443"));
+        assert_eq!(rest, b"GET /");
+
+        let (header, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap();
+        assert_eq!((header.source, header.destination), addresses("[2001:db8::1]:56324", "[2001:db8::2]:443"));
+
+        let (header, rest) = read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\nGET /").unwrap();
+        assert_eq!((header.source, header.destination), (None, None));
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[test]
+    fn rejects_malformed_v1_headers() {
+        for line in [
+            &b"PROXY TCP4 192.0.2.1 192.0.2.2 56324\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 192.0.2.2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 056324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 +56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\n",
+            b"PROXY UDP4 192.0.2.1 192.0.2.2 56324 443\r\n",
+            b"GET / HTTP/1.1\r\nHost: example.org\r\n\r\n",
+        ] {
+            assert_eq!(read(line).unwrap_err().kind(), io::ErrorKind::InvalidData, "{:?}", String::from_utf8_lossy(line));
+        }
+
+        // the line may not run on past 107 bytes
+        let mut line = b"PROXY UNKNOWN ".to_vec();
+        line.resize(V1_MAX_LEN, b'a');
+        line.extend_from_slice(b"\r\n");
+        assert_eq!(read(&line).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn parses_v2_headers() {
+        let block = [192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb];
+        let mut input = v2(V2_COMMAND_PROXY, 0x11, &block);
+        input.extend_from_slice(b"GET /");
+        let (header, rest) = read(&input).unwrap();
+        assert_eq!(header.version, 2);
+        assert_eq!((header.source, header.destination), addresses("192.0.2.1:56324", "192.0.2.2:443"));
+        assert_eq!(rest, b"GET /");
+
+        let mut block = Vec::new();
+        block.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        block.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        block.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        let (header, _) = read(&v2(V2_COMMAND_PROXY, 0x21, &block)).unwrap();
+        assert_eq!((header.source, header.destination), addresses("[2001:db8::1]:56324", "[2001:db8::2]:443"));
+    }
+
+    #[test]
+    fn skips_v2_tlvs() {
+        let mut block = vec![192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb];
+        // an ALPN and an authority TLV
+        block.extend_from_slice(&[0x01, 0x00, 0x02, b'h', b'2']);
+        block.extend_from_slice(&[0x02, 0x00, 0x0b]);
+        block.extend_from_slice(b"example.org");
+        let mut input = v2(V2_COMMAND_PROXY, 0x11, &block);
+        input.extend_from_slice(b"GET /");
+
+        let (header, rest) = read(&input).unwrap();
+        assert_eq!((header.source, header.destination), addresses("192.0.2.1:56324", "192.0.2.2:443"));
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[test]
+    fn v2_without_a_tcp_peer_has_no_addresses() {
+        let block = [192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb];
+        let local = [
+            v2(V2_COMMAND_LOCAL, 0x00, &[]),
+            // health checks may still send addresses
+            v2(V2_COMMAND_LOCAL, 0x11, &block),
+            // UDP and an unspecified transport
+            v2(V2_COMMAND_PROXY, 0x12, &block),
+            v2(V2_COMMAND_PROXY, 0x10, &block),
+            // a unix socket
+            v2(V2_COMMAND_PROXY, 0x31, &[0; 216]),
+        ];
+
+        for input in local {
+            let (header, rest) = read(&input).unwrap();
+            assert_eq!((header.version, header.source, header.destination), (2, None, None));
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_v2_headers() {
+        // addresses too short for the family
+        let error = read(&v2(V2_COMMAND_PROXY, 0x11, &[192, 0, 2, 1, 192, 0, 2, 2])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = read(&v2(V2_COMMAND_PROXY, 0x21, &[0; 32])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // a length running past the end of the connection
+        let mut input = v2(V2_COMMAND_PROXY, 0x11, &[0; 12]);
+        input[14..16].copy_from_slice(&1000u16.to_be_bytes());
+        assert_eq!(read(&input).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        // unknown versions and commands
+        let mut input = v2(V2_COMMAND_PROXY, 0x11, &[0; 12]);
+        input[12] = 0x31;
+        assert_eq!(read(&input).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read(&v2(0x2, 0x11, &[0; 12])).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // a signature that is almost right
+        let mut input = v2(V2_COMMAND_PROXY, 0x11, &[0; 12]);
+        input[11] = b'\r';
+        assert_eq!(read(&input).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // and a connection too short to tell
+        assert_eq!(read(b"PROXY").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,18 +1,138 @@
-use std::hash::DefaultHasher;
-use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+use std::future;
+use std::io::Read;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use async_std::channel;
+use async_std::channel::{Receiver, Sender};
 use async_std::io;
-use async_std::io::{BufReader, BufWriter};
+use async_std::io::{BufRead, BufReader, BufWriter};
 use async_std::net::{TcpListener, TcpStream};
 use async_std::prelude::*;
 use async_std::task;
-use log::{debug, info, trace};
-use crate::http::{CONNECTION_KEEP_ALIVE, Header, HEADER_CONNECTION, HEADER_CONTENT_LENGTH, HEADER_SERVER, Method, Status};
-use crate::message::{HttpRequest, HttpResponse};
+use log::debug;
+use crate::http::{CONNECTION_CLOSE, CONNECTION_KEEP_ALIVE, CONTENT_TYPE_MESSAGE_HTTP, EXPECT_100_CONTINUE, Header, HEADER_ALLOW, HEADER_AUTHORIZATION, HEADER_CONNECTION, HEADER_CONTENT_LENGTH, HEADER_CONTENT_TYPE, HEADER_COOKIE, HEADER_EXPECT, HEADER_HTTP2_SETTINGS, HEADER_PROXY_AUTHORIZATION, HEADER_SERVER, HEADER_TRAILER, HEADER_TRANSFER_ENCODING, HEADER_UPGRADE, HTTP_VERSION_1_0, HTTP_VERSION_1_1, HTTP_VERSION_2_0, Method, Status, UPGRADE_H2C};
+use crate::chunked;
+use crate::chunked::{ChunkedDecoder, TooLarge};
+use crate::decoder;
+use crate::decoder::Framing;
+use crate::http2;
+use crate::forwarded::TrustedProxies;
+use crate::message::{BodyStream, HttpRequest, HttpResponse, Reply};
+use crate::parser;
+use crate::parser::{Parsed, ParseError, RawHeader};
+use crate::proxy_protocol::ProxyHeader;
 
 const DEFAULT_SERVER_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+const STREAM_CHUNK_SIZE: usize = 16 * 1024;
+// pieces of a streamed request body waiting for the handler to read them
+const BODY_CHANNEL_CAPACITY: usize = 4;
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+const DEFAULT_MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_MAX_TRAILER_SIZE: usize = 16 * 1024;
 
 pub trait HttpHandler: Send + Sync + 'static {
     fn handle(&mut self, request: &HttpRequest) -> HttpResponse;
+
+    // Requests with any other method are answered with 501 Not Implemented.
+    // Handlers serving extension methods such as WebDAV's override this.
+    // HEAD, OPTIONS and TRACE are answered by the server unless the handler
+    // claims them: HEAD as a GET without the body, OPTIONS from
+    // `allowed_methods` and TRACE only when enabled on the server.
+    fn supports_method(&self, method: &Method) -> bool {
+        !matches!(method, Method::Extension(_) | Method::Head | Method::Options | Method::Trace)
+    }
+
+    // The methods listed in Allow for a path, "*" meaning the whole server.
+    // Handlers that route on the path can narrow this down per route.
+    fn allowed_methods(&self, _path: &str) -> Vec<Method> {
+        allowed_methods(self)
+    }
+
+    // Called with the head of a request sent with "Expect: 100-continue",
+    // before the client is told to send the body. Returning a response such
+    // as 413 Payload Too Large or 417 Expectation Failed refuses the upload.
+    fn expect_continue(&mut self, _request: &HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+
+    // Called with the head of a request that has a body. Returning true hands
+    // the request to the handler right away, with the body readable from
+    // `HttpRequest::body_stream` as it arrives instead of read into `body`
+    // first. Meant for large uploads, e.g. through `Multipart::from_request`.
+    // The trailers of a streamed body show up in `HttpRequest::trailers`
+    // once the body has been read to the end.
+    fn stream_body(&self, _request: &HttpRequest) -> bool {
+        false
+    }
+
+    // The server keeps handlers behind a single lock, so by default requests
+    // are handled one at a time across all connections. Handlers that can
+    // serve requests concurrently return a handler for a single request here,
+    // e.g. a clone sharing their state, which then runs without the lock.
+    fn fork(&self) -> Option<Box<dyn HttpHandler>> {
+        None
+    }
+}
+
+impl<H> HttpHandler for Box<H> where H: HttpHandler + ?Sized {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        (**self).handle(request)
+    }
+
+    fn supports_method(&self, method: &Method) -> bool {
+        (**self).supports_method(method)
+    }
+
+    fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        (**self).allowed_methods(path)
+    }
+
+    fn expect_continue(&mut self, request: &HttpRequest) -> Option<HttpResponse> {
+        (**self).expect_continue(request)
+    }
+
+    fn stream_body(&self, request: &HttpRequest) -> bool {
+        (**self).stream_body(request)
+    }
+
+    fn fork(&self) -> Option<Box<dyn HttpHandler>> {
+        (**self).fork()
+    }
+}
+
+// The standard methods a handler accepts according to `supports_method`
+pub fn allowed_methods<H>(handler: &H) -> Vec<Method> where H: HttpHandler + ?Sized {
+    let get = handler.supports_method(&Method::Get);
+    let head = get || handler.supports_method(&Method::Head);
+
+    [Method::Get, Method::Head, Method::Post, Method::Put, Method::Delete, Method::Patch, Method::Options]
+        .into_iter()
+        .filter(|method| match method {
+            Method::Get => get,
+            Method::Head => head,
+            Method::Options => true,
+            _ => handler.supports_method(method),
+        })
+        .collect()
+}
+
+// Answers OPTIONS with the allowed methods and no body
+pub fn options_response(methods: &[Method]) -> HttpResponse {
+    let allow: Vec<&str> = methods.iter().map(Method::as_str).collect();
+    HttpResponse::new(Status::Ok, vec![Header::new(HEADER_ALLOW, allow.join(", "))], None)
+}
+
+// Echoes the request back, leaving out the fields that carry credentials
+fn trace_response(request: &HttpRequest) -> HttpResponse {
+    let mut echo = request.clone();
+    echo.body = None;
+    echo.headers.retain(|header| {
+        ![HEADER_AUTHORIZATION, HEADER_PROXY_AUTHORIZATION, HEADER_COOKIE].iter().any(|key| header.key().eq_ignore_ascii_case(key))
+    });
+
+    HttpResponse::new(Status::Ok, vec![Header::new(HEADER_CONTENT_TYPE, CONTENT_TYPE_MESSAGE_HTTP)], Some(echo.head_to_bytes()))
 }
 
 #[derive(Clone)]
@@ -21,6 +141,25 @@ pub struct HttpServer {
     port: u16,
     default_headers: Vec<Header>,
     handler: Option<Arc<Mutex<dyn HttpHandler>>>,
+    trusted_proxies: TrustedProxies,
+    proxy_protocol: bool,
+    trace: bool,
+    pipeline_depth: usize,
+    strict: bool,
+    http2: bool,
+    max_body_size: usize,
+    max_trailer_size: usize,
+}
+
+// A request whose response has yet to be written
+pub(crate) struct InFlight {
+    pub(crate) method: Method,
+    pub(crate) accepts_trailers: bool,
+    // HTTP/1.0 clients can't tell informational responses from the final one
+    pub(crate) accepts_interim: bool,
+    pub(crate) replies: Receiver<Reply>,
+    // the connection ends after this response
+    pub(crate) close: bool,
 }
 
 impl Default for HttpServer {
@@ -33,6 +172,14 @@ impl Default for HttpServer {
                 Header::new(HEADER_CONNECTION, CONNECTION_KEEP_ALIVE),
             ],
             handler: None,
+            trusted_proxies: TrustedProxies::default(),
+            proxy_protocol: false,
+            trace: false,
+            pipeline_depth: 1,
+            strict: true,
+            http2: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_trailer_size: DEFAULT_MAX_TRAILER_SIZE,
         }
     }
 }
@@ -43,7 +190,8 @@ impl HttpServer {
             hostname,
             port,
             handler,
-            default_headers
+            default_headers,
+            ..HttpServer::default()
         }
     }
 
@@ -63,9 +211,9 @@ impl HttpServer {
 
             while let Some(stream) = incoming.next().await {
                 let stream = stream?;
-                let handler = self.handler.clone();
-                task::spawn(async {
-                    Self::handle_connection(stream, handler).await.unwrap();
+                let server = self.clone();
+                task::spawn(async move {
+                    server.handle_connection(stream).await.unwrap();
                 });
             }
             Ok(())
@@ -77,78 +225,628 @@ impl HttpServer {
         self
     }
 
-    async fn handle_connection(stream: TcpStream, handler: Option<Arc<Mutex<dyn HttpHandler>>>) -> io::Result<()> {
+    async fn handle_connection(&self, stream: TcpStream) -> io::Result<()> {
 
-        debug!("Incoming connection from: {}", stream.peer_addr()?);
+        let peer_addr = stream.peer_addr()?;
+        let local_addr = stream.local_addr()?;
+
+        debug!("Incoming connection from: {}", peer_addr);
         
         let mut reader = BufReader::new(&stream);
         let mut writer = BufWriter::new(&stream);
 
+        // the load balancer sends a PROXY header once, ahead of any request
+        let proxy_header = if self.proxy_protocol {
+            match ProxyHeader::read(&mut reader).await {
+                Ok(header) => Some(header),
+                Err(error) => {
+                    debug!("Rejecting connection from {}: {}", peer_addr, error);
+                    return Ok(());
+                }
+            }
+        } else {
+            None
+        };
+
+        // requests that have been dispatched but not yet answered, oldest first
+        let mut in_flight: VecDeque<InFlight> = VecDeque::new();
+        let mut head = Vec::new();
+        let mut requests = 0;
+
         loop {
 
-            // Read HTTP request line
-            let mut request_line = String::new();
-            reader.read_line(&mut request_line).await?;
+            // with responses outstanding, only read on when the next request is
+            // already buffered and it and everything before it may run concurrently
+            if let Some(oldest) = in_flight.pop_front() {
+                let read_ahead = in_flight.len() + 1 < self.pipeline_depth
+                    && oldest.method.is_idempotent()
+                    && in_flight.iter().all(|request| request.method.is_idempotent())
+                    && Self::buffered_method(reader.buffer()).is_some_and(|method| method.is_idempotent());
+
+                if !read_ahead {
+                    Self::write_response(&mut writer, oldest).await?;
+                    continue;
+                }
+                in_flight.push_front(oldest);
+            }
+
+            let mut request = match Self::read_head(&mut reader, &mut head, self.strict).await? {
+                Some(Ok(request)) => request,
+                Some(Err(status)) => {
+                    Self::reject(&mut writer, &mut in_flight, status).await?;
+                    break;
+                }
+                None => break,
+            };
 
-            if request_line.is_empty() {
-                break;
+            // a client with prior knowledge of HTTP/2 opens with PRI * HTTP/2.0,
+            // followed by the rest of the preface where a body would be
+            if request.method().as_str() == "PRI" && request.path() == "*" {
+                if !self.http2 || requests > 0 {
+                    Self::reject(&mut writer, &mut in_flight, Status::HTTPVersionNotSupported).await?;
+                    break;
+                }
+
+                let mut preface = [0u8; 6];
+                reader.read_exact(&mut preface).await?;
+                if !http2::PREFACE.ends_with(&preface) {
+                    debug!("Invalid HTTP/2 preface from {}", peer_addr);
+                    return Ok(());
+                }
+
+                return http2::serve(self, &stream, reader, proxy_header, None).await;
+            }
+
+            request.set_addrs(peer_addr, local_addr);
+            if let Some(proxy_header) = proxy_header {
+                request.set_proxy_header(proxy_header);
             }
 
-            let mut request = HttpRequest::parse(request_line);
+            let framing = match Self::body_framing(&request.headers, self.strict) {
+                Ok(framing) => framing,
+                Err(error) => {
+                    debug!("Rejecting request from {}: {}", peer_addr, error);
+                    Self::reject(&mut writer, &mut in_flight, Status::BadRequest).await?;
+                    break;
+                }
+            };
 
-            // Parse headers
-            loop {
-                let mut header_line = String::new();
-                reader.read_line(&mut header_line).await?;
+            // handlers that stream the body get the request before the body arrives
+            let has_body = matches!(framing, Framing::Length(1..) | Framing::Chunked);
+            let streamed = if has_body {
+                let streamed;
+                (request, streamed) = self.stream_body(request).await;
+                streamed
+            } else {
+                false
+            };
 
-                // Check if the header line is empty
-                if header_line.trim().is_empty() {
+            // a body that is too large to buffer is refused before the client sends it
+            if let (Framing::Length(length), false) = (framing, streamed) {
+                if usize::try_from(length).map_or(true, |length| length > self.max_body_size) {
+                    debug!("Rejecting request from {}: Content-Length too large", peer_addr);
+                    Self::reject(&mut writer, &mut in_flight, Status::PayloadTooLarge).await?;
                     break;
                 }
-                
-                request.headers.push(Header::parse(header_line));
             }
 
-            // Check if the request has a Content-Length header
-            let content_length = request.headers.iter()
-                .find(|header| header.key() == HEADER_CONTENT_LENGTH)
-                .and_then(|header| header.value().parse::<usize>().ok());
+            // 100-continue is the only expectation there is, anything else fails
+            if let Some(expect) = request.header(HEADER_EXPECT).map(str::to_string) {
+                // the answer to the expectation must not overtake earlier responses
+                Self::write_all_responses(&mut writer, &mut in_flight).await?;
+
+                let rejection = if !expect.trim().eq_ignore_ascii_case(EXPECT_100_CONTINUE) {
+                    Some(HttpResponse::new(Status::ExpectationFailed, vec![], None))
+                } else if let Some(ref handler) = self.handler {
+                    let handler = handler.clone();
+                    let (checked, rejection) = task::spawn_blocking(move || {
+                        let rejection = handler.lock().unwrap().expect_continue(&request);
+                        (request, rejection)
+                    }).await;
+                    request = checked;
+                    rejection
+                } else {
+                    None
+                };
+
+                // the body was never asked for, so the connection can't be reused
+                if let Some(mut response) = rejection {
+                    response.set_header(HEADER_CONNECTION, CONNECTION_CLOSE);
+                    writer.write_all(&response.to_bytes()).await?;
+                    writer.flush().await?;
+                    break;
+                }
+
+                if has_body {
+                    let interim = format!("{} {} {}\r\n\r\n", HTTP_VERSION_1_1, Status::Continue.as_u16(), Status::Continue.reason_phrase());
+                    writer.write_all(interim.as_bytes()).await?;
+                    writer.flush().await?;
+                }
+            }
+
+            if streamed {
+                let (sender, body) = BodyStream::channel(BODY_CHANNEL_CAPACITY);
+                request.set_body_stream(body);
+                let trailers = request.pending_trailers();
+                in_flight.push_back(self.spawn_handler(request));
+                requests += 1;
+
+                let complete = match Self::pump_body(&mut reader, framing, self.max_trailer_size, sender, &trailers).await {
+                    Ok(complete) => complete,
+                    Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                        debug!("Invalid body from {}: {}", peer_addr, error);
+                        false
+                    }
+                    Err(error) => return Err(error),
+                };
+
+                // without the whole body there is no telling where the next request starts
+                if !complete {
+                    if let Some(request) = in_flight.back_mut() {
+                        request.close = true;
+                    }
+                    break;
+                }
+                continue;
+            }
 
             // If the request has a body, read it
-            if let Some(content_length) = content_length {
-                // Read the message body
-                let mut message_body = vec![0u8; content_length];
-                reader.read_exact(&mut message_body).await?;
-                request.body = Some(message_body);
-            }
-            
-            let response = match handler {
-                Some(ref handler) => handler.lock().unwrap().handle(&request),
-                None => HttpResponse::new(Status::BadRequest, vec![], None),
+            match framing {
+                Framing::Length(length) => {
+                    let length = usize::try_from(length)
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Content-Length too large"))?;
+                    let mut message_body = vec![0u8; length];
+                    reader.read_exact(&mut message_body).await?;
+                    request.body = Some(message_body);
+                }
+                Framing::Chunked => match chunked::read_body(&mut reader, self.max_body_size, self.max_trailer_size).await {
+                    Ok((message_body, trailers)) => {
+                        request.body = Some(message_body);
+                        request.set_trailers(trailers);
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                        debug!("Rejecting request from {}: {}", peer_addr, error);
+                        let status = match chunked::too_large(&error) {
+                            Some(TooLarge::Body) => Status::PayloadTooLarge,
+                            Some(TooLarge::Trailers) => Status::RequestHeaderFieldsTooLarge,
+                            None => Status::BadRequest,
+                        };
+                        Self::reject(&mut writer, &mut in_flight, status).await?;
+                        break;
+                    }
+                    Err(error) => return Err(error),
+                },
+                _ => {}
+            }
+
+            // h2c takes over once every earlier response has been written
+            if let Some(settings) = http2::upgrade_settings(&request).filter(|_| self.http2) {
+                Self::write_all_responses(&mut writer, &mut in_flight).await?;
+
+                let switching = format!("{} {} {}\r\n{}: {}\r\n{}: {}\r\n\r\n", HTTP_VERSION_1_1, Status::SwitchingProtocols.as_u16(), Status::SwitchingProtocols.reason_phrase(),
+                    HEADER_CONNECTION, HEADER_UPGRADE, HEADER_UPGRADE, UPGRADE_H2C);
+                writer.write_all(switching.as_bytes()).await?;
+                writer.flush().await?;
+
+                // the client follows up with the connection preface
+                let mut preface = [0u8; http2::PREFACE.len()];
+                reader.read_exact(&mut preface).await?;
+                if preface != http2::PREFACE {
+                    debug!("Missing HTTP/2 preface from {}", peer_addr);
+                    return Ok(());
+                }
+
+                // the upgrade fields were only meant for the HTTP/1.1 connection
+                request.headers.retain(|header| {
+                    ![HEADER_CONNECTION, HEADER_UPGRADE, HEADER_HTTP2_SETTINGS].iter().any(|key| header.key().eq_ignore_ascii_case(key))
+                });
+                return http2::serve(self, &stream, reader, proxy_header, Some((request, settings))).await;
+            }
+
+            in_flight.push_back(self.spawn_handler(request));
+            requests += 1;
+        }
+
+        // the client may stop sending while responses are still owed
+        Self::write_all_responses(&mut writer, &mut in_flight).await?;
+
+        Ok(())
+    }
+
+    // Reads the next request head into `head`, which is reused for every request
+    // on the connection. Only the bytes belonging to the head are consumed, so
+    // the body and any pipelined requests stay in the reader. Returns None when
+    // the connection closes, and the status to reject the request with when the
+    // head is malformed or too large.
+    async fn read_head(reader: &mut BufReader<&TcpStream>, head: &mut Vec<u8>, strict: bool) -> io::Result<Option<Result<HttpRequest, Status>>> {
+        head.clear();
+
+        loop {
+            let filled = future::poll_fn(|cx| Pin::new(&mut *reader).poll_fill_buf(cx).map_ok(|bytes| !bytes.is_empty())).await?;
+            if !filled {
+                return Ok(None);
+            }
+
+            let previous = head.len();
+            head.extend_from_slice(reader.buffer());
+
+            let mut headers = [RawHeader::default(); MAX_HEADERS];
+            match parser::parse_request(head, &mut headers, strict) {
+                Ok(Parsed::Complete(parsed, len)) => {
+                    // HTTP/2.0 only appears in PRI * HTTP/2.0, which opens the
+                    // HTTP/2 connection preface rather than being a request
+                    let preface = parsed.method == "PRI" && parsed.target == "*";
+                    let rejection = match parsed.version {
+                        HTTP_VERSION_2_0 if preface => None,
+                        HTTP_VERSION_1_0 | HTTP_VERSION_1_1 if preface => Some(Status::BadRequest),
+                        HTTP_VERSION_1_0 | HTTP_VERSION_1_1 => None,
+                        _ => Some(Status::HTTPVersionNotSupported),
+                    };
+                    if let Some(status) = rejection {
+                        return Ok(Some(Err(status)));
+                    }
+
+                    let request = HttpRequest::from_head(&parsed);
+                    Pin::new(&mut *reader).consume(len - previous);
+                    return Ok(Some(Ok(request)));
+                }
+                Ok(Parsed::Partial) if head.len() < MAX_HEAD_SIZE => Pin::new(&mut *reader).consume(head.len() - previous),
+                Ok(Parsed::Partial) | Err(ParseError::TooManyHeaders) => return Ok(Some(Err(Status::RequestHeaderFieldsTooLarge))),
+                Err(error) => {
+                    debug!("Rejecting request: {}", error);
+                    return Ok(Some(Err(Status::BadRequest)));
+                }
+            }
+        }
+    }
+
+    // How the request body is delimited. In strict mode any framing that another
+    // server in front of this one could read differently is an error:
+    // Content-Length together with Transfer-Encoding, lengths that disagree or
+    // aren't plain digits, and transfer codings other than a single chunked,
+    // which is the only one the server can decode.
+    fn body_framing(headers: &[Header], strict: bool) -> io::Result<Framing> {
+        if !strict {
+            // with chunked out of place, go with chunked, and with conflicting
+            // lengths, with the first one that parses
+            return Ok(decoder::body_framing(headers, Framing::Empty).unwrap_or_else(|_| {
+                if decoder::transfer_codings(headers).iter().any(|coding| coding == "chunked") {
+                    return Framing::Chunked;
+                }
+                headers.iter()
+                    .filter(|header| header.key().eq_ignore_ascii_case(HEADER_CONTENT_LENGTH))
+                    .find_map(|header| header.value().trim().parse().ok())
+                    .map_or(Framing::Empty, Framing::Length)
+            }));
+        }
+
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        let present = |key: &str| headers.iter().any(|header| header.key().eq_ignore_ascii_case(key));
+
+        if present(HEADER_CONTENT_LENGTH) && present(HEADER_TRANSFER_ENCODING) {
+            return Err(invalid("both Content-Length and Transfer-Encoding"));
+        }
+
+        let digits = headers.iter()
+            .filter(|header| header.key().eq_ignore_ascii_case(HEADER_CONTENT_LENGTH))
+            .flat_map(|header| header.value().split(','))
+            .all(|length| !length.trim().is_empty() && length.trim().bytes().all(|byte| byte.is_ascii_digit()));
+        if !digits {
+            return Err(invalid("invalid Content-Length"));
+        }
+
+        if present(HEADER_TRANSFER_ENCODING) && decoder::transfer_codings(headers) != ["chunked"] {
+            return Err(invalid("unsupported transfer coding"));
+        }
+
+        decoder::body_framing(headers, Framing::Empty)
+    }
+
+    // Answers a request that can't be handled and ends the connection, since
+    // there is no telling where the next request would start. Responses still
+    // owed to earlier requests go first.
+    async fn reject(writer: &mut BufWriter<&TcpStream>, in_flight: &mut VecDeque<InFlight>, status: Status) -> io::Result<()> {
+        Self::write_all_responses(writer, in_flight).await?;
+
+        let mut response = HttpResponse::new(status, vec![], None);
+        response.set_header(HEADER_CONNECTION, CONNECTION_CLOSE);
+        writer.write_all(&response.to_bytes()).await?;
+        writer.flush().await
+    }
+
+    // The method of the next request, if enough of it has already arrived
+    fn buffered_method(buffer: &[u8]) -> Option<Method> {
+        let end = buffer.iter().position(|byte| *byte == b' ')?;
+        std::str::from_utf8(&buffer[..end]).ok()?.parse().ok()
+    }
+
+    // Whether the handler wants the body of the request as it arrives
+    async fn stream_body(&self, request: HttpRequest) -> (HttpRequest, bool) {
+        match self.handler {
+            Some(ref handler) => {
+                let handler = handler.clone();
+                task::spawn_blocking(move || {
+                    let streamed = handler.lock().unwrap().stream_body(&request);
+                    (request, streamed)
+                }).await
+            }
+            None => (request, false),
+        }
+    }
+
+    // Passes a request body on to the handler while it arrives, as well as any
+    // error reading it. The trailers are filled in before the handler sees the
+    // end of the body. Returns false when the handler stopped reading before
+    // the end, leaving the rest of the body unread.
+    async fn pump_body(reader: &mut BufReader<&TcpStream>, framing: Framing, max_trailer_size: usize, sender: Sender<io::Result<Vec<u8>>>, trailers: &OnceLock<Vec<Header>>) -> io::Result<bool> {
+        let mut remaining = match framing {
+            Framing::Length(length) => length,
+            _ => 0,
+        };
+        let mut decoder = ChunkedDecoder::new(max_trailer_size);
+
+        loop {
+            let chunk = match framing {
+                Framing::Length(_) if remaining == 0 => Ok(None),
+                Framing::Length(_) => {
+                    let mut chunk = vec![0u8; remaining.min(STREAM_CHUNK_SIZE as u64) as usize];
+                    remaining -= chunk.len() as u64;
+                    match reader.read_exact(&mut chunk).await {
+                        Ok(()) => Ok(Some(chunk)),
+                        Err(error) => Err(error),
+                    }
+                }
+                _ => decoder.next(reader, STREAM_CHUNK_SIZE).await,
             };
-            
-            writer.write_all(&response.to_bytes()).await?;
-            
-            writer.flush().await?;
 
+            match chunk {
+                Ok(Some(chunk)) => {
+                    if sender.send(Ok(chunk)).await.is_err() {
+                        return Ok(false);
+                    }
+                }
+                Ok(None) => {
+                    let _ = trailers.set(decoder.into_trailers());
+                    return Ok(true);
+                }
+                Err(error) => {
+                    let _ = sender.send(Err(io::Error::new(error.kind(), error.to_string()))).await;
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn spawn_handler(&self, request: HttpRequest) -> InFlight {
+        self.spawn_handler_with_body(request, None)
+    }
+
+    // Like `spawn_handler`, optionally for a request whose body is still
+    // arriving through a stream, with its trailers set on the request before
+    // the stream ends. Unless the handler streams the body, it is read in full
+    // first, up to the maximum body size.
+    pub(crate) fn spawn_handler_with_body(&self, mut request: HttpRequest, body: Option<BodyStream>) -> InFlight {
+        let forwarded = self.trusted_proxies.resolve(&request);
+        request.set_forwarded(forwarded);
+
+        let method = request.method().clone();
+        let accepts_trailers = request.accepts_trailers();
+        let accepts_interim = request.version() != HTTP_VERSION_1_0;
+        let (replies, receiver) = channel::unbounded();
+
+        match self.handler {
+            // handlers are synchronous and may block, so keep them off the executor
+            Some(ref handler) => {
+                let handler = handler.clone();
+                let trace = self.trace;
+                let max_body_size = self.max_body_size;
+                request.set_replies(replies.clone());
+
+                task::spawn_blocking(move || {
+                    if let Some(body) = body {
+                        if let Err(status) = Self::receive_body(&handler, &mut request, body, max_body_size) {
+                            let _ = replies.try_send(Reply::Final(HttpResponse::new(status, vec![], None)));
+                            return;
+                        }
+                    }
+
+                    let forked = handler.lock().unwrap().fork();
+                    let response = match forked {
+                        Some(mut forked) => Self::dispatch(&mut *forked, request, trace),
+                        None => Self::dispatch(&mut *handler.lock().unwrap(), request, trace),
+                    };
+                    let _ = replies.try_send(Reply::Final(response));
+                });
+            }
+            None => {
+                let _ = replies.try_send(Reply::Final(HttpResponse::new(Status::BadRequest, vec![], None)));
+            }
+        }
+
+        InFlight { method, accepts_trailers, accepts_interim, replies: receiver, close: false }
+    }
+
+    // Hands the body stream to handlers that read it themselves, and reads it
+    // into the request for all others
+    fn receive_body(handler: &Mutex<dyn HttpHandler>, request: &mut HttpRequest, mut body: BodyStream, max_body_size: usize) -> Result<(), Status> {
+        if handler.lock().unwrap().stream_body(request) {
+            request.set_body_stream(body);
+            return Ok(());
+        }
+
+        let mut message_body = Vec::new();
+        match (&mut body).take(max_body_size as u64 + 1).read_to_end(&mut message_body) {
+            Ok(length) if length > max_body_size => return Err(Status::PayloadTooLarge),
+            Ok(_) => {}
+            Err(_) => return Err(Status::BadRequest),
         }
 
+        request.body = Some(message_body);
         Ok(())
     }
-}
 
-pub struct HttpServerBuilder {
-    server: HttpServer,
-}
+    async fn write_all_responses(writer: &mut BufWriter<&TcpStream>, in_flight: &mut VecDeque<InFlight>) -> io::Result<()> {
+        while let Some(request) = in_flight.pop_front() {
+            Self::write_response(writer, request).await?;
+        }
+        Ok(())
+    }
 
-impl Default for HttpServerBuilder {
-    fn default() -> Self {
-        HttpServerBuilder {
-            server: HttpServer::default(),
+    async fn write_response(writer: &mut BufWriter<&TcpStream>, request: InFlight) -> io::Result<()> {
+
+        // informational responses go out as soon as the handler sends them, to
+        // clients that understand them
+        let mut response = loop {
+            match request.replies.recv().await {
+                Ok(Reply::Interim(mut interim)) if request.accepts_interim => {
+                    interim.remove_header(HEADER_CONTENT_LENGTH);
+                    interim.remove_header(HEADER_CONNECTION);
+                    writer.write_all(&interim.head_to_bytes()).await?;
+                    writer.flush().await?;
+                }
+                Ok(Reply::Interim(_)) => {}
+                Ok(Reply::Final(response)) => break response,
+                // the handler panicked
+                Err(_) => break HttpResponse::new(Status::InternalServerError, vec![], None),
+            }
+        };
+
+        // these responses never have a body, and 1xx and 204 no framing either
+        let code = response.status.as_u16();
+        if matches!(code, 100..=199 | 204) {
+            response.remove_header(HEADER_CONTENT_LENGTH);
+            response.remove_header(HEADER_TRANSFER_ENCODING);
+        }
+        if request.close {
+            response.set_header(HEADER_CONNECTION, CONNECTION_CLOSE);
         }
+        let send_body = request.method != Method::Head && !matches!(code, 100..=199 | 204 | 304);
+
+        // trailers can only follow a chunked body, and only go to clients that asked for them
+        let send_trailers = send_body && request.accepts_trailers && !response.trailers().is_empty();
+        if send_trailers {
+            let names: Vec<&str> = response.trailers().iter().map(Header::key).collect();
+            let names = names.join(", ");
+            response.set_header(HEADER_TRAILER, names);
+            response.remove_header(HEADER_CONTENT_LENGTH);
+            response.set_header(HEADER_TRANSFER_ENCODING, "chunked");
+        }
+        let trailers = if send_trailers { response.trailers() } else { &[] };
+
+        writer.write_all(&response.head_to_bytes()).await?;
+
+        if let (Some(body), true) = (response.stream(), send_body) {
+            Self::write_stream(writer, body, response.is_chunked(), trailers).await?;
+        } else if send_trailers {
+            // chunked framing was announced, so the last chunk goes out even without a body
+            let body = response.body().map(Vec::as_slice).unwrap_or_default();
+            if !body.is_empty() {
+                writer.write_all(format!("{:x}\r\n", body.len()).as_bytes()).await?;
+                writer.write_all(body).await?;
+                writer.write_all(b"\r\n").await?;
+            }
+            writer.write_all(&Self::last_chunk(trailers)).await?;
+        } else if let (Some(body), true) = (response.body(), send_body) {
+            writer.write_all(body).await?;
+        }
+
+        writer.flush().await
+    }
+
+    fn dispatch(handler: &mut dyn HttpHandler, mut request: HttpRequest, trace: bool) -> HttpResponse {
+        let mut response = Self::dispatch_method(handler, &mut request, trace);
+
+        // caches must know which request headers the representation was picked by
+        for header in request.negotiated() {
+            response.add_vary(header);
+        }
+        response
+    }
+
+    fn dispatch_method(handler: &mut dyn HttpHandler, request: &mut HttpRequest, trace: bool) -> HttpResponse {
+        let method = request.method().clone();
+
+        let allowed = |handler: &dyn HttpHandler, path: &str| {
+            let mut methods = handler.allowed_methods(path);
+            if trace && !methods.contains(&Method::Trace) {
+                methods.push(Method::Trace);
+            }
+            methods
+        };
+
+        // OPTIONS * is about the server as a whole, not any resource
+        if method == Method::Options && request.path() == "*" {
+            return options_response(&allowed(handler, "*"));
+        }
+
+        if handler.supports_method(&method) {
+            return handler.handle(request);
+        }
+
+        match method {
+            Method::Head if handler.supports_method(&Method::Get) => {
+                request.set_method(Method::Get);
+                handler.handle(request)
+            }
+            Method::Options => options_response(&allowed(handler, request.path())),
+            Method::Trace if trace => trace_response(request),
+            Method::Trace => {
+                let mut response = options_response(&allowed(handler, request.path()));
+                response.status = Status::MethodNotAllowed;
+                response
+            }
+            _ => HttpResponse::new(Status::NotImplemented, vec![], None),
+        }
+    }
+
+    async fn write_stream(writer: &mut BufWriter<&TcpStream>, body: &BodyStream, chunked: bool, trailers: &[Header]) -> io::Result<()> {
+        let mut buffer = vec![0u8; STREAM_CHUNK_SIZE];
+
+        loop {
+            let stream = body.clone();
+            let (result, returned) = task::spawn_blocking(move || {
+                let result = stream.read(&mut buffer);
+                (result, buffer)
+            }).await;
+            buffer = returned;
+
+            let count = result?;
+            if count == 0 {
+                break;
+            }
+
+            if chunked {
+                writer.write_all(format!("{:x}\r\n", count).as_bytes()).await?;
+                writer.write_all(&buffer[..count]).await?;
+                writer.write_all(b"\r\n").await?;
+            } else {
+                writer.write_all(&buffer[..count]).await?;
+            }
+        }
+
+        if chunked {
+            writer.write_all(&Self::last_chunk(trailers)).await?;
+        }
+
+        Ok(())
+    }
+
+    // The zero sized chunk ending a chunked body, followed by the trailer section
+    fn last_chunk(trailers: &[Header]) -> Vec<u8> {
+        let mut buffer = b"0\r\n".to_vec();
+        for trailer in trailers {
+            buffer.extend(format!("{}: {}\r\n", trailer.key, trailer.value).as_bytes());
+        }
+        buffer.extend(b"\r\n");
+        buffer
     }
 }
 
+#[derive(Default)]
+pub struct HttpServerBuilder {
+    server: HttpServer,
+}
+
 impl HttpServerBuilder {
 
     pub fn hostname<H>(&mut self, hostname: H) -> &mut Self where H: Into<String>{
@@ -171,6 +869,68 @@ impl HttpServerBuilder {
         self
     }
 
+    pub fn trusted_proxies(&mut self, trusted_proxies: TrustedProxies) -> &mut Self {
+        self.server.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    // Requires every connection to start with a PROXY protocol v1 or v2 header.
+    // Only enable this when all clients are load balancers that send one.
+    pub fn proxy_protocol(&mut self, enabled: bool) -> &mut Self {
+        self.server.proxy_protocol = enabled;
+        self
+    }
+
+    // Echoes TRACE requests back to the client. Off by default, since the echo
+    // can expose headers added by proxies in between.
+    pub fn trace(&mut self, enabled: bool) -> &mut Self {
+        self.server.trace = enabled;
+        self
+    }
+
+    // How many pipelined requests on a connection may be handled at once.
+    // Requests already sent by the client are read ahead and dispatched while
+    // earlier ones are still being answered, as long as they are idempotent;
+    // responses are always written in request order. Defaults to 1, handling
+    // requests strictly one after another. Requests only run at the same time
+    // when the handler implements `HttpHandler::fork`.
+    pub fn pipeline_depth(&mut self, depth: usize) -> &mut Self {
+        self.server.pipeline_depth = depth.max(1);
+        self
+    }
+
+    // Rejects requests that don't follow RFC 9112 exactly with 400 Bad Request
+    // and closes the connection, guarding against request smuggling. On by
+    // default; turning it off accepts bare LF line endings, whitespace before
+    // the colon, obsolete line folding and ambiguous body lengths.
+    pub fn strict(&mut self, enabled: bool) -> &mut Self {
+        self.server.strict = enabled;
+        self
+    }
+
+    // Serves HTTP/2 without TLS to clients that start with the HTTP/2
+    // connection preface or upgrade an HTTP/1.1 request with "Upgrade: h2c".
+    // Off by default.
+    pub fn http2(&mut self, enabled: bool) -> &mut Self {
+        self.server.http2 = enabled;
+        self
+    }
+
+    // The largest request body read into memory for the handler, answered
+    // with 413 Payload Too Large beyond that. Bodies streamed to handlers that
+    // implement `HttpHandler::stream_body` aren't limited. Defaults to 16 MiB.
+    pub fn max_body_size(&mut self, size: usize) -> &mut Self {
+        self.server.max_body_size = size;
+        self
+    }
+
+    // The largest trailer section after a chunked request body, answered with
+    // 431 Request Header Fields Too Large beyond that. Defaults to 16 KiB.
+    pub fn max_trailer_size(&mut self, size: usize) -> &mut Self {
+        self.server.max_trailer_size = size;
+        self
+    }
+
     pub fn build(&self) -> HttpServer {
         self.server.clone()
     }
@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use log::warn;
+use rand::RngCore;
+use crate::cookie::{Cookie, CookieError, CookieKeys, SameSite};
+use crate::http::{HEADER_SET_COOKIE, Method};
+use crate::message::{HttpRequest, HttpResponse};
+use crate::server::HttpHandler;
+
+const DEFAULT_SESSION_COOKIE: &str = "libhttp.sid";
+const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+pub type SessionData = HashMap<String, String>;
+
+pub trait SessionStore: Send + Sync + 'static {
+    fn load(&self, id: &str) -> Option<SessionData>;
+    fn store(&self, id: &str, data: SessionData, ttl: Duration);
+    fn destroy(&self, id: &str);
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (SessionData, Instant)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        match sessions.get(id) {
+            Some((data, expires)) if *expires > Instant::now() => Some(data.clone()),
+            Some(_) => {
+                sessions.remove(id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn store(&self, id: &str, data: SessionData, ttl: Duration) {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        // drop expired sessions while we hold the lock anyway
+        let now = Instant::now();
+        sessions.retain(|_, (_, expires)| *expires > now);
+
+        sessions.insert(id.to_string(), (data, now + ttl));
+    }
+
+    fn destroy(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SessionStatus {
+    Unchanged,
+    Changed,
+    Regenerated,
+    Destroyed,
+}
+
+#[derive(Debug)]
+struct SessionState {
+    id: Option<String>,
+    data: SessionData,
+    status: SessionStatus,
+}
+
+// A handle to the session of the current request. Clones share the same
+// state, so changes made by a handler are seen by the middleware afterwards.
+#[derive(Debug, Clone)]
+pub struct Session {
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl Session {
+
+    fn new(id: Option<String>, data: SessionData) -> Self {
+        Session {
+            state: Arc::new(Mutex::new(SessionState {
+                id,
+                data,
+                status: SessionStatus::Unchanged,
+            })),
+        }
+    }
+
+    pub fn id(&self) -> Option<String> {
+        self.state.lock().unwrap().id.clone()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state.lock().unwrap().data.get(key).cloned()
+    }
+
+    pub fn insert<K, V>(&self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let mut state = self.state.lock().unwrap();
+        state.data.insert(key.into(), value.into());
+        state.mark(SessionStatus::Changed);
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let value = state.data.remove(key);
+        if value.is_some() {
+            state.mark(SessionStatus::Changed);
+        }
+        value
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.data.clear();
+        state.mark(SessionStatus::Changed);
+    }
+
+    // Issues a new session id for the same data, e.g. after logging in
+    pub fn regenerate(&self) {
+        self.state.lock().unwrap().mark(SessionStatus::Regenerated);
+    }
+
+    pub fn destroy(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.data.clear();
+        state.status = SessionStatus::Destroyed;
+    }
+}
+
+impl SessionState {
+
+    fn mark(&mut self, status: SessionStatus) {
+        match (self.status, status) {
+            (SessionStatus::Destroyed, _) => {}
+            (SessionStatus::Regenerated, SessionStatus::Changed) => {}
+            (_, status) => self.status = status,
+        }
+    }
+}
+
+pub struct SessionMiddleware {
+    handler: Arc<Mutex<dyn HttpHandler>>,
+    store: Arc<dyn SessionStore>,
+    keys: CookieKeys,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+}
+
+impl SessionMiddleware {
+
+    pub fn new(handler: Arc<Mutex<dyn HttpHandler>>, keys: CookieKeys) -> Self {
+        Self::builder(handler, keys).build()
+    }
+
+    pub fn builder(handler: Arc<Mutex<dyn HttpHandler>>, keys: CookieKeys) -> SessionMiddlewareBuilder {
+        SessionMiddlewareBuilder {
+            handler,
+            store: Arc::new(MemoryStore::new()),
+            keys,
+            cookie_name: DEFAULT_SESSION_COOKIE.to_string(),
+            ttl: DEFAULT_SESSION_TTL,
+            secure: false,
+        }
+    }
+
+    fn load_session(&self, request: &HttpRequest) -> Session {
+        request.cookies()
+            .get_signed(&self.cookie_name, &self.keys)
+            .and_then(|cookie| {
+                let id = cookie.value().to_string();
+                self.store.load(&id).map(|data| Session::new(Some(id), data))
+            })
+            .unwrap_or_else(|| Session::new(None, SessionData::new()))
+    }
+
+    fn save_session(&self, session: &Session, response: &mut HttpResponse) {
+        let state = session.state.lock().unwrap();
+
+        match (state.status, &state.id) {
+            (SessionStatus::Unchanged, _) => {}
+            (SessionStatus::Destroyed, id) => {
+                if let Some(id) = id {
+                    self.store.destroy(id);
+                    self.set_cookie(response, self.removal_cookie());
+                }
+            }
+            (SessionStatus::Changed, Some(id)) => {
+                self.store.store(id, state.data.clone(), self.ttl);
+                self.set_cookie(response, self.session_cookie(id));
+            }
+            (SessionStatus::Changed, None) | (SessionStatus::Regenerated, _) => {
+                if let Some(id) = &state.id {
+                    self.store.destroy(id);
+                }
+                let id = generate_session_id();
+                self.store.store(&id, state.data.clone(), self.ttl);
+                self.set_cookie(response, self.session_cookie(&id));
+            }
+        }
+    }
+
+    // Only a cookie name that isn't a token can make this fail
+    fn set_cookie(&self, response: &mut HttpResponse, cookie: Result<Cookie, CookieError>) {
+        match cookie {
+            Ok(cookie) => response.add_header(HEADER_SET_COOKIE, cookie.to_string()),
+            Err(error) => warn!("Not setting the session cookie: {}", error),
+        }
+    }
+
+    fn session_cookie(&self, id: &str) -> Result<Cookie, CookieError> {
+        let cookie = Cookie::builder(self.cookie_name.clone(), id)
+            .path("/")
+            .max_age(self.ttl.as_secs() as i64)
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .build()?;
+
+        Ok(self.keys.sign(cookie))
+    }
+
+    fn removal_cookie(&self) -> Result<Cookie, CookieError> {
+        Cookie::builder(self.cookie_name.clone(), "")
+            .path("/")
+            .max_age(0)
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .build()
+    }
+}
+
+impl HttpHandler for SessionMiddleware {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+
+        let session = self.load_session(request);
+
+        let mut request = request.clone();
+        request.set_session(session.clone());
+
+        let mut response = self.handler.lock().unwrap().handle(&request);
+
+        self.save_session(&session, &mut response);
+
+        response
+    }
+
+    fn supports_method(&self, method: &Method) -> bool {
+        self.handler.lock().unwrap().supports_method(method)
+    }
+
+    fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        self.handler.lock().unwrap().allowed_methods(path)
+    }
+
+    fn expect_continue(&mut self, request: &HttpRequest) -> Option<HttpResponse> {
+        self.handler.lock().unwrap().expect_continue(request)
+    }
+
+    fn stream_body(&self, request: &HttpRequest) -> bool {
+        self.handler.lock().unwrap().stream_body(request)
+    }
+
+    fn fork(&self) -> Option<Box<dyn HttpHandler>> {
+        let handler = self.handler.lock().unwrap().fork()?;
+        Some(Box::new(SessionMiddleware {
+            handler: Arc::new(Mutex::new(handler)),
+            store: self.store.clone(),
+            keys: self.keys.clone(),
+            cookie_name: self.cookie_name.clone(),
+            ttl: self.ttl,
+            secure: self.secure,
+        }))
+    }
+}
+
+pub struct SessionMiddlewareBuilder {
+    handler: Arc<Mutex<dyn HttpHandler>>,
+    store: Arc<dyn SessionStore>,
+    keys: CookieKeys,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+}
+
+impl SessionMiddlewareBuilder {
+
+    pub fn store(&mut self, store: Arc<dyn SessionStore>) -> &mut Self {
+        self.store = store;
+        self
+    }
+
+    pub fn cookie_name<N>(&mut self, cookie_name: N) -> &mut Self where N: Into<String> {
+        self.cookie_name = cookie_name.into();
+        self
+    }
+
+    pub fn ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn secure(&mut self, secure: bool) -> &mut Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn build(&self) -> SessionMiddleware {
+        SessionMiddleware {
+            handler: self.handler.clone(),
+            store: self.store.clone(),
+            keys: self.keys.clone(),
+            cookie_name: self.cookie_name.clone(),
+            ttl: self.ttl,
+            secure: self.secure,
+        }
+    }
+}
+
+fn generate_session_id() -> String {
+    let mut id = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut id);
+    URL_SAFE_NO_PAD.encode(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::cookie::CookieKey;
+    use crate::http::{HEADER_COOKIE, Status};
+
+    // Counts visits in the session, /login regenerates it and /logout destroys it
+    struct Visits;
+
+    impl HttpHandler for Visits {
+        fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+            let session = request.session().unwrap();
+            match request.path() {
+                "/login" => session.regenerate(),
+                "/logout" => session.destroy(),
+                _ => {
+                    let visits = session.get("visits").map_or(0, |visits| visits.parse().unwrap()) + 1;
+                    session.insert("visits", visits.to_string());
+                }
+            }
+
+            let body = session.get("visits").unwrap_or_default();
+            HttpResponse::new(Status::Ok, vec![], Some(body.into_bytes()))
+        }
+    }
+
+    fn middleware(ttl: Duration) -> SessionMiddleware {
+        SessionMiddleware::builder(Arc::new(Mutex::new(Visits)), CookieKeys::new(CookieKey::from_secret(b"secret")))
+            .ttl(ttl)
+            .build()
+    }
+
+    // Returns the body and the session cookie set in the response, as "name=value"
+    fn visit(middleware: &mut SessionMiddleware, path: &str, cookie: Option<&str>) -> (String, Option<String>) {
+        let mut builder = HttpRequest::builder();
+        builder.path(path);
+        if let Some(cookie) = cookie {
+            builder.header(HEADER_COOKIE, cookie);
+        }
+
+        let response = middleware.handle(&builder.build());
+        let body = String::from_utf8(response.body().unwrap().to_vec()).unwrap();
+        let cookie = response.header(HEADER_SET_COOKIE).map(|cookie| cookie.split(';').next().unwrap().to_string());
+        (body, cookie)
+    }
+
+    #[test]
+    fn sessions_are_read_back_through_the_signed_cookie() {
+        let mut middleware = middleware(DEFAULT_SESSION_TTL);
+
+        let (visits, cookie) = visit(&mut middleware, "/", None);
+        assert_eq!(visits, "1");
+        let cookie = cookie.unwrap();
+        assert!(cookie.starts_with("libhttp.sid="));
+
+        let (visits, _) = visit(&mut middleware, "/", Some(&cookie));
+        assert_eq!(visits, "2");
+        let (visits, _) = visit(&mut middleware, "/", Some(&cookie));
+        assert_eq!(visits, "3");
+    }
+
+    #[test]
+    fn regenerating_replaces_the_id_and_invalidates_the_old_one() {
+        let mut middleware = middleware(DEFAULT_SESSION_TTL);
+        let (_, cookie) = visit(&mut middleware, "/", None);
+        let old = cookie.unwrap();
+
+        let (visits, cookie) = visit(&mut middleware, "/login", Some(&old));
+        let new = cookie.unwrap();
+        assert_eq!(visits, "1");
+        assert_ne!(new, old);
+
+        // the data moves to the new id
+        assert_eq!(visit(&mut middleware, "/", Some(&new)).0, "2");
+        assert_eq!(visit(&mut middleware, "/", Some(&old)).0, "1");
+    }
+
+    #[test]
+    fn destroying_clears_the_cookie_and_the_session() {
+        let mut middleware = middleware(DEFAULT_SESSION_TTL);
+        let (_, cookie) = visit(&mut middleware, "/", None);
+        let cookie = cookie.unwrap();
+
+        let mut builder = HttpRequest::builder();
+        builder.path("/logout").header(HEADER_COOKIE, &cookie);
+        let response = middleware.handle(&builder.build());
+        let removal = response.header(HEADER_SET_COOKIE).unwrap();
+        assert!(removal.starts_with("libhttp.sid=;"), "{}", removal);
+        assert!(removal.contains("Max-Age=0"), "{}", removal);
+
+        assert_eq!(visit(&mut middleware, "/", Some(&cookie)).0, "1");
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let mut middleware = middleware(Duration::from_millis(50));
+        let (_, cookie) = visit(&mut middleware, "/", None);
+        let cookie = cookie.unwrap();
+        assert_eq!(visit(&mut middleware, "/", Some(&cookie)).0, "2");
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(visit(&mut middleware, "/", Some(&cookie)).0, "1");
+    }
+
+    #[test]
+    fn tampered_or_unknown_cookies_start_a_fresh_session() {
+        let mut middleware = middleware(DEFAULT_SESSION_TTL);
+        let (_, cookie) = visit(&mut middleware, "/", None);
+        let cookie = cookie.unwrap();
+        assert_eq!(visit(&mut middleware, "/", Some(&cookie)).0, "2");
+
+        // a changed id no longer matches its signature
+        let (name, value) = cookie.split_once('=').unwrap();
+        let first = if value.starts_with('x') { 'y' } else { 'x' };
+        let tampered = format!("{}={}{}", name, first, &value[1..]);
+        let (visits, fresh) = visit(&mut middleware, "/", Some(&tampered));
+        assert_eq!(visits, "1");
+        assert_ne!(fresh.unwrap(), cookie);
+
+        // as does one signed with another key
+        let other = CookieKeys::new(CookieKey::from_secret(b"other")).sign(Cookie::new(name, value.rsplit_once('.').unwrap().0).unwrap());
+        assert_eq!(visit(&mut middleware, "/", Some(&other.to_string())).0, "1");
+
+        // and a properly signed id the store doesn't know
+        let unknown = middleware.keys.sign(Cookie::new(name, "unknown").unwrap());
+        assert_eq!(visit(&mut middleware, "/", Some(&unknown.to_string())).0, "1");
+
+        // none of which touched the real session
+        assert_eq!(visit(&mut middleware, "/", Some(&cookie)).0, "3");
+    }
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use libhttp::auth::{AuthMiddleware, AuthVerifier, DigestAlgorithm, DigestCredentials};
+use libhttp::http::{Method, Status};
+use libhttp::message::{HttpRequest, HttpResponse};
+use libhttp::server::{HttpHandler, HttpServer};
+
+const REALM: &str = "http-auth@example.org";
+
+// Answers with the method it was called with
+struct Hello;
+
+impl HttpHandler for Hello {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        HttpResponse::new(Status::Ok, vec![], Some(request.method().to_string().into_bytes()))
+    }
+}
+
+struct Mufasa;
+
+impl AuthVerifier for Mufasa {
+    fn digest_password(&self, username: &str, _realm: &str) -> Option<String> {
+        (username == "Mufasa").then(|| "Circle of Life".to_string())
+    }
+}
+
+fn server() -> SocketAddr {
+    let auth = AuthMiddleware::builder(Arc::new(Mutex::new(Hello)), Arc::new(Mufasa))
+        .realm(REALM)
+        .digest(DigestAlgorithm::Sha256)
+        .build();
+    common::start(HttpServer::builder().handler(Arc::new(Mutex::new(auth))))
+}
+
+// The Authorization header a client would send for a request with `method`
+fn authorization(nonce: &str, method: &str, nc: u32) -> String {
+    let params = format!(
+        r#"username="Mufasa", realm="{}", uri="/dir/index.html", algorithm=SHA-256, nonce="{}", nc={:08x}, cnonce="f2/wE4q74E6zIJEtWaHKaf5wv", qop=auth"#,
+        REALM, nonce, nc
+    );
+    let credentials = DigestCredentials::parse(&format!(r#"{}, response="""#, params)).unwrap();
+    let response = credentials.expected_response(method, "Circle of Life").unwrap();
+    format!(r#"Digest {}, response="{}""#, params, response)
+}
+
+#[test]
+fn digest_covers_the_method_the_client_sent() {
+    let addr = server();
+    let (mut stream, mut reader) = common::connect(addr);
+
+    stream.write_all(b"HEAD /dir/index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let challenge = common::read_response(&mut reader, &Method::Head);
+    assert_eq!(challenge.status, Status::Unauthorized);
+    let challenge = challenge.header("WWW-Authenticate").unwrap();
+    let nonce = challenge.split("nonce=\"").nth(1).unwrap().split('"').next().unwrap();
+
+    // the server answers HEAD as GET, but the client signed HEAD
+    let request = format!("HEAD /dir/index.html HTTP/1.1\r\nHost: localhost\r\nAuthorization: {}\r\n\r\n", authorization(nonce, "HEAD", 1));
+    stream.write_all(request.as_bytes()).unwrap();
+    let response = common::read_response(&mut reader, &Method::Head);
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(response.header("Content-Length"), Some("3"));
+
+    // so a response computed for GET doesn't fit
+    let request = format!("HEAD /dir/index.html HTTP/1.1\r\nHost: localhost\r\nAuthorization: {}\r\n\r\n", authorization(nonce, "GET", 2));
+    stream.write_all(request.as_bytes()).unwrap();
+    let response = common::read_response(&mut reader, &Method::Head);
+    assert_eq!(response.status, Status::Unauthorized);
+}
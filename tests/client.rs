@@ -0,0 +1,182 @@
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use libhttp::client::{Client, ClientError};
+use libhttp::http::{Header, Status};
+use libhttp::message::{HttpRequest, HttpResponse};
+use libhttp::server::{HttpHandler, HttpServer};
+
+// Redirects /<code>/<target> with that status, and describes any other request
+// or one with a query
+struct Redirects;
+
+impl HttpHandler for Redirects {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        let mut segments = request.path().trim_start_matches('/').splitn(2, '/');
+        if let (Some(code), Some(target), false) = (segments.next(), segments.next(), request.path().contains('?')) {
+            if let Ok(code) = code.parse::<u16>() {
+                let status = Status::from_u16(code);
+                return HttpResponse::new(status, vec![Header::new("Location", target.replace("%2F", "/").replace("%3F", "?"))], None);
+            }
+        }
+
+        let description = format!("{} {} body={} type={} auth={}",
+            request.method(),
+            request.path(),
+            request.body.as_ref().map_or(0, Vec::len),
+            request.header("Content-Type").unwrap_or("-"),
+            request.header("Authorization").unwrap_or("-"));
+        HttpResponse::new(Status::Ok, vec![], Some(description.into_bytes()))
+    }
+
+    fn supports_method(&self, _method: &libhttp::http::Method) -> bool {
+        true
+    }
+}
+
+fn server() -> SocketAddr {
+    common::start(HttpServer::builder().handler(Arc::new(Mutex::new(Redirects))))
+}
+
+fn body(response: HttpResponse) -> String {
+    String::from_utf8(response.body().unwrap().clone()).unwrap()
+}
+
+#[test]
+fn redirects_rewrite_the_method_where_required() {
+    let addr = server();
+    let client = Client::new();
+    let url = |path: &str| format!("http://{}{}", addr, path);
+
+    let post = |path: &str| client.post(url(path)).header("Content-Type", "text/plain").body(b"hello".to_vec()).send().unwrap();
+
+    // 303 always continues as GET, 301 and 302 only after a POST
+    assert_eq!(body(post("/303/%2Fdone")), "GET /done body=0 type=- auth=-");
+    assert_eq!(body(post("/301/%2Fdone")), "GET /done body=0 type=- auth=-");
+    assert_eq!(body(post("/302/%2Fdone")), "GET /done body=0 type=- auth=-");
+
+    // 307 and 308 repeat the request as it was
+    assert_eq!(body(post("/307/%2Fdone")), "POST /done body=5 type=text/plain auth=-");
+    assert_eq!(body(post("/308/%2Fdone")), "POST /done body=5 type=text/plain auth=-");
+
+    let put = client.put(url("/301/%2Fdone")).body(b"hello".to_vec()).send().unwrap();
+    assert_eq!(body(put), "PUT /done body=5 type=- auth=-");
+
+    // relative locations resolve against the redirecting url
+    assert_eq!(body(client.get(url("/302/done%3Ffinal")).send().unwrap()), "GET /302/done?final body=0 type=- auth=-");
+}
+
+#[test]
+fn credentials_stay_with_their_host() {
+    let first = server();
+    let second = server();
+    let client = Client::new();
+
+    let same_host = client.get(format!("http://{}/302/%2Fhere", first)).header("Authorization", "Bearer secret").send().unwrap();
+    assert_eq!(body(same_host), "GET /here body=0 type=- auth=Bearer secret");
+
+    let location = format!("http:%2F%2F{}%2Fthere", second);
+    let other_host = client.get(format!("http://{}/302/{}", first, location)).header("Authorization", "Bearer secret").send().unwrap();
+    assert_eq!(body(other_host), "GET /there body=0 type=- auth=-");
+}
+
+#[test]
+fn redirect_limit_is_enforced() {
+    let addr = server();
+
+    let client = Client::builder().max_redirects(2).build();
+    // redirects to itself
+    let result = client.get(format!("http://{}/302/loop", addr)).send();
+    assert!(matches!(result, Err(ClientError::TooManyRedirects)));
+
+    let client = Client::builder().max_redirects(0).build();
+    let response = client.get(format!("http://{}/302/%2Fdone", addr)).send().unwrap();
+    assert_eq!(response.status, Status::Found);
+}
+
+// Answers one request per connection, then closes it despite keeping it alive
+fn closing_server() -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    let accepted = connections.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            accepted.fetch_add(1, Ordering::SeqCst);
+
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+        }
+    });
+
+    (addr, connections)
+}
+
+#[test]
+fn stale_pooled_connections_are_replaced_for_idempotent_requests() {
+    let (addr, connections) = closing_server();
+    let client = Client::new();
+    let url = format!("http://{}/", addr);
+
+    for _ in 0..3 {
+        assert_eq!(body(client.get(&url).send().unwrap()), "ok");
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn stale_pooled_connections_fail_non_idempotent_requests() {
+    let (addr, _) = closing_server();
+    let client = Client::new();
+    let url = format!("http://{}/", addr);
+
+    assert_eq!(body(client.get(&url).send().unwrap()), "ok");
+
+    // the server may have seen the POST, so it isn't repeated
+    let result = client.post(&url).body(b"once".to_vec()).send();
+    assert!(matches!(result, Err(ClientError::Io(_))), "{:?}", result);
+
+    // the dead connection is gone from the pool
+    assert_eq!(body(client.post(&url).body(b"once".to_vec()).send().unwrap()), "ok");
+}
+
+#[test]
+fn response_bodies_are_limited() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let responses: [&[u8]; 3] = [
+            b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nhello world",
+        ];
+        for (stream, response) in listener.incoming().zip(responses.iter().cycle()) {
+            let mut stream = stream.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(response);
+        }
+    });
+
+    let url = format!("http://{}/", addr);
+    let limited = Client::builder().max_body_size(10).max_idle_per_host(0).build();
+    for _ in 0..3 {
+        assert!(matches!(limited.get(&url).send(), Err(ClientError::BodyTooLarge)));
+    }
+
+    let fits = Client::builder().max_body_size(11).max_idle_per_host(0).build();
+    for _ in 0..3 {
+        assert_eq!(body(fits.get(&url).send().unwrap()), "hello world");
+    }
+}
+
@@ -0,0 +1,45 @@
+#![allow(dead_code)]
+
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use libhttp::decoder::ResponseHead;
+use libhttp::http::Method;
+use libhttp::message::HttpResponse;
+use libhttp::server::HttpServerBuilder;
+
+// Starts the server on a free loopback port and waits until it accepts connections
+pub fn start(builder: &mut HttpServerBuilder) -> SocketAddr {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = builder.hostname("127.0.0.1").port(port).build();
+    thread::spawn(move || server.start());
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    for _ in 0..200 {
+        if TcpStream::connect(addr).is_ok() {
+            return addr;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("server did not start on {}", addr);
+}
+
+pub fn connect(addr: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    (stream, reader)
+}
+
+pub fn read_response(reader: &mut BufReader<TcpStream>, method: &Method) -> HttpResponse {
+    let head = ResponseHead::read(reader).unwrap();
+    HttpResponse::decode(head, reader, method).unwrap()
+}
+
+// Sends a raw request and reads the response to it
+pub fn exchange(addr: SocketAddr, request: &[u8]) -> HttpResponse {
+    let (mut stream, mut reader) = connect(addr);
+    stream.write_all(request).unwrap();
+    read_response(&mut reader, &Method::Get)
+}
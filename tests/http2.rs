@@ -0,0 +1,412 @@
+mod common;
+
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::time::Duration;
+use libhttp::hpack;
+use libhttp::http::Status;
+use libhttp::http2::PREFACE;
+use libhttp::message::{BodyStream, HttpRequest, HttpResponse};
+use libhttp::server::{HttpHandler, HttpServer, HttpServerBuilder};
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+
+// The window a stream starts with
+const WINDOW_SIZE: usize = 65_535;
+
+// Describes the request it got, or streams a body of the size in /stream/<size>
+struct Echo;
+
+impl HttpHandler for Echo {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        if let Some(size) = request.path().strip_prefix("/stream/") {
+            let body = vec![b'x'; size.parse().unwrap()];
+            return HttpResponse::streaming(Status::Ok, vec![], BodyStream::new(Cursor::new(body)));
+        }
+
+        let description = format!("{} {} body={}", request.method(), request.path(), request.body.as_ref().map_or(0, Vec::len));
+        HttpResponse::new(Status::Ok, vec![], Some(description.into_bytes()))
+    }
+}
+
+fn server(builder: &mut HttpServerBuilder) -> SocketAddr {
+    common::start(builder.http2(true).handler(Arc::new(Mutex::new(Echo))))
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+// How a stream ended
+#[derive(Debug)]
+enum Outcome {
+    Response(Vec<(String, String)>, Vec<u8>),
+    Reset(u32),
+}
+
+// Just enough of an HTTP/2 client to talk to the server frame by frame
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    decoder: hpack::Decoder,
+}
+
+impl Client {
+
+    // Connects with prior knowledge
+    fn connect(addr: SocketAddr) -> Self {
+        let (stream, reader) = common::connect(addr);
+        Client::start(stream, reader)
+    }
+
+    // Sends the preface on a connection that speaks HTTP/2 from here on
+    fn start(mut stream: TcpStream, reader: BufReader<TcpStream>) -> Self {
+        stream.write_all(PREFACE).unwrap();
+        let mut client = Client { stream, reader, decoder: hpack::Decoder::new(4096, usize::MAX) };
+        client.send(SETTINGS, 0, 0, &[]);
+        client
+    }
+
+    fn send(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+        let length = (payload.len() as u32).to_be_bytes();
+        let mut frame = vec![length[1], length[2], length[3], kind, flags];
+        frame.extend(stream_id.to_be_bytes());
+        frame.extend(payload);
+        self.stream.write_all(&frame).unwrap();
+    }
+
+    fn send_headers(&mut self, stream_id: u32, fields: &[(&str, &str)], end_stream: bool) {
+        let flags = FLAG_END_HEADERS | if end_stream { FLAG_END_STREAM } else { 0 };
+        self.send(HEADERS, flags, stream_id, &hpack::encode(fields.iter().copied()));
+    }
+
+    fn get(&mut self, stream_id: u32, path: &str) {
+        self.send_headers(stream_id, &[(":method", "GET"), (":scheme", "http"), (":path", path), (":authority", "localhost")], true);
+    }
+
+    // Sends a body in frames as large as the server's windows allow, ending the stream
+    fn send_body(&mut self, stream_id: u32, body: &[u8]) {
+        let mut connection_window = WINDOW_SIZE;
+        let mut stream_window = WINDOW_SIZE;
+
+        for chunk in body.chunks(16_384) {
+            while connection_window.min(stream_window) < chunk.len() {
+                let frame = self.read_frame();
+                if frame.kind == WINDOW_UPDATE {
+                    let increment = u32::from_be_bytes(frame.payload[..4].try_into().unwrap()) as usize;
+                    match frame.stream_id {
+                        0 => connection_window += increment,
+                        id if id == stream_id => stream_window += increment,
+                        _ => {}
+                    }
+                }
+            }
+
+            self.send(DATA, 0, stream_id, chunk);
+            connection_window -= chunk.len();
+            stream_window -= chunk.len();
+        }
+        self.send(DATA, FLAG_END_STREAM, stream_id, &[]);
+    }
+
+    fn read_frame(&mut self) -> Frame {
+        let mut header = [0u8; 9];
+        self.reader.read_exact(&mut header).unwrap();
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let mut payload = vec![0u8; length];
+        self.reader.read_exact(&mut payload).unwrap();
+
+        Frame {
+            kind: header[3],
+            flags: header[4],
+            stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+            payload,
+        }
+    }
+
+    // Reads frames until the stream ends, skipping those of the connection
+    fn outcome(&mut self, stream_id: u32) -> Outcome {
+        let mut fields = Vec::new();
+        let mut body = Vec::new();
+
+        loop {
+            let frame = self.read_frame();
+            let code = || u32::from_be_bytes(frame.payload[..4].try_into().unwrap());
+            match frame.kind {
+                GOAWAY => panic!("the connection was closed"),
+                _ if frame.stream_id != stream_id => continue,
+                RST_STREAM => return Outcome::Reset(code()),
+                HEADERS => fields.extend(self.decoder.decode(&frame.payload).unwrap()),
+                DATA => {
+                    // the window is given back right away, so the body can't stall
+                    body.extend(&frame.payload);
+                    if !frame.payload.is_empty() {
+                        let increment = (frame.payload.len() as u32).to_be_bytes();
+                        self.send(WINDOW_UPDATE, 0, 0, &increment);
+                        self.send(WINDOW_UPDATE, 0, stream_id, &increment);
+                    }
+                }
+                _ => {}
+            }
+            if frame.flags & FLAG_END_STREAM != 0 {
+                return Outcome::Response(fields, body);
+            }
+        }
+    }
+}
+
+fn status(fields: &[(String, String)]) -> &str {
+    fields.iter().find(|(name, _)| name == ":status").map(|(_, value)| value.as_str()).unwrap()
+}
+
+// Reads the response to a request, failing on anything else
+fn response(outcome: Outcome) -> (String, Vec<u8>) {
+    match outcome {
+        Outcome::Response(fields, body) => (status(&fields).to_string(), body),
+        outcome => panic!("{:?}", outcome),
+    }
+}
+
+// An HPACK integer with the given prefix size and flag bits (RFC 7541 5.1)
+fn hpack_integer(mut value: usize, prefix: u8, flags: u8) -> Vec<u8> {
+    let mask = (1usize << prefix) - 1;
+    if value < mask {
+        return vec![flags | value as u8];
+    }
+
+    let mut bytes = vec![flags | mask as u8];
+    value -= mask;
+    while value >= 0x80 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+    bytes
+}
+
+// A literal field with a new name that is added to the dynamic table
+fn hpack_indexed_literal(name: &str, value: &str) -> Vec<u8> {
+    let mut field = vec![0x40];
+    for string in [name, value] {
+        field.extend(hpack_integer(string.len(), 7, 0x00));
+        field.extend(string.as_bytes());
+    }
+    field
+}
+
+#[test]
+fn requests_are_served_with_prior_knowledge() {
+    let addr = server(&mut HttpServer::builder());
+    let mut client = Client::connect(addr);
+
+    client.get(1, "/first");
+    assert_eq!(response(client.outcome(1)), ("200".to_string(), b"GET /first body=0".to_vec()));
+
+    // a body larger than the initial window only arrives as the handler reads it
+    let body = vec![b'a'; 3 * WINDOW_SIZE];
+    client.send_headers(3, &[(":method", "POST"), (":scheme", "http"), (":path", "/upload"), (":authority", "localhost")], false);
+    client.send_body(3, &body);
+    assert_eq!(response(client.outcome(3)), ("200".to_string(), format!("POST /upload body={}", body.len()).into_bytes()));
+}
+
+#[test]
+fn data_in_the_frame_ending_the_stream_reaches_the_handler() {
+    let addr = server(&mut HttpServer::builder());
+    let mut client = Client::connect(addr);
+
+    client.send_headers(1, &[(":method", "POST"), (":scheme", "http"), (":path", "/form"), (":authority", "localhost"), ("content-length", "11")], false);
+    client.send(DATA, FLAG_END_STREAM, 1, b"hello world");
+    assert_eq!(response(client.outcome(1)), ("200".to_string(), b"POST /form body=11".to_vec()));
+
+    // and after earlier frames of the same body
+    client.send_headers(3, &[(":method", "POST"), (":scheme", "http"), (":path", "/form"), (":authority", "localhost")], false);
+    client.send(DATA, 0, 3, b"hello");
+    client.send(DATA, FLAG_END_STREAM, 3, b" world");
+    assert_eq!(response(client.outcome(3)), ("200".to_string(), b"POST /form body=11".to_vec()));
+}
+
+#[test]
+fn requests_are_served_after_an_h2c_upgrade() {
+    let addr = server(&mut HttpServer::builder());
+    let (mut stream, mut reader) = common::connect(addr);
+
+    stream.write_all(b"GET /upgraded HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk\r\n\r\n").unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("HTTP/1.1 101 "), "{}", line);
+    while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+
+    // the upgraded request is answered on stream 1
+    let mut client = Client::start(stream, reader);
+    assert_eq!(response(client.outcome(1)), ("200".to_string(), b"GET /upgraded body=0".to_vec()));
+
+    client.get(3, "/next");
+    assert_eq!(response(client.outcome(3)), ("200".to_string(), b"GET /next body=0".to_vec()));
+}
+
+#[test]
+fn streamed_responses_follow_the_window() {
+    let addr = server(&mut HttpServer::builder());
+    let mut client = Client::connect(addr);
+
+    let size = 4 * WINDOW_SIZE;
+    client.get(1, &format!("/stream/{}", size));
+    let (status, body) = response(client.outcome(1));
+    assert_eq!(status, "200");
+    assert_eq!(body, vec![b'x'; size]);
+}
+
+#[test]
+fn header_lists_over_the_limit_are_refused() {
+    let addr = server(&mut HttpServer::builder());
+    let mut client = Client::connect(addr);
+    let request = [(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "localhost")];
+
+    // one large table entry named again and again
+    let mut block = hpack::encode(request);
+    block.extend(hpack_indexed_literal("x-large", &"a".repeat(4000)));
+    block.extend([0x80 | 62; 100]);
+    client.send(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, &block);
+    assert_eq!(response(client.outcome(1)).0, "431");
+
+    // the table is still in step with the client's
+    let mut block = hpack::encode(request);
+    block.push(0x80 | 62);
+    client.send(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 3, &block);
+    assert_eq!(response(client.outcome(3)), ("200".to_string(), b"GET / body=0".to_vec()));
+}
+
+#[test]
+fn bodies_over_the_limit_are_refused() {
+    let addr = server(HttpServer::builder().max_body_size(16));
+    let mut client = Client::connect(addr);
+
+    client.send_headers(1, &[(":method", "POST"), (":scheme", "http"), (":path", "/"), (":authority", "localhost")], false);
+    client.send(DATA, 0, 1, &[b'a'; 32]);
+    assert_eq!(response(client.outcome(1)).0, "413");
+}
+
+// Streams the body but doesn't read it until released
+struct Stalled(Mutex<mpsc::Receiver<()>>);
+
+impl HttpHandler for Stalled {
+    fn handle(&mut self, _request: &HttpRequest) -> HttpResponse {
+        let _ = self.0.lock().unwrap().recv_timeout(Duration::from_secs(10));
+        HttpResponse::new(Status::Ok, vec![], None)
+    }
+
+    fn stream_body(&self, _request: &HttpRequest) -> bool {
+        true
+    }
+}
+
+#[test]
+fn data_beyond_the_window_resets_the_stream() {
+    let (release, released) = mpsc::channel();
+    let addr = common::start(HttpServer::builder().http2(true).handler(Arc::new(Mutex::new(Stalled(Mutex::new(released))))));
+    let mut client = Client::connect(addr);
+
+    client.send_headers(1, &[(":method", "POST"), (":scheme", "http"), (":path", "/"), (":authority", "localhost")], false);
+    for _ in 0..WINDOW_SIZE / 16_384 + 1 {
+        client.send(DATA, 0, 1, &[b'a'; 16_384]);
+    }
+    assert!(matches!(client.outcome(1), Outcome::Reset(FLOW_CONTROL_ERROR)));
+    drop(release);
+}
+
+#[test]
+fn malformed_fields_reset_the_stream() {
+    let addr = server(&mut HttpServer::builder());
+    let mut client = Client::connect(addr);
+
+    let request = [(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "localhost")];
+    let malformed: [(&str, &str); 7] = [
+        ("x-injected", "a\r\nx-smuggled: 1"),
+        ("x-injected", "a\nb"),
+        ("x-injected", "a\0b"),
+        ("x-padded", " a"),
+        ("x injected", "a"),
+        ("X-Upper", "a"),
+        ("", "a"),
+    ];
+
+    let mut stream_id = 1;
+    for field in malformed {
+        client.send_headers(stream_id, &[&request[..], &[field]].concat(), true);
+        assert!(matches!(client.outcome(stream_id), Outcome::Reset(PROTOCOL_ERROR)), "{:?}", field);
+        stream_id += 2;
+    }
+
+    // pseudo-headers end up in the request line
+    client.get(stream_id, "/ HTTP/1.1\r\nx-smuggled: 1");
+    assert!(matches!(client.outcome(stream_id), Outcome::Reset(PROTOCOL_ERROR)));
+    stream_id += 2;
+
+    // the connection is still fine
+    client.get(stream_id, "/fine");
+    match client.outcome(stream_id) {
+        Outcome::Response(fields, body) => {
+            assert_eq!(status(&fields), "200");
+            assert_eq!(body, b"GET /fine body=0");
+        }
+        outcome => panic!("{:?}", outcome),
+    }
+}
+
+#[test]
+fn malformed_trailers_reset_the_stream() {
+    let addr = server(&mut HttpServer::builder());
+    let mut client = Client::connect(addr);
+
+    client.send_headers(1, &[(":method", "POST"), (":scheme", "http"), (":path", "/"), (":authority", "localhost")], false);
+    client.send(DATA, 0, 1, b"hello");
+    client.send_headers(1, &[("x-checksum", "1\r\nx-smuggled: 1")], true);
+    assert!(matches!(client.outcome(1), Outcome::Reset(PROTOCOL_ERROR)));
+}
+
+// Reads the body as it arrives and describes it with its trailers
+struct StreamedTrailers;
+
+impl HttpHandler for StreamedTrailers {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        let mut body = Vec::new();
+        request.body_stream().unwrap().clone().read_to_end(&mut body).unwrap();
+        let trailers: Vec<String> = request.trailers().iter().map(|trailer| format!("{}={}", trailer.key(), trailer.value())).collect();
+        let description = format!("body={} trailers={}", body.len(), trailers.join(","));
+        HttpResponse::new(Status::Ok, vec![], Some(description.into_bytes()))
+    }
+
+    fn stream_body(&self, _request: &HttpRequest) -> bool {
+        true
+    }
+}
+
+#[test]
+fn trailers_of_a_streamed_body_reach_the_handler() {
+    let addr = common::start(HttpServer::builder().http2(true).handler(Arc::new(Mutex::new(StreamedTrailers))));
+    let mut client = Client::connect(addr);
+
+    client.send_headers(1, &[(":method", "POST"), (":scheme", "http"), (":path", "/"), (":authority", "localhost")], false);
+    client.send(DATA, 0, 1, b"hello");
+    client.send_headers(1, &[("checksum", "abc")], true);
+    assert_eq!(response(client.outcome(1)), ("200".to_string(), b"body=5 trailers=checksum=abc".to_vec()));
+}
@@ -0,0 +1,145 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use libhttp::http::{Header, Method, Status};
+use libhttp::message::{HttpRequest, HttpResponse};
+use libhttp::server::{allowed_methods, HttpHandler, HttpServer, HttpServerBuilder};
+
+// Describes the request it got, with the status in /status/<code>, and
+// serves WebDAV's PROPFIND as well
+struct Resources;
+
+impl HttpHandler for Resources {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        let status = request.path().strip_prefix("/status/").map_or(Status::Ok, |code| Status::from_u16(code.parse().unwrap()));
+        let description = format!("{} {}", request.method(), request.path());
+        HttpResponse::new(status, vec![Header::new("X-Method", request.method().as_str())], Some(description.into_bytes()))
+    }
+
+    fn supports_method(&self, method: &Method) -> bool {
+        match method {
+            Method::Extension(method) => method == "PROPFIND",
+            method => !matches!(method, Method::Head | Method::Options | Method::Trace),
+        }
+    }
+
+    // everything below /static is read-only
+    fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        if path.starts_with("/static/") {
+            vec![Method::Get, Method::Head, Method::Options]
+        } else {
+            allowed_methods(self)
+        }
+    }
+}
+
+fn server(builder: &mut HttpServerBuilder) -> SocketAddr {
+    common::start(builder.handler(Arc::new(Mutex::new(Resources))))
+}
+
+// Sends a request followed by a GET on the same connection and returns the
+// head of the first response and everything after it
+fn exchange_followed_by_get(addr: SocketAddr, request: &str) -> (String, String) {
+    let (mut stream, mut reader) = common::connect(addr);
+    stream.write_all(request.as_bytes()).unwrap();
+    stream.write_all(b"GET /next HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+    let mut response = String::new();
+    reader.read_to_string(&mut response).unwrap();
+    let (head, rest) = response.split_once("\r\n\r\n").unwrap();
+    (head.to_string(), rest.to_string())
+}
+
+#[test]
+fn extension_methods_reach_handlers_that_support_them() {
+    let addr = server(&mut HttpServer::builder());
+
+    let response = common::exchange(addr, b"PROPFIND /docs HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(response.body().unwrap(), b"PROPFIND /docs");
+}
+
+#[test]
+fn unsupported_methods_are_not_implemented() {
+    let addr = server(&mut HttpServer::builder());
+
+    for request in [&b"PURGE /docs HTTP/1.1\r\nHost: localhost\r\n\r\n"[..], b"get /docs HTTP/1.1\r\nHost: localhost\r\n\r\n"] {
+        let response = common::exchange(addr, request);
+        assert_eq!(response.status, Status::NotImplemented, "{}", String::from_utf8_lossy(request));
+    }
+}
+
+#[test]
+fn method_names_that_are_not_tokens_are_bad_requests() {
+    let addr = server(&mut HttpServer::builder());
+
+    let response = common::exchange(addr, b"GE(T /docs HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.status, Status::BadRequest);
+}
+
+#[test]
+fn head_is_answered_as_get_without_the_body() {
+    let addr = server(&mut HttpServer::builder());
+
+    let (head, rest) = exchange_followed_by_get(addr, "HEAD /docs HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+    assert!(head.contains("\r\nX-Method: GET"), "{}", head);
+    // the length is the one a GET would have had
+    assert!(head.contains("\r\nContent-Length: 9"), "{}", head);
+    assert!(rest.starts_with("HTTP/1.1 200 "), "{}", rest);
+    assert!(rest.ends_with("GET /next"), "{}", rest);
+}
+
+#[test]
+fn no_content_and_not_modified_have_no_body() {
+    let addr = server(&mut HttpServer::builder());
+
+    let (head, rest) = exchange_followed_by_get(addr, "GET /status/204 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(head.starts_with("HTTP/1.1 204 "), "{}", head);
+    assert!(!head.contains("Content-Length"), "{}", head);
+    assert!(rest.starts_with("HTTP/1.1 200 "), "{}", rest);
+
+    let (head, rest) = exchange_followed_by_get(addr, "GET /status/304 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(head.starts_with("HTTP/1.1 304 "), "{}", head);
+    assert!(rest.starts_with("HTTP/1.1 200 "), "{}", rest);
+}
+
+#[test]
+fn options_lists_the_allowed_methods() {
+    let addr = server(&mut HttpServer::builder());
+
+    let response = common::exchange(addr, b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(response.header("Allow"), Some("GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS"));
+    assert_eq!(response.header("Content-Length"), Some("0"));
+
+    let response = common::exchange(addr, b"OPTIONS /static/logo.png HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+}
+
+#[test]
+fn trace_is_not_allowed_unless_enabled() {
+    let addr = server(&mut HttpServer::builder());
+
+    let response = common::exchange(addr, b"TRACE /docs HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.status, Status::MethodNotAllowed);
+    assert_eq!(response.header("Allow"), Some("GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS"));
+}
+
+#[test]
+fn trace_echoes_the_request_when_enabled() {
+    let addr = server(HttpServer::builder().trace(true));
+
+    let response = common::exchange(addr, b"TRACE /docs HTTP/1.1\r\nHost: localhost\r\nX-Hop: 1\r\nCookie: id=secret\r\nAuthorization: Basic c2VjcmV0\r\n\r\n");
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(response.header("Content-Type"), Some("message/http"));
+    // without the credentials
+    assert_eq!(response.body().unwrap(), b"TRACE /docs HTTP/1.1\r\nHost: localhost\r\nX-Hop: 1\r\n\r\n");
+
+    let response = common::exchange(addr, b"OPTIONS /static/logo.png HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS, TRACE"));
+}
@@ -0,0 +1,145 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::time::Duration;
+use libhttp::http::{Method, Status};
+use libhttp::message::{HttpRequest, HttpResponse};
+use libhttp::multipart::{Multipart, MultipartLimits};
+use libhttp::server::{HttpHandler, HttpServer};
+
+const BOUNDARY: &str = "xYzZy";
+
+// Reports every part it reads, as soon as it has read it
+struct Uploads {
+    seen: Mutex<mpsc::Sender<String>>,
+    limits: MultipartLimits,
+}
+
+impl HttpHandler for Uploads {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        let mut multipart = match Multipart::from_request(request, self.limits.clone()) {
+            Ok(multipart) => multipart,
+            Err(error) => return error.into(),
+        };
+
+        let mut summary = Vec::new();
+        loop {
+            let mut part = match multipart.next_part() {
+                Ok(Some(part)) => part,
+                Ok(None) => break,
+                Err(error) => return error.into(),
+            };
+
+            let mut size = 0;
+            let mut buffer = [0u8; 4096];
+            loop {
+                match part.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(count) => size += count,
+                    Err(_) => return HttpResponse::new(Status::PayloadTooLarge, vec![], None),
+                }
+            }
+
+            let line = format!("{}={}", part.name().unwrap_or_default(), size);
+            let _ = self.seen.lock().unwrap().send(line.clone());
+            summary.push(line);
+        }
+
+        let streamed = request.body.is_none() && request.body_stream().is_some();
+        summary.push(format!("streamed={}", streamed));
+        HttpResponse::new(Status::Ok, vec![], Some(summary.join("\n").into_bytes()))
+    }
+
+    fn stream_body(&self, _request: &HttpRequest) -> bool {
+        true
+    }
+}
+
+fn server(limits: MultipartLimits) -> (SocketAddr, mpsc::Receiver<String>) {
+    let (sender, receiver) = mpsc::channel();
+    let handler = Uploads { seen: Mutex::new(sender), limits };
+    let addr = common::start(HttpServer::builder().handler(Arc::new(Mutex::new(handler))));
+    (addr, receiver)
+}
+
+fn part(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut part = format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n", BOUNDARY, name).into_bytes();
+    part.extend_from_slice(data);
+    part.extend_from_slice(b"\r\n");
+    part
+}
+
+fn send_chunk(stream: &mut TcpStream, data: &[u8]) {
+    stream.write_all(format!("{:x}\r\n", data.len()).as_bytes()).unwrap();
+    stream.write_all(data).unwrap();
+    stream.write_all(b"\r\n").unwrap();
+}
+
+fn head(framing: &str) -> String {
+    format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary={}\r\n{}\r\n\r\n", BOUNDARY, framing)
+}
+
+#[test]
+fn handler_reads_parts_before_the_upload_ends() {
+    let (addr, seen) = server(MultipartLimits::default());
+    let (mut stream, mut reader) = common::connect(addr);
+
+    let first = part("first", b"hello");
+    let second = part("second", &vec![b'x'; 300_000]);
+    let end = format!("--{}--\r\n", BOUNDARY);
+
+    stream.write_all(head("Transfer-Encoding: chunked").as_bytes()).unwrap();
+    send_chunk(&mut stream, &first);
+
+    // a part is only complete once the next delimiter arrives
+    assert!(seen.recv_timeout(Duration::from_millis(300)).is_err());
+
+    // the first part is handled while the end of the body is still missing
+    send_chunk(&mut stream, &second);
+    assert_eq!(seen.recv_timeout(Duration::from_secs(5)).unwrap(), "first=5");
+
+    send_chunk(&mut stream, end.as_bytes());
+    stream.write_all(b"0\r\n\r\n").unwrap();
+    let response = common::read_response(&mut reader, &Method::Post);
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(response.body().unwrap(), b"first=5\nsecond=300000\nstreamed=true");
+}
+
+#[test]
+fn content_length_body_is_streamed_and_connection_reused() {
+    let (addr, _seen) = server(MultipartLimits::default());
+    let (mut stream, mut reader) = common::connect(addr);
+
+    let mut body = part("file", &vec![b'y'; 100_000]);
+    body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+
+    for _ in 0..2 {
+        stream.write_all(head(&format!("Content-Length: {}", body.len())).as_bytes()).unwrap();
+        stream.write_all(&body).unwrap();
+
+        let response = common::read_response(&mut reader, &Method::Post);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body().unwrap(), b"file=100000\nstreamed=true");
+    }
+}
+
+#[test]
+fn limits_apply_to_streamed_uploads() {
+    let limits = MultipartLimits { max_part_size: 1000, ..MultipartLimits::default() };
+    let (addr, _seen) = server(limits);
+    let (mut stream, mut reader) = common::connect(addr);
+
+    let mut body = part("file", &vec![b'z'; 200_000]);
+    body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+
+    stream.write_all(head(&format!("Content-Length: {}", body.len())).as_bytes()).unwrap();
+    // the server may stop reading once the handler gives up
+    let _ = stream.write_all(&body);
+
+    let response = common::read_response(&mut reader, &Method::Post);
+    assert_eq!(response.status, Status::PayloadTooLarge);
+    assert_eq!(response.header("Connection"), Some("close"));
+}
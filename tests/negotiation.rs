@@ -0,0 +1,48 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use libhttp::http::Status;
+use libhttp::message::{HttpRequest, HttpResponse};
+use libhttp::negotiation::Negotiation;
+use libhttp::server::{HttpHandler, HttpServer};
+
+// Negotiates without ever calling `apply`
+struct Greeting;
+
+impl HttpHandler for Greeting {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        let mut negotiation = Negotiation::new(request);
+        let language = match negotiation.language(&["en", "de"]) {
+            Ok(language) => language,
+            Err(error) => return error.into(),
+        };
+
+        let body = if request.path() == "/plain" {
+            "hello"
+        } else {
+            match (language, negotiation.media_type(&["text/plain", "text/html"])) {
+                ("de", Ok(_)) => "hallo",
+                (_, Ok(_)) => "hello",
+                (_, Err(error)) => return error.into(),
+            }
+        };
+        HttpResponse::new(Status::Ok, vec![], Some(body.as_bytes().to_vec()))
+    }
+}
+
+#[test]
+fn vary_lists_every_negotiated_header() {
+    let addr = common::start(HttpServer::builder().handler(Arc::new(Mutex::new(Greeting))));
+
+    let response = common::exchange(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Language: de\r\n\r\n");
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(response.body().unwrap(), b"hallo");
+    assert_eq!(response.header("Vary"), Some("Accept-Language, Accept"));
+
+    let response = common::exchange(addr, b"GET /plain HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.header("Vary"), Some("Accept-Language"));
+
+    let response = common::exchange(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept: image/png\r\n\r\n");
+    assert_eq!(response.status, Status::NotAcceptable);
+    assert_eq!(response.header("Vary"), Some("Accept-Language, Accept"));
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use libhttp::http::{Method, Status};
+use libhttp::message::{HttpRequest, HttpResponse};
+use libhttp::server::{HttpHandler, HttpServer};
+
+// Requests to /wait/<n> return once n requests are waiting at the same time
+#[derive(Clone, Default)]
+struct Rendezvous {
+    waiting: Arc<(Mutex<usize>, Condvar)>,
+    forks: bool,
+}
+
+impl HttpHandler for Rendezvous {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        if let Some(millis) = request.path().strip_prefix("/sleep/") {
+            thread::sleep(Duration::from_millis(millis.parse().unwrap()));
+        }
+
+        if let Some(count) = request.path().strip_prefix("/wait/") {
+            let count: usize = count.parse().unwrap();
+            let (waiting, arrived) = &*self.waiting;
+            let mut waiting = waiting.lock().unwrap();
+            *waiting += 1;
+            arrived.notify_all();
+
+            let (_waiting, timeout) = arrived.wait_timeout_while(waiting, Duration::from_secs(2), |waiting| *waiting < count).unwrap();
+            if timeout.timed_out() {
+                return HttpResponse::new(Status::ServiceUnavailable, vec![], None);
+            }
+        }
+
+        HttpResponse::new(Status::Ok, vec![], Some(request.path().as_bytes().to_vec()))
+    }
+
+    fn fork(&self) -> Option<Box<dyn HttpHandler>> {
+        self.forks.then(|| Box::new(self.clone()) as Box<dyn HttpHandler>)
+    }
+}
+
+fn server(forks: bool, pipeline_depth: usize) -> std::net::SocketAddr {
+    let handler = Rendezvous { forks, ..Rendezvous::default() };
+    common::start(HttpServer::builder().pipeline_depth(pipeline_depth).handler(Arc::new(Mutex::new(handler))))
+}
+
+fn get(path: &str) -> String {
+    format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)
+}
+
+#[test]
+fn pipelined_responses_keep_request_order() {
+    let addr = server(true, 4);
+    let (mut stream, mut reader) = common::connect(addr);
+
+    // the earliest request takes longest to answer
+    let paths = ["/sleep/300", "/sleep/150", "/sleep/0", "/wait/1"];
+    let requests: String = paths.iter().map(|path| get(path)).collect();
+    stream.write_all(requests.as_bytes()).unwrap();
+
+    for path in paths {
+        let response = common::read_response(&mut reader, &Method::Get);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body().unwrap(), path.as_bytes());
+    }
+}
+
+#[test]
+fn pipelined_requests_run_concurrently_when_the_handler_forks() {
+    let addr = server(true, 3);
+    let (mut stream, mut reader) = common::connect(addr);
+
+    stream.write_all([get("/wait/3"), get("/wait/3"), get("/wait/3")].concat().as_bytes()).unwrap();
+
+    for _ in 0..3 {
+        assert_eq!(common::read_response(&mut reader, &Method::Get).status, Status::Ok);
+    }
+}
+
+#[test]
+fn forked_handlers_serve_connections_concurrently() {
+    let addr = server(true, 1);
+
+    let clients: Vec<_> = (0..2)
+        .map(|_| thread::spawn(move || common::exchange(addr, get("/wait/2").as_bytes()).status))
+        .collect();
+    for client in clients {
+        assert_eq!(client.join().unwrap(), Status::Ok);
+    }
+}
+
+#[test]
+fn handlers_without_fork_run_one_at_a_time() {
+    let addr = server(false, 1);
+
+    let clients: Vec<_> = (0..2)
+        .map(|_| thread::spawn(move || common::exchange(addr, get("/wait/2").as_bytes()).status))
+        .collect();
+    let statuses: Vec<Status> = clients.into_iter().map(|client| client.join().unwrap()).collect();
+
+    // the first one gives up waiting before the second is let in
+    assert!(statuses.contains(&Status::ServiceUnavailable));
+}
+
+#[test]
+fn non_idempotent_requests_are_not_read_ahead() {
+    let addr = server(true, 4);
+    let (mut stream, mut reader) = common::connect(addr);
+
+    let post = "POST /sleep/100 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n";
+    stream.write_all([post, &get("/wait/1")].concat().as_bytes()).unwrap();
+
+    assert_eq!(common::read_response(&mut reader, &Method::Post).body().unwrap(), b"/sleep/100");
+    assert_eq!(common::read_response(&mut reader, &Method::Get).body().unwrap(), b"/wait/1");
+
+    // nothing else was sent back
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
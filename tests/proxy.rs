@@ -0,0 +1,147 @@
+mod common;
+
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use libhttp::forwarded::TrustedProxies;
+use libhttp::http::{Method, Status};
+use libhttp::message::{HttpRequest, HttpResponse};
+use libhttp::proxy::ReverseProxy;
+use libhttp::server::{HttpHandler, HttpServer};
+
+// Describes the request it got. Requests to /wait/<n> return once n requests
+// are waiting at the same time.
+#[derive(Clone, Default)]
+struct Upstream {
+    waiting: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl HttpHandler for Upstream {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        if let Some(count) = request.path().strip_prefix("/wait/") {
+            let count: usize = count.parse().unwrap();
+            let (waiting, arrived) = &*self.waiting;
+            let mut waiting = waiting.lock().unwrap();
+            *waiting += 1;
+            arrived.notify_all();
+
+            let (_waiting, timeout) = arrived.wait_timeout_while(waiting, Duration::from_secs(2), |waiting| *waiting < count).unwrap();
+            if timeout.timed_out() {
+                return HttpResponse::new(Status::ServiceUnavailable, vec![], None);
+            }
+        }
+
+        let body = request.body.as_deref().unwrap_or_default();
+        let description = format!("{} {} length={} checksum={} forwarded-for={}",
+            request.method(), request.path(), body.len(), checksum(body), request.header("X-Forwarded-For").unwrap_or("-"));
+
+        HttpResponse::new(Status::Created, vec![], Some(description.into_bytes()))
+    }
+
+    fn fork(&self) -> Option<Box<dyn HttpHandler>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+// Echoes the forwarding headers it got
+struct ForwardingHeaders;
+
+impl HttpHandler for ForwardingHeaders {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        let description = ["X-Forwarded-For", "X-Forwarded-Proto", "X-Forwarded-Host", "X-Forwarded-Port"].iter()
+            .map(|key| request.header(key).unwrap_or("-"))
+            .collect::<Vec<_>>()
+            .join(" | ");
+        HttpResponse::new(Status::Ok, vec![], Some(description.into_bytes()))
+    }
+}
+
+fn proxy_to(upstream: SocketAddr) -> SocketAddr {
+    let proxy = ReverseProxy::builder(upstream.to_string()).via("test-proxy").build();
+    common::start(HttpServer::builder().handler(Arc::new(Mutex::new(proxy))))
+}
+
+fn proxy() -> SocketAddr {
+    let upstream = common::start(HttpServer::builder().handler(Arc::new(Mutex::new(Upstream::default()))));
+    proxy_to(upstream)
+}
+
+fn checksum(body: &[u8]) -> u32 {
+    body.iter().fold(0u32, |sum, byte| sum.wrapping_mul(31).wrapping_add(*byte as u32))
+}
+
+#[test]
+fn relays_requests_and_responses() {
+    let addr = proxy();
+
+    let response = common::exchange(addr, b"GET /hello HTTP/1.1\r\nHost: example.org\r\n\r\n");
+    assert_eq!(response.status, Status::Created);
+    assert_eq!(response.body().unwrap(), b"GET /hello length=0 checksum=0 forwarded-for=127.0.0.1");
+    assert_eq!(response.header("Via"), Some("1.1 test-proxy"));
+}
+
+#[test]
+fn streams_request_bodies_upstream() {
+    let addr = proxy();
+    let (mut stream, mut reader) = common::connect(addr);
+    let body: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    let expected = format!("POST /upload length={} checksum={} forwarded-for=127.0.0.1", body.len(), checksum(&body));
+
+    // a known length is passed on
+    stream.write_all(format!("POST /upload HTTP/1.1\r\nHost: example.org\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes()).unwrap();
+    stream.write_all(&body).unwrap();
+    let response = common::read_response(&mut reader, &Method::Post);
+    assert_eq!(response.body().unwrap(), expected.as_bytes());
+
+    // a chunked body is chunked upstream as well, on the same client connection
+    stream.write_all(b"POST /upload HTTP/1.1\r\nHost: example.org\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap();
+    for piece in body.chunks(70_000) {
+        stream.write_all(format!("{:x}\r\n", piece.len()).as_bytes()).unwrap();
+        stream.write_all(piece).unwrap();
+        stream.write_all(b"\r\n").unwrap();
+    }
+    stream.write_all(b"0\r\n\r\n").unwrap();
+    let response = common::read_response(&mut reader, &Method::Post);
+    assert_eq!(response.body().unwrap(), expected.as_bytes());
+}
+
+#[test]
+fn proxies_requests_concurrently() {
+    let addr = proxy();
+
+    let clients: Vec<_> = (0..3)
+        .map(|_| thread::spawn(move || common::exchange(addr, b"GET /wait/3 HTTP/1.1\r\nHost: example.org\r\n\r\n").status))
+        .collect();
+    for client in clients {
+        assert_eq!(client.join().unwrap(), Status::Created);
+    }
+}
+
+#[test]
+fn unreachable_upstream_is_a_bad_gateway() {
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let addr = proxy_to(closed);
+
+    let response = common::exchange(addr, b"GET / HTTP/1.1\r\nHost: example.org\r\n\r\n");
+    assert_eq!(response.status, Status::BadGateway);
+}
+
+#[test]
+fn forwarding_headers_are_only_kept_from_trusted_proxies() {
+    let upstream = common::start(HttpServer::builder().handler(Arc::new(Mutex::new(ForwardingHeaders))));
+    let request = b"GET / HTTP/1.1\r\nHost: example.org\r\nX-Forwarded-For: 203.0.113.7\r\nX-Forwarded-Proto: https\r\nX-Forwarded-Host: spoofed.example\r\nX-Forwarded-Port: 1\r\n\r\n";
+
+    // spoofed values from an untrusted client are replaced
+    let addr = proxy_to(upstream);
+    let response = common::exchange(addr, request);
+    let expected = format!("127.0.0.1 | http | example.org | {}", addr.port());
+    assert_eq!(response.body().unwrap(), expected.as_bytes());
+
+    // a trusted proxy's are passed on, with the peer appended to the chain
+    let proxy = ReverseProxy::builder(upstream.to_string()).trusted_proxies(TrustedProxies::parse(&["127.0.0.1"]).unwrap()).build();
+    let addr = common::start(HttpServer::builder().handler(Arc::new(Mutex::new(proxy))));
+    let response = common::exchange(addr, request);
+    assert_eq!(response.body().unwrap(), b"203.0.113.7, 127.0.0.1 | https | spoofed.example | 1");
+}
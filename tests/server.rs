@@ -0,0 +1,249 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use libhttp::http::{Header, Method, Status};
+use libhttp::message::{HttpRequest, HttpResponse};
+use libhttp::server::{HttpHandler, HttpServer, HttpServerBuilder};
+
+// Describes the request it got
+struct Echo;
+
+impl HttpHandler for Echo {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        let description = format!("{} {} body={}", request.method(), request.path(), request.body.as_ref().map_or(0, Vec::len));
+        HttpResponse::new(Status::Ok, vec![], Some(description.into_bytes()))
+    }
+}
+
+fn server(builder: &mut HttpServerBuilder) -> SocketAddr {
+    common::start(builder.handler(Arc::new(Mutex::new(Echo))))
+}
+
+// Sends a raw request and returns everything the server sends back before closing
+fn exchange_raw(addr: SocketAddr, request: &[u8]) -> String {
+    let (mut stream, mut reader) = common::connect(addr);
+    stream.write_all(request).unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+    let mut response = Vec::new();
+    reader.read_to_end(&mut response).unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[test]
+fn http_1_0_and_1_1_are_served() {
+    let addr = server(&mut HttpServer::builder());
+
+    for version in ["HTTP/1.0", "HTTP/1.1"] {
+        let request = format!("GET /version {}\r\nHost: localhost\r\n\r\n", version);
+        let response = common::exchange(addr, request.as_bytes());
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body().unwrap(), b"GET /version body=0");
+    }
+}
+
+#[test]
+fn other_versions_are_not_supported() {
+    let addr = server(&mut HttpServer::builder());
+
+    for version in ["HTTP/0.9", "HTTP/1.2", "HTTP/2.0", "HTTP/3.0"] {
+        let request = format!("GET / {}\r\nHost: localhost\r\n\r\n", version);
+        let response = exchange_raw(addr, request.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 505 "), "{}: {}", version, response);
+        assert!(response.contains("Connection: close\r\n"));
+    }
+}
+
+#[test]
+fn http_2_preface_is_only_accepted_as_http_2_0() {
+    let addr = server(&mut HttpServer::builder());
+
+    // without HTTP/2 enabled the preface is answered in HTTP/1.1
+    let response = exchange_raw(addr, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 505 "), "{}", response);
+
+    let response = exchange_raw(addr, b"PRI * HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+}
+
+#[test]
+fn strict_mode_only_accepts_a_single_chunked_coding() {
+    let addr = server(&mut HttpServer::builder());
+    let chunked_body = "5\r\nhello\r\n0\r\n\r\n";
+
+    let response = common::exchange(addr, format!("POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n{}", chunked_body).as_bytes());
+    assert_eq!(response.body().unwrap(), b"POST / body=5");
+
+    for codings in ["chunked\r\nTransfer-Encoding: gzip", "chunked\r\nTransfer-Encoding: identity", "gzip, chunked", "gzip\r\nTransfer-Encoding: chunked", "chunked, chunked", "gzip", "identity"] {
+        let request = format!("POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: {}\r\n\r\n{}", codings, chunked_body);
+        let response = exchange_raw(addr, request.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}: {}", codings, response);
+    }
+}
+
+#[test]
+fn lenient_mode_reads_misplaced_chunked_codings_as_chunked() {
+    let addr = server(HttpServer::builder().strict(false));
+
+    let request = "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: identity\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+    let response = common::exchange(addr, request.as_bytes());
+    assert_eq!(response.body().unwrap(), b"POST / body=5");
+}
+
+#[test]
+fn bodies_over_the_limit_are_refused() {
+    let addr = server(HttpServer::builder().max_body_size(16));
+
+    let response = common::exchange(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 16\r\n\r\n0123456789abcdef");
+    assert_eq!(response.body().unwrap(), b"POST / body=16");
+
+    let response = exchange_raw(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 17\r\n\r\n0123456789abcdefg");
+    assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+
+    // a length that doesn't fit in memory at all
+    let response = exchange_raw(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 18446744073709551615\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+
+    let response = exchange_raw(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n10\r\n0123456789abcdef\r\n1\r\ng\r\n0\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+}
+
+#[test]
+fn bodies_over_the_limit_are_refused_before_100_continue() {
+    let addr = server(HttpServer::builder().max_body_size(16));
+
+    let response = exchange_raw(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 17\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+    assert!(!response.contains("100 Continue"), "{}", response);
+}
+
+#[test]
+fn unknown_expectations_fail() {
+    let addr = server(&mut HttpServer::builder());
+
+    let response = exchange_raw(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nExpect: 200-ok\r\nContent-Length: 5\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 417 "), "{}", response);
+    assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+}
+
+// Refuses uploads to /closed before the body is sent
+struct Uploads;
+
+impl HttpHandler for Uploads {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        Echo.handle(request)
+    }
+
+    fn expect_continue(&mut self, request: &HttpRequest) -> Option<HttpResponse> {
+        (request.path() == "/closed").then(|| HttpResponse::new(Status::Forbidden, vec![], None))
+    }
+}
+
+#[test]
+fn continue_is_sent_before_the_body_is_read() {
+    let addr = common::start(HttpServer::builder().handler(Arc::new(Mutex::new(Uploads))));
+    let (mut stream, mut reader) = common::connect(addr);
+
+    // the body only goes out once the server asked for it
+    stream.write_all(b"POST /open HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n").unwrap();
+    let mut interim = [0u8; 25];
+    reader.read_exact(&mut interim).unwrap();
+    assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+    stream.write_all(b"hello").unwrap();
+    let response = common::read_response(&mut reader, &Method::Post);
+    assert_eq!(response.body().unwrap(), b"POST /open body=5");
+
+    let response = exchange_raw(addr, b"POST /closed HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 403 "), "{}", response);
+    assert!(!response.contains("100 Continue"), "{}", response);
+}
+
+#[test]
+fn trailers_over_the_limit_are_refused() {
+    let addr = server(HttpServer::builder().max_trailer_size(32));
+
+    let response = common::exchange(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nChecksum: 1234\r\n\r\n");
+    assert_eq!(response.body().unwrap(), b"POST / body=0");
+
+    let trailer = format!("X-Padding: {}", "a".repeat(32));
+    let request = format!("POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n{}\r\n\r\n", trailer);
+    let response = exchange_raw(addr, request.as_bytes());
+    assert!(response.starts_with("HTTP/1.1 431 "), "{}", response);
+}
+
+// Answers without a body but with a trailer
+struct Trailing;
+
+impl HttpHandler for Trailing {
+    fn handle(&mut self, _request: &HttpRequest) -> HttpResponse {
+        let mut response = HttpResponse::new(Status::Ok, vec![], None);
+        response.add_trailer("Checksum", "none");
+        response
+    }
+}
+
+#[test]
+fn trailers_end_a_response_without_a_body() {
+    let addr = common::start(HttpServer::builder().handler(Arc::new(Mutex::new(Trailing))));
+
+    let response = exchange_raw(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\nTE: trailers\r\nConnection: close\r\n\r\n");
+    assert!(response.contains("Transfer-Encoding: chunked\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n0\r\nChecksum: none\r\n\r\n"), "{}", response);
+}
+
+// Reads the body as it arrives and describes it with its trailers
+struct StreamedTrailers;
+
+impl HttpHandler for StreamedTrailers {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        let mut body = Vec::new();
+        request.body_stream().unwrap().clone().read_to_end(&mut body).unwrap();
+        let trailers: Vec<String> = request.trailers().iter().map(|trailer| format!("{}={}", trailer.key(), trailer.value())).collect();
+        let description = format!("body={} trailers={}", body.len(), trailers.join(","));
+        HttpResponse::new(Status::Ok, vec![], Some(description.into_bytes()))
+    }
+
+    fn stream_body(&self, _request: &HttpRequest) -> bool {
+        true
+    }
+}
+
+#[test]
+fn trailers_of_a_streamed_body_reach_the_handler() {
+    let addr = common::start(HttpServer::builder().handler(Arc::new(Mutex::new(StreamedTrailers))));
+
+    let response = common::exchange(addr, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nChecksum: abc\r\nExpires: never\r\n\r\n");
+    assert_eq!(response.body().unwrap(), b"body=5 trailers=Checksum=abc,Expires=never");
+}
+
+// Hints at a stylesheet before answering
+struct EarlyHints;
+
+impl HttpHandler for EarlyHints {
+    fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        let hints = HttpResponse::new(Status::EarlyHints, vec![Header::new("Link", "</style.css>; rel=preload; as=style")], None);
+        request.send_interim(hints).unwrap();
+        HttpResponse::new(Status::Ok, vec![], Some(b"page".to_vec()))
+    }
+}
+
+#[test]
+fn interim_responses_go_out_before_the_final_one() {
+    let addr = common::start(HttpServer::builder().handler(Arc::new(Mutex::new(EarlyHints))));
+
+    let response = exchange_raw(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let (interim, last) = response.split_once("\r\n\r\n").unwrap();
+    assert!(interim.starts_with("HTTP/1.1 103 Early Hints\r\n"), "{}", response);
+    assert!(interim.contains("\r\nLink: </style.css>; rel=preload; as=style"), "{}", response);
+    assert!(!interim.contains("Content-Length"), "{}", response);
+    assert!(last.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(last.ends_with("\r\n\r\npage"), "{}", response);
+
+    // HTTP/1.0 has no informational responses
+    let response = exchange_raw(addr, b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(!response.contains("103"), "{}", response);
+}